@@ -1,8 +1,18 @@
 use crate::common::{Graph, *};
+use crate::schedule::Schedule;
 use ordered_float::NotNan;
-use petgraph::prelude::*;
+use petgraph::{
+    prelude::*,
+    visit::{Dfs, Reversed, Topo},
+};
 use slotmap::SparseSecondaryMap;
-use std::{cell::RefCell, convert::TryInto, ops};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    f32::consts::{LN_2, LOG2_E},
+    ops,
+};
 use tinyvec::ArrayVec as TinyVec;
 
 #[derive(Clone, Copy)]
@@ -24,6 +34,20 @@ pub struct DualArray<'s> {
     scope: &'s Scope,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PadMode {
+    Zero,
+    Reflect,
+    Replicate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddingBagMode {
+    Sum,
+    Mean,
+    Max,
+}
+
 pub trait IntoArray<'s> {
     fn into_array(self, scope: &'s Scope) -> Array<'s>;
 }
@@ -96,6 +120,88 @@ impl IntoAxis for isize {
     }
 }
 
+fn variance_count(length: usize, unbiased: bool) -> f32 {
+    if unbiased {
+        assert!(length > 1, "unbiased variance needs at least 2 elements");
+        (length - 1) as f32
+    } else {
+        length as f32
+    }
+}
+
+// as in NumPy, a vector on the left is a row and a vector on the right is a column,
+// and the inserted axes are removed from the result (a dot product has shape [1])
+//
+// Leading batch axes are flattened into a single one, so the returned operand shapes
+// are rank 3.  An operand without batch axes is shared by all batches: on the right
+// the batches of the left are folded into its rows, on the left it has a batch size
+// of 1 that must be broadcast.
+fn matmul_shapes(lhs: Shape, rhs: Shape) -> (Shape, Shape, Shape) {
+    assert!(
+        !lhs.is_empty() && !rhs.is_empty(),
+        "matmul expects arrays of rank 1 or more, got {} and {}",
+        lhs,
+        rhs
+    );
+    let (lhs_batch, m, k) = match lhs.len() {
+        1 => (&[][..], 1, lhs[0]),
+        n => (&lhs[..n - 2], lhs[n - 2], lhs[n - 1]),
+    };
+    let (rhs_batch, rhs_k, n) = match rhs.len() {
+        1 => (&[][..], rhs[0], 1),
+        n => (&rhs[..n - 2], rhs[n - 2], rhs[n - 1]),
+    };
+    assert_eq!(
+        k, rhs_k,
+        "matmul inner dimensions differ for {} and {}",
+        lhs, rhs
+    );
+
+    let (lhs_shape, rhs_shape, batch) = if rhs_batch.is_empty() {
+        let rows = lhs_batch.iter().product::<usize>() * m;
+        ([1, rows, k], [1, k, n], lhs_batch)
+    } else if lhs_batch.is_empty() {
+        let batch_size = rhs_batch.iter().product();
+        ([1, m, k], [batch_size, k, n], rhs_batch)
+    } else {
+        assert_eq!(
+            lhs_batch, rhs_batch,
+            "matmul batch axes differ for {} and {}",
+            lhs, rhs
+        );
+        let batch_size = lhs_batch.iter().product();
+        ([batch_size, m, k], [batch_size, k, n], lhs_batch)
+    };
+
+    let mut output_shape: Vec<usize> = batch.to_vec();
+    if lhs.len() > 1 {
+        output_shape.push(m);
+    }
+    if rhs.len() > 1 {
+        output_shape.push(n);
+    }
+    if output_shape.is_empty() {
+        output_shape.push(1);
+    }
+    (
+        Shape::from(lhs_shape),
+        Shape::from(rhs_shape),
+        output_shape.into_iter().collect(),
+    )
+}
+
+fn swapaxes_perm(rank: usize, a: Axis, b: Axis) -> TinyVec<[usize; MAX_DIM]> {
+    let mut perm: TinyVec<[usize; MAX_DIM]> = (0..rank).collect();
+    perm.swap(a.index(), b.index());
+    perm
+}
+
+fn moveaxis_perm(rank: usize, src: Axis, dst: Axis) -> TinyVec<[usize; MAX_DIM]> {
+    let mut perm: TinyVec<[usize; MAX_DIM]> = (0..rank).filter(|&i| i != src.index()).collect();
+    perm.insert(dst.index(), src.index());
+    perm
+}
+
 macro_rules! implement_array_common {
     ($array:ident, $into_array:ident) => {
         impl<'s> $array<'s> {
@@ -122,7 +228,11 @@ macro_rules! implement_array_common {
             }
 
             pub fn broadcast(self, shape: impl Into<Shape>) -> Self {
-                self.view(View::broadcast(self.shape(), shape.into()))
+                let shape = shape.into();
+                if self.shape() == shape {
+                    return self;
+                }
+                self.view(View::broadcast(self.shape(), shape))
             }
 
             fn unary_op(self, op: UnaryOp) -> Self {
@@ -210,6 +320,10 @@ macro_rules! implement_array_common {
                 })
             }
 
+            pub fn reshape_infer(self, dims: &[isize]) -> Self {
+                self.reshape(self.shape().infer_reshape(dims))
+            }
+
             pub fn transpose(self) -> Self {
                 self.view(self.shape().identity_view().transposed())
             }
@@ -224,7 +338,33 @@ macro_rules! implement_array_common {
 implement_array_common!(Array, IntoArray);
 implement_array_common!(UArray, IntoUArray);
 
+// A move can be collapsed into the gradient it is accumulated into if nothing reads
+// it yet, since it was then only created for this accumulation.
+fn collapsible_move(ops: &OpGraph, node_id: OpNodeId, grad_node_id: OpNodeId) -> Option<OpEdgeId> {
+    let node = &ops[node_id];
+    if node.op != Op::Unary(UnaryOp::Mov)
+        || node.no_fuse
+        || ops.edges_directed(node_id, Outgoing).next().is_some()
+    {
+        return None;
+    }
+    let mut edges = ops.edges_directed(node_id, Incoming);
+    let edge_ref = edges.next()?;
+    if edges.next().is_some() || edge_ref.source() == grad_node_id {
+        return None;
+    }
+    Some(edge_ref.id())
+}
+
 impl<'s> Array<'s> {
+    // keep this op in a kernel of its own, so that its result is written to memory
+    pub fn no_fuse(self) -> Self {
+        self.scope.with_state(|state| {
+            state.ops[self.node_id].no_fuse = true;
+        });
+        self
+    }
+
     pub fn with_empty_grad(self) -> (Self, Self) {
         let grad = self.scope.with_state(|state| {
             let shape = state.ops[self.node_id].shape;
@@ -359,6 +499,51 @@ impl<'s> Array<'s> {
             .keep_axis(axis, keep_axis)
     }
 
+    pub fn reduce_mean(self, axis: impl IntoAxis, keep_axis: bool) -> Self {
+        let axis = axis.into_axis(self.shape());
+        let count = self.shape()[axis];
+        self.reduce_sum(axis, keep_axis) * (1.0 / (count as f32))
+    }
+
+    pub fn norm(self, p: f32, axis: impl IntoAxis, keep_axis: bool) -> Self {
+        assert!(p > 0.0, "norm order {} is not positive", p);
+        let axis = axis.into_axis(self.shape());
+        if p == 1.0 {
+            self.abs().reduce_sum(axis, keep_axis)
+        } else if p == 2.0 {
+            self.square().reduce_sum(axis, keep_axis).sqrt()
+        } else {
+            self.abs().pow(p).reduce_sum(axis, keep_axis).pow(1.0 / p)
+        }
+    }
+
+    // two passes, since mean(x^2) - mean(x)^2 loses precision to cancellation
+    // when the mean is large compared to the spread
+    pub fn variance(self, axis: impl IntoAxis, keep_axis: bool, unbiased: bool) -> Self {
+        let axis = axis.into_axis(self.shape());
+        let count = variance_count(self.shape()[axis], unbiased);
+        let centered = self - self.reduce_mean(axis, true);
+        centered.square().reduce_sum(axis, keep_axis) * (1.0 / count)
+    }
+
+    pub fn std(self, axis: impl IntoAxis, keep_axis: bool, unbiased: bool) -> Self {
+        self.variance(axis, keep_axis, unbiased).sqrt()
+    }
+
+    pub fn softmax(self, axis: impl IntoAxis) -> Self {
+        // subtract the max for stability, keep the axis so the divide broadcasts
+        let axis = axis.into_axis(self.shape());
+        let t = (self - self.reduce_max(axis, true)).exp();
+        t / t.reduce_sum(axis, true)
+    }
+
+    pub fn reduce_sum_of_log(self, axis: impl IntoAxis, eps: f32) -> Self {
+        // clamp so that zeros give a large finite value instead of -inf, the
+        // clamp and log are evaluated inside the reduction kernel
+        assert!(eps > 0.0);
+        self.clamp(eps, f32::MAX).log().reduce_sum(axis, false)
+    }
+
     pub fn argmax(self, axis: impl IntoAxis, keep_axis: bool) -> Self {
         // implement with reduce_max for now
         let axis = axis.into_axis(self.shape());
@@ -395,6 +580,32 @@ impl<'s> Array<'s> {
             }
         })
     }
+
+    pub fn gather_nd(self, indices: impl IntoUArray<'s>) -> Self {
+        // each row of indices [index_count, index_dims] addresses the leading index_dims
+        // axes, the result has shape [index_count] followed by the remaining axes
+        let indices = indices.into_array(self.scope);
+        let [index_count, index_dims]: [usize; 2] = indices.shape().try_into().unwrap();
+
+        let shape = self.shape();
+        assert!(index_dims > 0 && index_dims <= shape.len());
+        let (outer, inner) = shape.split_at(index_dims);
+
+        let mut flat_index = indices.lock_axis(-1, 0, false);
+        for (index, len) in outer.iter().copied().enumerate().skip(1) {
+            flat_index = flat_index * (len as u32) + indices.lock_axis(-1, index, false);
+        }
+
+        let flat_shape: Shape = std::iter::once(outer.iter().product())
+            .chain(inner.iter().copied())
+            .collect();
+        let output_shape: Shape = std::iter::once(index_count)
+            .chain(inner.iter().copied())
+            .collect();
+        self.reshape(flat_shape)
+            .gather(0, flat_index)
+            .reshape(output_shape)
+    }
     pub fn scatter_add(
         self,
         values: impl IntoArray<'s>,
@@ -441,18 +652,67 @@ impl<'s> Array<'s> {
         self.compare_and_select(CompareMode::Gt, rhs, pass, fail)
     }
 
+    pub fn equal(self, rhs: impl IntoArray<'s>) -> Self {
+        self.compare_and_select(CompareMode::Eq, rhs, 1.0, 0.0)
+    }
+    pub fn not_equal(self, rhs: impl IntoArray<'s>) -> Self {
+        self.compare_and_select(CompareMode::Ne, rhs, 1.0, 0.0)
+    }
+    pub fn greater_than(self, rhs: impl IntoArray<'s>) -> Self {
+        self.compare_and_select(CompareMode::Gt, rhs, 1.0, 0.0)
+    }
+    pub fn greater_equal(self, rhs: impl IntoArray<'s>) -> Self {
+        self.compare_and_select(CompareMode::Ge, rhs, 1.0, 0.0)
+    }
+    pub fn less_than(self, rhs: impl IntoArray<'s>) -> Self {
+        self.compare_and_select(CompareMode::Lt, rhs, 1.0, 0.0)
+    }
+    pub fn less_equal(self, rhs: impl IntoArray<'s>) -> Self {
+        self.compare_and_select(CompareMode::Le, rhs, 1.0, 0.0)
+    }
+
+    pub fn clamp(self, min: impl IntoArray<'s>, max: impl IntoArray<'s>) -> Self {
+        let min = min.into_array(self.scope);
+        let max = max.into_array(self.scope);
+        let lower = min.select_gt(self, min, self);
+        lower.select_gt(max, max, lower)
+    }
+
+    pub fn safe_div(self, rhs: impl IntoArray<'s>, fill: f32) -> Self {
+        let rhs = rhs.into_array(self.scope);
+        rhs.select_eq(0.0, fill, self / rhs)
+    }
+
+    pub fn fake_quantize(self, scale: f32, zero_point: f32, qmin: f32, qmax: f32) -> Self {
+        assert!(qmin <= qmax);
+        let q = (self / scale + zero_point).clamp(qmin, qmax);
+
+        // round half up, offset so that the value is non-negative for the conversion
+        let r = (q - qmin + 0.5).into_u32().into_f32() + qmin;
+        (r - zero_point) * scale
+    }
+
     pub fn square(self) -> Self {
         self * self
     }
     pub fn sqrt(self) -> Self {
         self.unary_op(UnaryOp::Sqrt)
     }
+    pub fn rsqrt(self) -> Self {
+        self.unary_op(UnaryOp::Rsqrt)
+    }
     pub fn exp(self) -> Self {
         self.unary_op(UnaryOp::Exp)
     }
     pub fn log(self) -> Self {
         self.unary_op(UnaryOp::Log)
     }
+    pub fn exp2(self) -> Self {
+        (self * LN_2).exp()
+    }
+    pub fn log2(self) -> Self {
+        self.log() * LOG2_E
+    }
     pub fn sin(self) -> Self {
         self.unary_op(UnaryOp::Sin)
     }
@@ -468,13 +728,38 @@ impl<'s> Array<'s> {
     pub fn into_u32(self) -> UArray<'s> {
         self.unary_op(UnaryOp::FloatToUint).to_u32_bits()
     }
+    pub fn to_f16(self) -> UArray<'s> {
+        self.unary_op(UnaryOp::FloatToHalf).to_u32_bits()
+    }
+
+    // pairs along the last axis share a word, an odd length is padded with zero
+    pub fn pack_f16(self) -> UArray<'s> {
+        let shape = self.shape();
+        let axis = Axis::from_index(shape.len() - 1);
+        let pair_shape = shape
+            .packed_f16()
+            .insert_axis(Axis::from_index(shape.len()), 2);
+        let pairs = self
+            .pad(axis, 0, shape[axis] % 2)
+            .reshape(pair_shape)
+            .to_f16();
+        let lo = pairs.lock_axis(-1, 0, false);
+        let hi = pairs.lock_axis(-1, 1, false);
+        lo | (hi << 16u32)
+    }
     pub fn sigmoid(self) -> Self {
-        self.exp() / (self.exp() + 1.0)
+        // only exponentiate non-positive values to avoid overflow
+        let e = (-self.abs()).exp();
+        let r = 1.0 / (e + 1.0);
+        self.select_gt(0.0, r, e * r)
     }
     pub fn tanh(self) -> Self {
-        let a = self.exp();
-        let b = (-self).exp();
-        (a - b) / (a + b)
+        let e = (-2.0 * self.abs()).exp();
+        let t = (1.0 - e) / (1.0 + e);
+        self.select_gt(0.0, t, -t)
+    }
+    pub fn abs(self) -> Self {
+        self.select_gt(0.0, self, -self)
     }
 
     pub fn pow(self, rhs: impl IntoArray<'s>) -> Self {
@@ -489,24 +774,146 @@ impl<'s> Array<'s> {
         self.view(self.shape().identity_view().permute_axes(perm))
     }
 
+    pub fn swapaxes(self, a: impl IntoAxis, b: impl IntoAxis) -> Self {
+        let shape = self.shape();
+        self.permute_axes(&swapaxes_perm(
+            shape.len(),
+            a.into_axis(shape),
+            b.into_axis(shape),
+        ))
+    }
+
+    pub fn moveaxis(self, src: impl IntoAxis, dst: impl IntoAxis) -> Self {
+        let shape = self.shape();
+        self.permute_axes(&moveaxis_perm(
+            shape.len(),
+            src.into_axis(shape),
+            dst.into_axis(shape),
+        ))
+    }
+
+    pub fn diagonal(self) -> Self {
+        let shape = self.shape();
+        assert!(
+            shape.len() == 2 && shape[0] == shape[1],
+            "diagonal expects a square matrix, got {}",
+            shape
+        );
+        let n = shape[0];
+
+        // step through the flattened matrix by one row and one column at a time
+        let flat_shape = Shape::from([n * n]);
+        let mut view = flat_shape.identity_view();
+        view.output_mapping[0] = flat_shape
+            .identity_mapping(Axis::from_index(0))
+            .stepped((n + 1) as isize);
+        view.output_shape = Shape::from([n]);
+        self.reshape(flat_shape).view(view)
+    }
+
+    pub fn diag_embed(self) -> Self {
+        let [n]: [usize; 1] = self.shape().try_into().unwrap();
+        self.scope.coord(n).value().one_hot(n) * self.reshape([n, 1])
+    }
+
+    pub fn tril(self, diagonal: isize) -> Self {
+        // zero where col - row > diagonal
+        let offset = self.coord(-1) - self.coord(-2);
+        offset.select_gt(diagonal as f32, 0.0, self)
+    }
+
+    pub fn triu(self, diagonal: isize) -> Self {
+        // zero where col - row < diagonal
+        let offset = self.coord(-2) - self.coord(-1);
+        offset.select_gt(-diagonal as f32, 0.0, self)
+    }
+
+    pub fn covariance(self, axis: impl IntoAxis) -> Self {
+        let shape = self.shape();
+        assert_eq!(shape.len(), 2, "covariance expects a rank 2 array");
+        let axis = axis.into_axis(shape);
+
+        // move observations to the outer axis
+        let x = if axis.index() == 0 {
+            self
+        } else {
+            self.transpose()
+        };
+        let count = x.shape()[0];
+        let centered = x - x.reduce_mean(0, true);
+        centered.transpose().matmul(centered) * (1.0 / (count as f32))
+    }
+
     pub fn matmul(self, rhs: impl IntoArray<'s>) -> Self {
-        let axis = Axis::from_index(0);
-        let lhs = self.insert_axis(axis);
-        let rhs = rhs.into_array(self.scope).insert_axis(axis);
-        let result = lhs.batched_matmul(rhs, MatMulOutputMode::Batches);
-        result.remove_axis(axis)
+        self.matmul_split(rhs, true)
+    }
+
+    // Accumulates over k in order in a single kernel, instead of summing partial results
+    // for chunks of k, so results only depend on the order of the inputs.  This is slower
+    // for large k since there is less parallelism.
+    pub fn matmul_ordered(self, rhs: impl IntoArray<'s>) -> Self {
+        self.matmul_split(rhs, false)
+    }
+
+    fn matmul_split(self, rhs: impl IntoArray<'s>, split_k: bool) -> Self {
+        self.matmul_typed(rhs.into_array(self.scope), split_k, StorageType::F32)
+    }
+
+    fn matmul_typed(self, rhs: Array, split_k: bool, input_type: StorageType) -> Self {
+        let (lhs_shape, rhs_shape, output_shape) = matmul_shapes(self.shape(), rhs.shape());
+
+        let lhs = self
+            .reshape(lhs_shape)
+            .broadcast(lhs_shape.resize_axis(Axis::from_index(0), rhs_shape[0]));
+        let rhs = rhs.reshape(rhs_shape);
+        let result = lhs.batched_matmul_typed(rhs, MatMulOutputMode::Batches, split_k, input_type);
+        result.reshape(output_shape)
+    }
+
+    // equivalent to diag(d).matmul(self) without building the diagonal matrix
+    pub fn scale_rows(self, d: impl IntoArray<'s>) -> Self {
+        let shape = self.shape();
+        assert_eq!(shape.len(), 2, "scale_rows expects a rank 2 array");
+        self * d.into_array(self.scope).reshape([shape[0], 1])
+    }
+
+    // equivalent to self.matmul(diag(d)) without building the diagonal matrix
+    pub fn scale_cols(self, d: impl IntoArray<'s>) -> Self {
+        let shape = self.shape();
+        assert_eq!(shape.len(), 2, "scale_cols expects a rank 2 array");
+        self * d.into_array(self.scope).reshape([1, shape[1]])
+    }
+
+    pub(crate) fn batched_matmul(
+        self,
+        rhs: Array,
+        output_mode: MatMulOutputMode,
+        split_k: bool,
+    ) -> Self {
+        self.batched_matmul_typed(rhs, output_mode, split_k, StorageType::F32)
     }
 
-    pub(crate) fn batched_matmul(self, rhs: Array, output_mode: MatMulOutputMode) -> Self {
+    fn batched_matmul_typed(
+        self,
+        rhs: Array,
+        output_mode: MatMulOutputMode,
+        split_k: bool,
+        input_type: StorageType,
+    ) -> Self {
         let chunks = self.scope.with_state(|state| {
-            let shape = state.ops[self.node_id]
-                .shape
-                .batched_matmul(state.ops[rhs.node_id].shape, output_mode);
+            let shape = state.ops[self.node_id].shape.batched_matmul(
+                state.ops[rhs.node_id].shape,
+                output_mode,
+                split_k,
+            );
             Array {
                 node_id: state.ops.new_node(
                     state.next_colour,
                     shape,
-                    Op::MatMul { output_mode },
+                    Op::MatMul {
+                        output_mode,
+                        input_type,
+                    },
                     &[self.node_id, rhs.node_id],
                 ),
                 scope: self.scope,
@@ -528,21 +935,35 @@ impl<'s> Array<'s> {
         self.view(shape.padded_view(axis, before, after))
     }
 
-    pub fn zero_pad(self, axis: impl IntoAxis, before: usize, after: usize) -> Self{
-        if before + after == 0{
-            return self;
+    pub fn zero_pad(self, axis: impl IntoAxis, before: usize, after: usize) -> Self {
+        self.pad_with(axis, before, after, 0.0)
+    }
+
+    pub fn diff(self, axis: impl IntoAxis) -> Self {
+        let shape = self.shape();
+        let axis = axis.into_axis(shape);
+        let length = shape[axis];
+        assert!(length > 1);
+        self.limit_axis(axis, 1..) - self.limit_axis(axis, ..(length - 1))
+    }
+
+    pub fn pad_with(self, axis: impl IntoAxis, before: usize, after: usize, value: f32) -> Self {
+        let shape = self.shape();
+        let axis = axis.into_axis(shape);
+        let mut output = self;
+        if before > 0 {
+            let fill = value
+                .into_array(self.scope)
+                .broadcast(shape.resize_axis(axis, before));
+            output = fill.concat(output, axis);
         }
-        let axis = axis.into_axis(self.shape());
-        let zero = 0f32.into_array(self.scope);
-        let mut before_shape = self.shape();
-        before_shape[axis] = before;
-        let mut after_shape = self.shape();
-        after_shape[axis] = after;
-        let before_size = before_shape.element_count();
-        let after_size = after_shape.element_count();
-        let zero_before = zero.pad(0, before_size - 1, 0).reshape(before_shape);
-        let zero_after = zero.pad(0, 0, after_size -1).reshape(after_shape);
-        zero_before.concat(self, axis).concat(zero_after, axis)
+        if after > 0 {
+            let fill = value
+                .into_array(self.scope)
+                .broadcast(shape.resize_axis(axis, after));
+            output = output.concat(fill, axis);
+        }
+        output
     }
 
     pub(crate) fn unpad(self, axis: impl IntoAxis, pad: usize) -> Self {
@@ -631,38 +1052,96 @@ impl<'s> Array<'s> {
         })
     }
 
+    fn is_zero_grad(&self) -> bool {
+        self.scope
+            .with_state(|state| matches!(state.ops[self.node_id].op, Op::Literal(_)))
+    }
+
     pub fn accumulate(&self, src: impl IntoArray<'s>) {
-        let src = src.into_array(self.scope);
+        self.accumulate_impl(src.into_array(self.scope), false);
+    }
+
+    // For arrays built only to be accumulated here, such as the reshape of a gradient
+    // back to the input shape.  The first accumulation takes over the argument of such
+    // a move instead of chaining the two, so the array must not be used again.
+    pub(crate) fn accumulate_owned(&self, src: Array<'s>) {
+        self.accumulate_impl(src, true);
+    }
+
+    fn accumulate_impl(&self, src: Array<'s>, is_owned: bool) {
+        // gradients of constants are not needed, so are never accumulated
+        if self.is_zero_grad() {
+            return;
+        }
         self.scope.with_state(|state| {
             assert_eq!(state.ops[self.node_id].op, Op::Unary(UnaryOp::Mov));
-            assert_eq!(state.ops[self.node_id].shape, state.ops[src.node_id].shape);
-            let src_id =
-                if let Some(edge_ref) = state.ops.edges_directed(self.node_id, Incoming).next() {
-                    // remove the edge from the current source to this move
-                    let prev_edge_id = edge_ref.id();
-                    let prev_src_id = edge_ref.source();
-                    state.ops.remove_edge(prev_edge_id);
-
-                    // accumulate with the given array
-                    state.ops.new_node(
-                        state.next_colour,
-                        state.ops[src.node_id].shape,
-                        Op::Binary(BinaryOp::Add),
-                        &[prev_src_id, src.node_id],
-                    )
+            let shape = state.ops[self.node_id].shape;
+            assert_eq!(shape, state.ops[src.node_id].shape);
+            if let Some(edge_ref) = state.ops.edges_directed(self.node_id, Incoming).next() {
+                // remove the edge from the current source to this move
+                let prev_edge_id = edge_ref.id();
+                let prev_src_id = edge_ref.source();
+                let prev_view = edge_ref.weight().view;
+                state.ops.remove_edge(prev_edge_id);
+
+                // restore the move if one was collapsed into this one
+                let prev_src_id = if state.ops[prev_src_id].shape == shape
+                    && prev_view == shape.identity_view()
+                {
+                    prev_src_id
                 } else {
-                    src.node_id
+                    let node_id =
+                        state
+                            .ops
+                            .new_node(state.next_colour, shape, Op::Unary(UnaryOp::Mov), &[]);
+                    state.ops.add_edge(
+                        prev_src_id,
+                        node_id,
+                        OpEdge {
+                            arg: 0,
+                            view: prev_view,
+                        },
+                    );
+                    node_id
                 };
 
-            // add the edge to the move
-            state.ops.add_edge(
-                src_id,
-                self.node_id,
-                OpEdge {
-                    arg: 0,
-                    view: state.ops[src.node_id].shape.identity_view(),
-                },
-            );
+                // accumulate with the given array
+                let src_id = state.ops.new_node(
+                    state.next_colour,
+                    shape,
+                    Op::Binary(BinaryOp::Add),
+                    &[prev_src_id, src.node_id],
+                );
+                state.ops.add_edge(
+                    src_id,
+                    self.node_id,
+                    OpEdge {
+                        arg: 0,
+                        view: shape.identity_view(),
+                    },
+                );
+            } else if let Some(edge_id) = if is_owned {
+                collapsible_move(&state.ops, src.node_id, self.node_id)
+            } else {
+                None
+            } {
+                // most gradients are only accumulated once, so this avoids a chain of
+                // moves per op, a second accumulation restores the move above
+                let (src_src_id, _) = state.ops.edge_endpoints(edge_id).unwrap();
+                let edge = state.ops.remove_edge(edge_id).unwrap();
+                state.ops.remove_node(src.node_id);
+                state.ops.add_edge(src_src_id, self.node_id, edge);
+            } else {
+                // add the edge to the move
+                state.ops.add_edge(
+                    src.node_id,
+                    self.node_id,
+                    OpEdge {
+                        arg: 0,
+                        view: shape.identity_view(),
+                    },
+                );
+            }
         })
     }
 
@@ -699,6 +1178,76 @@ impl<'s> UArray<'s> {
     pub fn into_f32(self) -> Array<'s> {
         self.unary_op(UnaryOp::UintToFloat).to_f32_bits()
     }
+    // both inputs hold f16 values from to_f16, the products are summed in f32
+    pub fn matmul_f16(self, rhs: impl IntoUArray<'s>) -> Array<'s> {
+        let rhs = rhs.into_array(self.scope);
+        self.to_f32_bits()
+            .matmul_typed(rhs.to_f32_bits(), true, StorageType::F16)
+    }
+
+    #[allow(clippy::wrong_self_convention)]
+    pub fn from_f16(self) -> Array<'s> {
+        self.unary_op(UnaryOp::HalfToFloat).to_f32_bits()
+    }
+
+    // inverse of pack_f16, len is the unpacked length of the last axis
+    pub fn unpack_f16(self, len: usize) -> Array<'s> {
+        let shape = self.shape();
+        let axis = Axis::from_index(shape.len() - 1);
+        assert_eq!(shape[axis], len.div_round_up(2));
+        let pair_axis = Axis::from_index(shape.len());
+        let shift = self.scope.coord(2).value().into_u32() * 16u32;
+        let words = self
+            .reshape(shape.insert_axis(pair_axis, 1))
+            .broadcast(shape.insert_axis(pair_axis, 2));
+        (words >> shift)
+            .from_f16()
+            .reshape(shape.resize_axis(axis, 2 * shape[axis]))
+            .limit_axis(axis, 0..len)
+    }
+
+    fn compare_and_select(
+        self,
+        compare_mode: CompareMode,
+        rhs: impl IntoUArray<'s>,
+        pass: impl IntoUArray<'s>,
+        fail: impl IntoUArray<'s>,
+    ) -> Self {
+        self.to_f32_bits()
+            .compare_and_select(
+                compare_mode,
+                rhs.into_array(self.scope).to_f32_bits(),
+                pass.into_array(self.scope).to_f32_bits(),
+                fail.into_array(self.scope).to_f32_bits(),
+            )
+            .to_u32_bits()
+    }
+
+    pub fn select_eq(
+        self,
+        rhs: impl IntoUArray<'s>,
+        pass: impl IntoUArray<'s>,
+        fail: impl IntoUArray<'s>,
+    ) -> Self {
+        self.compare_and_select(CompareMode::UEq, rhs, pass, fail)
+    }
+
+    pub fn select_gt(
+        self,
+        rhs: impl IntoUArray<'s>,
+        pass: impl IntoUArray<'s>,
+        fail: impl IntoUArray<'s>,
+    ) -> Self {
+        self.compare_and_select(CompareMode::UGt, rhs, pass, fail)
+    }
+
+    pub fn reduce_sum(self, axis: impl IntoAxis, keep_axis: bool) -> Self {
+        let axis = axis.into_axis(self.shape());
+        self.to_f32_bits()
+            .reduce_op(ReduceOp::USum, axis)
+            .to_u32_bits()
+            .keep_axis(axis, keep_axis)
+    }
 }
 
 macro_rules! implement_arithmetic {
@@ -789,6 +1338,16 @@ impl<'s> ops::Neg for Array<'s> {
     }
 }
 
+// division by zero is undefined in the kernels, most GPUs return 0xffffffff
+impl<'s, T> ops::Div<T> for UArray<'s>
+where
+    T: IntoUArray<'s>,
+{
+    type Output = UArray<'s>;
+    fn div(self, rhs: T) -> Self::Output {
+        self.binary_op(rhs, BinaryOp::UDiv)
+    }
+}
 impl<'s, T> ops::Rem<T> for UArray<'s>
 where
     T: IntoUArray<'s>,
@@ -807,6 +1366,43 @@ where
         self.binary_op(rhs, BinaryOp::UBitXor)
     }
 }
+impl<'s, T> ops::BitAnd<T> for UArray<'s>
+where
+    T: IntoUArray<'s>,
+{
+    type Output = UArray<'s>;
+    fn bitand(self, rhs: T) -> Self::Output {
+        self.binary_op(rhs, BinaryOp::UBitAnd)
+    }
+}
+impl<'s, T> ops::BitOr<T> for UArray<'s>
+where
+    T: IntoUArray<'s>,
+{
+    type Output = UArray<'s>;
+    fn bitor(self, rhs: T) -> Self::Output {
+        self.binary_op(rhs, BinaryOp::UBitOr)
+    }
+}
+// shift amounts are masked to 0..=31, as on most GPUs
+impl<'s, T> ops::Shl<T> for UArray<'s>
+where
+    T: IntoUArray<'s>,
+{
+    type Output = UArray<'s>;
+    fn shl(self, rhs: T) -> Self::Output {
+        self.binary_op(rhs, BinaryOp::UShl)
+    }
+}
+impl<'s, T> ops::Shr<T> for UArray<'s>
+where
+    T: IntoUArray<'s>,
+{
+    type Output = UArray<'s>;
+    fn shr(self, rhs: T) -> Self::Output {
+        self.binary_op(rhs, BinaryOp::UShr)
+    }
+}
 
 impl<'s> DualArray<'s> {
     pub fn new(value: Array<'s>, loss_grad: Array<'s>) -> Self {
@@ -874,7 +1470,7 @@ impl<'s> DualArray<'s> {
             .reduce_sum(1, true)
             .reshape([m, output_h, output_w, groups * group_nc]);
 
-        da.accumulate(summed);
+        da.accumulate_owned(summed);
 
         (b, db).into()
     }
@@ -905,89 +1501,242 @@ impl<'s> DualArray<'s> {
 
         //We also need to pad the gradient by the crop we just did
         let padded = db.zero_pad(1, top, bottom).zero_pad(2, left, right);
-        da.accumulate(padded);
+        da.accumulate_owned(padded);
         
         (b, db).into()
     }
 
+    pub fn exp2(self) -> Self {
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.exp2().with_empty_grad();
+        da.accumulate_owned(db * b * LN_2);
+
+        (b, db).into()
+    }
+    pub fn log2(self) -> Self {
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.log2().with_empty_grad();
+        da.accumulate_owned(db * LOG2_E / a);
+
+        (b, db).into()
+    }
+
     pub fn sin(self) -> Self {
         let (a, da) = self.into_inner();
 
         let (b, db) = a.sin().with_empty_grad();
-        da.accumulate(db * a.cos());
+        da.accumulate_owned(db * a.cos());
 
         (b, db).into()
     }
     pub fn tanh(self) -> Self {
         let (a, da) = self.into_inner();
 
-        // d/dx tanh(x) = 1 / cosh^2 (x) = 4 / (e^2x + 2 + e^(-2x))
+        // d/dx tanh(x) = 1 - tanh^2(x)
         let (b, db) = a.tanh().with_empty_grad();
-        da.accumulate(db * 4.0 / ((2.0 * a).exp() + 2.0 + (-2.0 * a).exp()));
+        da.accumulate_owned(db * (1.0 - b * b));
 
         (b, db).into()
     }
     pub fn sigmoid(self) -> Self {
         let (a, da) = self.into_inner();
 
-        // d/dx e^x / (1 + e^x) = e^x / (1 + e^x)^2
+        // d/dx sigmoid(x) = sigmoid(x) * (1 - sigmoid(x))
         let (b, db) = a.sigmoid().with_empty_grad();
-        da.accumulate(db * a.exp() / (a.exp() + 1.0).square());
+        da.accumulate_owned(db * b * (1.0 - b));
 
         (b, db).into()
     }
 
-    pub fn leaky_relu(self, leakiness: f32) -> Self {
+    pub fn softmax(self, axis: impl IntoAxis) -> Self {
         let (a, da) = self.into_inner();
+        let axis = axis.into_axis(a.shape());
 
-        let (b, db) = a.select_gt(0.0, a, a * leakiness).with_empty_grad();
-        da.accumulate(a.select_gt(0.0, db, db * leakiness));
+        let (b, db) = a.softmax(axis).with_empty_grad();
+
+        // d/dx softmax(x) = s * (ds - sum(ds * s)), written in terms of the forward result
+        // so that the normalization is not recomputed
+        da.accumulate_owned(b * (db - (db * b).reduce_sum(axis, true)));
 
         (b, db).into()
     }
 
-    pub(crate) fn batched_matmul(self, rhs: DualArray, output_mode: MatMulOutputMode) -> Self {
+    pub fn clamp(self, min: f32, max: f32) -> Self {
         let (a, da) = self.into_inner();
-        let (b, db) = rhs.into_inner();
-
-        let (c, dc) = a.batched_matmul(b, output_mode).with_empty_grad();
-        da.accumulate(dc.batched_matmul(b.transpose(), MatMulOutputMode::Batches));
-        db.accumulate(a.transpose().batched_matmul(dc, MatMulOutputMode::Batches));
 
-        (c, dc).into()
-    }
+        // gradient only passes where the value is within range
+        let (b, db) = a.clamp(min, max).with_empty_grad();
+        let min = min.into_array(self.scope);
+        da.accumulate_owned(a.select_gt(max, 0.0, min.select_gt(a, 0.0, db)));
 
-    pub fn matmul(self, rhs: impl IntoDualArray<'s>) -> Self {
-        let axis = Axis::from_index(0);
-        let lhs = self.insert_axis(axis);
-        let rhs = rhs.into_dual_array(self.scope).insert_axis(axis);
-        let result = lhs.batched_matmul(rhs, MatMulOutputMode::Batches);
-        result.remove_axis(axis)
+        (b, db).into()
     }
 
-    pub fn transpose(self) -> Self {
+    pub fn fake_quantize(self, scale: f32, zero_point: f32, qmin: f32, qmax: f32) -> Self {
         let (a, da) = self.into_inner();
 
-        let (b, db) = a.transpose().with_empty_grad();
-        da.accumulate(db.transpose());
+        // straight-through estimator: gradient passes where the value was not clamped
+        let (b, db) = a
+            .fake_quantize(scale, zero_point, qmin, qmax)
+            .with_empty_grad();
+        let q = a / scale + zero_point;
+        let qmin = qmin.into_array(self.scope);
+        da.accumulate_owned(q.select_gt(qmax, 0.0, qmin.select_gt(q, 0.0, db)));
 
         (b, db).into()
     }
 
-    pub fn pow(self, rhs: impl IntoDualArray<'s>) -> Self {
+    pub fn safe_div(self, rhs: impl IntoDualArray<'s>, fill: f32) -> Self {
+        let rhs = rhs.into_dual_array(self.scope);
+
         let (a, da) = self.into_inner();
-        let (b, db) = rhs.into_dual_array(self.scope).into_inner();
+        let (b, db) = rhs.into_inner();
 
-        // c = a ^ b
-        let (c, dc) = a.pow(b).with_empty_grad();
-        da.accumulate((dc * b * a.pow(b - 1.0)).unbroadcast(a.shape()));
-        db.accumulate((dc * a.log() * c).unbroadcast(b.shape()));
+        // no gradient flows through the filled positions
+        let (c, dc) = a.safe_div(b, fill).with_empty_grad();
+        let dc_over_b = dc.safe_div(b, 0.0);
+        da.accumulate_owned(dc_over_b.unbroadcast(a.shape()));
+        if !db.is_zero_grad() {
+            db.accumulate_owned((-dc_over_b * c).unbroadcast(b.shape()));
+        }
 
         (c, dc).into()
     }
 
-    pub fn select_eq(
+    pub fn hardtanh(self, min_val: f32, max_val: f32) -> Self {
+        self.clamp(min_val, max_val)
+    }
+
+    pub fn leaky_relu(self, leakiness: f32) -> Self {
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.select_gt(0.0, a, a * leakiness).with_empty_grad();
+        da.accumulate_owned(a.select_gt(0.0, db, db * leakiness));
+
+        (b, db).into()
+    }
+
+    pub(crate) fn batched_matmul(
+        self,
+        rhs: DualArray,
+        output_mode: MatMulOutputMode,
+        split_k: bool,
+    ) -> Self {
+        let (a, da) = self.into_inner();
+        let (b, db) = rhs.into_inner();
+
+        let (c, dc) = a.batched_matmul(b, output_mode, split_k).with_empty_grad();
+        da.accumulate_owned(dc.batched_matmul(b.transpose(), MatMulOutputMode::Batches, split_k));
+        db.accumulate_owned(
+            a.transpose()
+                .batched_matmul(dc, MatMulOutputMode::Batches, split_k),
+        );
+
+        (c, dc).into()
+    }
+
+    pub fn covariance(self, axis: impl IntoAxis) -> Self {
+        let shape = self.shape();
+        assert_eq!(shape.len(), 2, "covariance expects a rank 2 array");
+        let axis = axis.into_axis(shape);
+
+        // move observations to the outer axis
+        let x = if axis.index() == 0 {
+            self
+        } else {
+            self.transpose()
+        };
+        let count = x.shape()[0];
+        let centered = x - x.reduce_mean(0, true);
+        centered.transpose().matmul(centered) * (1.0 / (count as f32))
+    }
+
+    pub fn matmul(self, rhs: impl IntoDualArray<'s>) -> Self {
+        self.matmul_split(rhs, true)
+    }
+
+    // the gradient matmuls also accumulate in order
+    pub fn matmul_ordered(self, rhs: impl IntoDualArray<'s>) -> Self {
+        self.matmul_split(rhs, false)
+    }
+
+    fn matmul_split(self, rhs: impl IntoDualArray<'s>, split_k: bool) -> Self {
+        let rhs = rhs.into_dual_array(self.scope);
+        let (lhs_shape, rhs_shape, output_shape) = matmul_shapes(self.shape(), rhs.shape());
+
+        let mut lhs = self.reshape(lhs_shape);
+        if lhs_shape[0] != rhs_shape[0] {
+            lhs = lhs.broadcast_to(lhs_shape.resize_axis(Axis::from_index(0), rhs_shape[0]));
+        }
+        let rhs = rhs.reshape(rhs_shape);
+        let result = lhs.batched_matmul(rhs, MatMulOutputMode::Batches, split_k);
+        result.reshape(output_shape)
+    }
+
+    pub fn scale_rows(self, d: impl IntoDualArray<'s>) -> Self {
+        let shape = self.shape();
+        assert_eq!(shape.len(), 2, "scale_rows expects a rank 2 array");
+        self * d.into_dual_array(self.scope).reshape([shape[0], 1])
+    }
+
+    pub fn scale_cols(self, d: impl IntoDualArray<'s>) -> Self {
+        let shape = self.shape();
+        assert_eq!(shape.len(), 2, "scale_cols expects a rank 2 array");
+        self * d.into_dual_array(self.scope).reshape([1, shape[1]])
+    }
+
+    pub fn transpose(self) -> Self {
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.transpose().with_empty_grad();
+        da.accumulate_owned(db.transpose());
+
+        (b, db).into()
+    }
+
+    pub fn no_fuse(self) -> Self {
+        let (a, da) = self.into_inner();
+        (a.no_fuse(), da).into()
+    }
+
+    pub fn scatter_add(
+        self,
+        values: impl IntoDualArray<'s>,
+        axis: impl IntoAxis,
+        indices: impl IntoUArray<'s>,
+    ) -> Self {
+        let (a, da) = self.into_inner();
+        let (b, db) = values.into_dual_array(self.scope).into_inner();
+        let indices = indices.into_array(self.scope);
+        let axis = axis.into_axis(a.shape());
+
+        // the gradient of each scattered value is read back from where it was added
+        let (c, dc) = a.scatter_add(b, axis, indices).with_empty_grad();
+        da.accumulate(dc);
+        db.accumulate_owned(dc.gather(axis, indices));
+
+        (c, dc).into()
+    }
+
+    pub fn pow(self, rhs: impl IntoDualArray<'s>) -> Self {
+        let (a, da) = self.into_inner();
+        let (b, db) = rhs.into_dual_array(self.scope).into_inner();
+
+        // c = a ^ b
+        let (c, dc) = a.pow(b).with_empty_grad();
+        da.accumulate_owned((dc * b * a.pow(b - 1.0)).unbroadcast(a.shape()));
+        if !db.is_zero_grad() {
+            db.accumulate_owned((dc * a.log() * c).unbroadcast(b.shape()));
+        }
+
+        (c, dc).into()
+    }
+
+    fn compare_and_select(
         self,
+        compare_mode: CompareMode,
         rhs: impl IntoDualArray<'s>,
         pass: impl IntoDualArray<'s>,
         fail: impl IntoDualArray<'s>,
@@ -997,19 +1746,55 @@ impl<'s> DualArray<'s> {
         let (pass, dpass) = pass.into_dual_array(self.scope).into_inner();
         let (fail, dfail) = fail.into_dual_array(self.scope).into_inner();
 
-        let (c, dc) = a.select_eq(b, pass, fail).with_empty_grad();
-        // TODO: da and db derivative?
-        dpass.accumulate(a.select_eq(b, dc, 0.0).unbroadcast(pass.shape()));
-        dfail.accumulate(a.select_eq(b, 0.0, dc).unbroadcast(fail.shape()));
+        // the result is piecewise constant in the compared values, so they get zero
+        // gradient, each branch gets the gradient where it was selected
+        let (c, dc) = a
+            .compare_and_select(compare_mode, b, pass, fail)
+            .with_empty_grad();
+        dpass.accumulate_owned(
+            a.compare_and_select(compare_mode, b, dc, 0.0)
+                .unbroadcast(pass.shape()),
+        );
+        dfail.accumulate_owned(
+            a.compare_and_select(compare_mode, b, 0.0, dc)
+                .unbroadcast(fail.shape()),
+        );
 
         (c, dc).into()
     }
 
+    pub fn select_eq(
+        self,
+        rhs: impl IntoDualArray<'s>,
+        pass: impl IntoDualArray<'s>,
+        fail: impl IntoDualArray<'s>,
+    ) -> Self {
+        self.compare_and_select(CompareMode::Eq, rhs, pass, fail)
+    }
+
+    pub fn where_eq(
+        self,
+        rhs: impl IntoDualArray<'s>,
+        pass: impl IntoDualArray<'s>,
+        fail: impl IntoDualArray<'s>,
+    ) -> Self {
+        self.compare_and_select(CompareMode::Eq, rhs, pass, fail)
+    }
+
+    pub fn where_gt(
+        self,
+        rhs: impl IntoDualArray<'s>,
+        pass: impl IntoDualArray<'s>,
+        fail: impl IntoDualArray<'s>,
+    ) -> Self {
+        self.compare_and_select(CompareMode::Gt, rhs, pass, fail)
+    }
+
     fn lock_axis_impl(self, axis: Axis, coord: usize) -> Self {
         let (a, da) = self.into_inner();
 
         let (b, db) = a.lock_axis(axis, coord, true).with_empty_grad();
-        da.accumulate(a.coord(axis).select_eq(coord as f32, db, 0.0));
+        da.accumulate_owned(a.coord(axis).select_eq(coord as f32, db, 0.0));
 
         (b, db).into()
     }
@@ -1019,6 +1804,33 @@ impl<'s> DualArray<'s> {
         self.lock_axis_impl(axis, coord).keep_axis(axis, keep_axis)
     }
 
+    pub fn reshape_infer(self, dims: &[isize]) -> Self {
+        self.reshape(self.shape().infer_reshape(dims))
+    }
+
+    pub fn broadcast_to(self, shape: impl Into<Shape>) -> Self {
+        let old_shape = self.shape();
+        let new_shape = shape.into();
+        assert!(
+            old_shape.len() <= new_shape.len()
+                && old_shape
+                    .iter()
+                    .rev()
+                    .zip(new_shape.iter().rev())
+                    .all(|(&from, &to)| from == to || from == 1),
+            "cannot broadcast {} to {}",
+            old_shape,
+            new_shape
+        );
+
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.broadcast(new_shape).with_empty_grad();
+        da.accumulate_owned(db.unbroadcast(old_shape));
+
+        (b, db).into()
+    }
+
     pub fn reshape(self, shape: impl Into<Shape>) -> Self {
         let old_shape = self.shape();
         let new_shape = shape.into();
@@ -1026,20 +1838,76 @@ impl<'s> DualArray<'s> {
         let (a, da) = self.into_inner();
 
         let (b, db) = a.reshape(new_shape).with_empty_grad();
-        da.accumulate(db.reshape(old_shape));
+        da.accumulate_owned(db.reshape(old_shape));
 
         (b, db).into()
     }
 
+    pub fn pad_with(self, axis: impl IntoAxis, before: usize, after: usize, value: f32) -> Self {
+        let shape = self.shape();
+        let axis = axis.into_axis(shape);
+
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.pad_with(axis, before, after, value).with_empty_grad();
+        da.accumulate_owned(db.limit_axis(axis, before..(before + shape[axis])));
+
+        (b, db).into()
+    }
+
+    pub fn diff(self, axis: impl IntoAxis) -> Self {
+        let axis = axis.into_axis(self.shape());
+
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.diff(axis).with_empty_grad();
+        da.accumulate_owned(db.zero_pad(axis, 1, 0) - db.zero_pad(axis, 0, 1));
+
+        (b, db).into()
+    }
+
+    pub fn temporal_smoothness(self, axis: impl IntoAxis) -> Self {
+        let axis = axis.into_axis(self.shape());
+        self.diff(axis).square().reduce_mean(axis, true)
+    }
+
     pub(crate) fn pad_image(self, pad: usize) -> Self {
         let (a, da) = self.into_inner();
 
         let (b, db) = a.pad_image(pad).with_empty_grad();
-        da.accumulate(db.unpad_image(pad));
+        da.accumulate_owned(db.unpad_image(pad));
 
         (b, db).into()
     }
 
+    fn reflect_pad(self, axis: Axis, pad: usize) -> Self {
+        let length = self.shape()[axis];
+        assert!(pad < length);
+        let mut output = self;
+        for i in 1..=pad {
+            output = self.lock_axis(axis, i, true).concat(output, axis);
+        }
+        for i in 1..=pad {
+            output = output.concat(self.lock_axis(axis, length - 1 - i, true), axis);
+        }
+        output
+    }
+
+    pub fn pad_image_mode(self, pad: usize, mode: PadMode) -> Self {
+        if pad == 0 {
+            return self;
+        }
+        match mode {
+            PadMode::Zero => self.pad_with(-3, pad, pad, 0.0).pad_with(-2, pad, pad, 0.0),
+            PadMode::Reflect => {
+                let shape = self.shape();
+                self.reflect_pad(shape.axis(-3), pad)
+                    .reflect_pad(shape.axis(-2), pad)
+            }
+            PadMode::Replicate => self.pad_image(pad),
+        }
+    }
+
     pub(crate) fn image_to_windows(
         self,
         filter: (usize, usize),
@@ -1049,7 +1917,7 @@ impl<'s> DualArray<'s> {
         let (a, da) = self.into_inner();
 
         let (b, db) = a.image_to_windows(filter, stride, groups).with_empty_grad();
-        da.accumulate(db.windows_to_image(stride));
+        da.accumulate_owned(db.windows_to_image(stride));
 
         (b, db).into()
     }
@@ -1103,7 +1971,7 @@ impl<'s> DualArray<'s> {
             ])
             .permute_axes(&[1, 0, 2]);
         let b = filter.reshape([filter_g, filter_oc, filter_h * filter_w * filter_ic]);
-        let c = a.batched_matmul(b.transpose(), MatMulOutputMode::Rows);
+        let c = a.batched_matmul(b.transpose(), MatMulOutputMode::Rows, true);
 
         // reshape output back to 4D
         c.permute_axes(&[1, 0, 2])
@@ -1131,8 +1999,9 @@ impl<'s> DualArray<'s> {
 
         let (b, db) = a.reduce_op(reduce_op, axis).with_empty_grad();
         match reduce_op {
-            ReduceOp::Max => da.accumulate(a.select_eq(b, db, 0.0)),
-            ReduceOp::Sum => da.accumulate(db.broadcast(da.shape())),
+            ReduceOp::Max => da.accumulate_owned(a.select_eq(b, db, 0.0)),
+            ReduceOp::Sum => da.accumulate_owned(db.broadcast(da.shape())),
+            ReduceOp::USum => unreachable!(),
         }
 
         (b, db).into()
@@ -1142,7 +2011,7 @@ impl<'s> DualArray<'s> {
         let (a, da) = self.into_inner();
 
         let (b, db) = a.insert_axis(axis).with_empty_grad();
-        da.accumulate(db.remove_axis(axis));
+        da.accumulate_owned(db.remove_axis(axis));
 
         (b, db).into()
     }
@@ -1151,7 +2020,7 @@ impl<'s> DualArray<'s> {
         let (a, da) = self.into_inner();
 
         let (b, db) = a.remove_axis(axis).with_empty_grad();
-        da.accumulate(db.insert_axis(axis));
+        da.accumulate_owned(db.insert_axis(axis));
 
         (b, db).into()
     }
@@ -1174,6 +2043,196 @@ impl<'s> DualArray<'s> {
         self.reduce_op(ReduceOp::Max, axis)
             .keep_axis(axis, keep_axis)
     }
+    pub fn reduce_mean(self, axis: impl IntoAxis, keep_axis: bool) -> Self {
+        let axis = axis.into_axis(self.shape());
+        let count = self.shape()[axis];
+        self.reduce_sum(axis, keep_axis) * (1.0 / (count as f32))
+    }
+
+    pub fn norm(self, p: f32, axis: impl IntoAxis, keep_axis: bool) -> Self {
+        let axis = axis.into_axis(self.shape());
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.norm(p, axis, true).with_empty_grad();
+
+        // d|x|_p/dx = sign(x) * (|x| / |x|_p)^(p - 1), zero where the norm is zero
+        if p == 1.0 {
+            da.accumulate_owned((a.greater_than(0.0) - a.less_than(0.0)) * db);
+        } else if p == 2.0 {
+            da.accumulate_owned((a * db).safe_div(b, 0.0));
+        } else {
+            let sign = a.greater_than(0.0) - a.less_than(0.0);
+            da.accumulate_owned(sign * a.abs().safe_div(b, 0.0).pow(p - 1.0) * db);
+        }
+
+        DualArray::from((b, db)).keep_axis(axis, keep_axis)
+    }
+
+    pub fn variance(self, axis: impl IntoAxis, keep_axis: bool, unbiased: bool) -> Self {
+        let axis = axis.into_axis(self.shape());
+        let count = variance_count(self.shape()[axis], unbiased);
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.variance(axis, true, unbiased).with_empty_grad();
+        let centered = a - a.reduce_mean(axis, true);
+        da.accumulate_owned(centered * db * (2.0 / count));
+
+        DualArray::from((b, db)).keep_axis(axis, keep_axis)
+    }
+
+    pub fn std(self, axis: impl IntoAxis, keep_axis: bool, unbiased: bool) -> Self {
+        let axis = axis.into_axis(self.shape());
+        let count = variance_count(self.shape()[axis], unbiased);
+        let (a, da) = self.into_inner();
+
+        // zero gradient where all elements are equal
+        let (b, db) = a.std(axis, true, unbiased).with_empty_grad();
+        let centered = a - a.reduce_mean(axis, true);
+        da.accumulate_owned((centered * db * (1.0 / count)).safe_div(b, 0.0));
+
+        DualArray::from((b, db)).keep_axis(axis, keep_axis)
+    }
+
+    pub fn rms_norm(self, axis: impl IntoAxis, gamma: impl IntoDualArray<'s>, eps: f32) -> Self {
+        let axis = axis.into_axis(self.shape());
+        let gamma = gamma.into_dual_array(self.scope);
+
+        let (x, dx) = self.into_inner();
+        let (g, dg) = gamma.into_inner();
+
+        let r = (x.square().reduce_mean(axis, true) + eps).rsqrt();
+        let x_hat = x * r;
+        let (y, dy) = (x_hat * g).with_empty_grad();
+
+        // the rms term depends on every element along the axis:
+        // dx = r * (dx_hat - x_hat * mean(dx_hat * x_hat))
+        let dx_hat = dy * g;
+        dx.accumulate_owned(r * (dx_hat - x_hat * (dx_hat * x_hat).reduce_mean(axis, true)));
+        dg.accumulate_owned((dy * x_hat).unbroadcast(g.shape()));
+
+        (y, dy).into()
+    }
+
+    pub fn l2_normalize(self, axis: impl IntoAxis, eps: f32) -> Self {
+        let axis = axis.into_axis(self.shape());
+        let (x, dx) = self.into_inner();
+
+        let r = (x.square().reduce_sum(axis, true) + eps).rsqrt();
+        let (y, dy) = (x * r).with_empty_grad();
+
+        // the norm depends on every element along the axis:
+        // dx = r * (dy - y * sum(dy * y))
+        dx.accumulate_owned(r * (dy - y * (dy * y).reduce_sum(axis, true)));
+
+        (y, dy).into()
+    }
+
+    pub fn layer_norm(
+        self,
+        gamma: impl IntoDualArray<'s>,
+        beta: impl IntoDualArray<'s>,
+        eps: f32,
+        normalized_axes: usize,
+    ) -> Self {
+        let shape = self.shape();
+        let gamma = gamma.into_dual_array(self.scope);
+        let beta = beta.into_dual_array(self.scope);
+
+        // flatten the normalized axes into a single trailing axis
+        let (outer, inner) = shape.rsplit_at(normalized_axes);
+        let inner_count: usize = inner.iter().product();
+        let flat_shape: Shape = outer.iter().copied().chain(Some(inner_count)).collect();
+        let axis = Axis::from_index(flat_shape.len() - 1);
+
+        let (x, dx) = self.into_inner();
+        let (g, dg) = gamma.into_inner();
+        let (b, db) = beta.into_inner();
+
+        let x_flat = x.reshape(flat_shape);
+        let centered = x_flat - x_flat.reduce_mean(axis, true);
+        let inv_std = (centered.square().reduce_mean(axis, true) + eps).rsqrt();
+        let x_hat = (centered * inv_std).reshape(shape);
+        let (y, dy) = (x_hat * g + b).with_empty_grad();
+
+        // dx = inv_std * (dx_hat - mean(dx_hat) - x_hat * mean(dx_hat * x_hat))
+        let dx_hat = (dy * g).reshape(flat_shape);
+        let x_hat_flat = x_hat.reshape(flat_shape);
+        dx.accumulate_owned(
+            (inv_std
+                * (dx_hat
+                    - dx_hat.reduce_mean(axis, true)
+                    - x_hat_flat * (dx_hat * x_hat_flat).reduce_mean(axis, true)))
+            .reshape(shape),
+        );
+        dg.accumulate_owned((dy * x_hat).unbroadcast(g.shape()));
+        db.accumulate_owned(dy.unbroadcast(b.shape()));
+
+        (y, dy).into()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn batch_norm(
+        self,
+        gamma: impl IntoDualArray<'s>,
+        beta: impl IntoDualArray<'s>,
+        running_mean: &Parameter,
+        running_var: &Parameter,
+        training: bool,
+        momentum: f32,
+        eps: f32,
+    ) -> Self {
+        let scope = self.scope;
+        let gamma = gamma.into_dual_array(scope);
+        let beta = beta.into_dual_array(scope);
+
+        let (x, dx) = self.into_inner();
+        let (g, dg) = gamma.into_inner();
+        let (b, db) = beta.into_inner();
+
+        let axis = Axis::from_index(0);
+        let stats_shape = x.shape().reduce(axis);
+        let (x_hat, inv_std) = if training {
+            let count = x.shape()[axis];
+            let mean = x.reduce_mean(axis, true);
+            let centered = x - mean;
+            let var = centered.square().reduce_mean(axis, true);
+            let inv_std = (var + eps).rsqrt();
+
+            // running variance tracks the unbiased estimate
+            let unbias = (count as f32) / ((count.max(2) - 1) as f32);
+            scope.update_parameter_value(running_mean, |rm| {
+                rm * (1.0 - momentum) + mean.reshape(rm.shape()) * momentum
+            });
+            scope.update_parameter_value(running_var, |rv| {
+                rv * (1.0 - momentum) + var.reshape(rv.shape()) * (momentum * unbias)
+            });
+
+            (centered * inv_std, inv_std)
+        } else {
+            let mean = scope.parameter_value(running_mean).reshape(stats_shape);
+            let var = scope.parameter_value(running_var).reshape(stats_shape);
+            let inv_std = (var + eps).rsqrt();
+            ((x - mean) * inv_std, inv_std)
+        };
+        let (y, dy) = (x_hat * g + b).with_empty_grad();
+
+        let dx_hat = dy * g;
+        if training {
+            // closed form gradient through the batch statistics
+            dx.accumulate_owned(
+                inv_std
+                    * (dx_hat
+                        - dx_hat.reduce_mean(axis, true)
+                        - x_hat * (dx_hat * x_hat).reduce_mean(axis, true)),
+            );
+        } else {
+            dx.accumulate_owned(inv_std * dx_hat);
+        }
+        dg.accumulate_owned((dy * x_hat).unbroadcast(g.shape()));
+        db.accumulate_owned(dy.unbroadcast(b.shape()));
+
+        (y, dy).into()
+    }
 
     pub fn flatten(self) -> Self {
         let shape = self.shape();
@@ -1203,7 +2262,61 @@ impl<'s> DualArray<'s> {
         let (a, da) = self.into_inner();
 
         let (b, db) = a.permute_axes(perm).with_empty_grad();
-        da.accumulate(db.permute_axes(&inv_perm));
+        da.accumulate_owned(db.permute_axes(&inv_perm));
+
+        (b, db).into()
+    }
+
+    pub fn swapaxes(self, a: impl IntoAxis, b: impl IntoAxis) -> Self {
+        let shape = self.shape();
+        self.permute_axes(&swapaxes_perm(
+            shape.len(),
+            a.into_axis(shape),
+            b.into_axis(shape),
+        ))
+    }
+
+    pub fn moveaxis(self, src: impl IntoAxis, dst: impl IntoAxis) -> Self {
+        let shape = self.shape();
+        self.permute_axes(&moveaxis_perm(
+            shape.len(),
+            src.into_axis(shape),
+            dst.into_axis(shape),
+        ))
+    }
+
+    pub fn diagonal(self) -> Self {
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.diagonal().with_empty_grad();
+        da.accumulate_owned(db.diag_embed());
+
+        (b, db).into()
+    }
+
+    pub fn diag_embed(self) -> Self {
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.diag_embed().with_empty_grad();
+        da.accumulate_owned(db.diagonal());
+
+        (b, db).into()
+    }
+
+    pub fn tril(self, diagonal: isize) -> Self {
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.tril(diagonal).with_empty_grad();
+        da.accumulate_owned(db.tril(diagonal));
+
+        (b, db).into()
+    }
+
+    pub fn triu(self, diagonal: isize) -> Self {
+        let (a, da) = self.into_inner();
+
+        let (b, db) = a.triu(diagonal).with_empty_grad();
+        da.accumulate_owned(db.triu(diagonal));
 
         (b, db).into()
     }
@@ -1219,8 +2332,8 @@ impl<'s> DualArray<'s> {
         let (b, db) = other.into_inner();
 
         let (c, dc) = a.concat(b, axis).with_empty_grad();
-        da.accumulate(dc.limit_axis(axis, ..length));
-        db.accumulate(dc.limit_axis(axis, length..));
+        da.accumulate_owned(dc.limit_axis(axis, ..length));
+        db.accumulate_owned(dc.limit_axis(axis, length..));
 
         (c, dc).into()
     }
@@ -1278,8 +2391,10 @@ where
         let (b, db) = rhs.into_inner();
 
         let (c, dc) = (a + b).with_empty_grad();
-        da.accumulate(dc.unbroadcast(a.shape()));
-        db.accumulate(dc.unbroadcast(b.shape()));
+        da.accumulate_owned(dc.unbroadcast(a.shape()));
+        if !db.is_zero_grad() {
+            db.accumulate_owned(dc.unbroadcast(b.shape()));
+        }
 
         (c, dc).into()
     }
@@ -1307,8 +2422,10 @@ where
         let (b, db) = rhs.into_inner();
 
         let (c, dc) = (a - b).with_empty_grad();
-        da.accumulate(dc.unbroadcast(a.shape()));
-        db.accumulate(-dc.unbroadcast(b.shape()));
+        da.accumulate_owned(dc.unbroadcast(a.shape()));
+        if !db.is_zero_grad() {
+            db.accumulate_owned(-dc.unbroadcast(b.shape()));
+        }
 
         (c, dc).into()
     }
@@ -1326,8 +2443,10 @@ where
         let (b, db) = rhs.into_inner();
 
         let (c, dc) = (a * b).with_empty_grad();
-        da.accumulate((b * dc).unbroadcast(a.shape()));
-        db.accumulate((a * dc).unbroadcast(b.shape()));
+        da.accumulate_owned((b * dc).unbroadcast(a.shape()));
+        if !db.is_zero_grad() {
+            db.accumulate_owned((a * dc).unbroadcast(b.shape()));
+        }
 
         (c, dc).into()
     }
@@ -1346,6 +2465,8 @@ struct ScopeState {
     parameters: SharedParameters,
     inputs: SparseSecondaryMap<ParameterId, GraphInput>,
     outputs: SparseSecondaryMap<ParameterId, OpNodeId>,
+    zero_grad_node_id: Option<OpNodeId>,
+    fuse_matmul_epilogues: bool,
 }
 
 pub struct Scope {
@@ -1362,6 +2483,8 @@ impl Scope {
                 parameters,
                 inputs: SparseSecondaryMap::new(),
                 outputs: SparseSecondaryMap::new(),
+                zero_grad_node_id: None,
+                fuse_matmul_epilogues: false,
             }),
         }
     }
@@ -1375,7 +2498,8 @@ impl Scope {
     }
 
     pub fn literal(&self, value: f32) -> DualArray {
-        self.with_state(|state| Array {
+        assert!(value.is_finite(), "literal value {} is not finite", value);
+        let value = self.with_state(|state| Array {
             node_id: state.ops.new_node(
                 state.next_colour,
                 [1],
@@ -1383,9 +2507,30 @@ impl Scope {
                 &[],
             ),
             scope: self,
+        });
+        (value, self.zero_grad()).into()
+    }
+
+    // Literals share a single zero gradient instead of each creating an accumulator
+    // move, and accumulating into it is skipped, so scalar arithmetic does not build
+    // backward ops that would only be removed as dead code.
+    fn zero_grad(&self) -> Array {
+        self.with_state(|state| {
+            let next_colour = state.next_colour;
+            let ops = &mut state.ops;
+            let node_id = *state.zero_grad_node_id.get_or_insert_with(|| {
+                ops.new_node(
+                    next_colour,
+                    [1],
+                    Op::Literal(Literal::F32(NotNan::new(0.0).unwrap())),
+                    &[],
+                )
+            });
+            Array {
+                node_id,
+                scope: self,
+            }
         })
-        .with_empty_grad()
-        .into()
     }
 
     pub fn literal_u32(&self, value: u32) -> UArray {
@@ -1436,12 +2581,117 @@ impl Scope {
         .into()
     }
 
+    pub fn from_coords<'s>(
+        &'s self,
+        shape: impl Into<Shape>,
+        f: impl FnOnce(&[Array<'s>]) -> Array<'s>,
+    ) -> Array<'s> {
+        // each coordinate array spans its own axis and has length 1 elsewhere, so that
+        // combining them broadcasts to the full shape
+        let shape = shape.into();
+        let coords: Vec<Array> = shape
+            .iter_axes()
+            .map(|axis| self.coord(shape[axis]).value().reshape(shape.coord(axis)))
+            .collect();
+        f(&coords).broadcast(shape)
+    }
+
+    pub fn meshgrid(&self, axes: &[usize]) -> Vec<Array> {
+        let shape: Shape = axes.iter().copied().collect();
+        shape
+            .iter_axes()
+            .map(|axis| {
+                self.coord(shape[axis])
+                    .value()
+                    .reshape(shape.coord(axis))
+                    .broadcast(shape)
+            })
+            .collect()
+    }
+
+    pub fn embedding_bag<'s>(
+        &'s self,
+        table: impl IntoDualArray<'s>,
+        indices: impl IntoUArray<'s>,
+        offsets: impl IntoUArray<'s>,
+        mode: EmbeddingBagMode,
+    ) -> DualArray<'s> {
+        let (table, dtable) = table.into_dual_array(self).into_inner();
+        let indices = indices.into_array(self);
+        let offsets = offsets.into_array(self);
+        let [row_count, dim]: [usize; 2] = table.shape().try_into().unwrap();
+        let [index_count]: [usize; 1] = indices.shape().try_into().unwrap();
+        let [bag_count]: [usize; 1] = offsets.shape().try_into().unwrap();
+
+        // each bag starts at its offset, so the bag of an index is the number of
+        // offsets at or before it (minus one)
+        let position = self.coord(index_count).value().reshape([1, index_count]);
+        let bag_index = position
+            .select_gt(offsets.into_f32().reshape([bag_count, 1]) - 0.5, 1.0, 0.0)
+            .reduce_sum(0, false)
+            - 1.0;
+        let bag_index = bag_index.into_u32();
+
+        let rows = table.gather(0, indices);
+        let zeros = self.literal(0.0).value().broadcast([bag_count, dim]);
+        let counts = self
+            .literal(0.0)
+            .value()
+            .broadcast([bag_count, 1])
+            .scatter_add(
+                self.literal(1.0).value().broadcast([index_count, 1]),
+                0,
+                bag_index,
+            );
+
+        let (output, doutput) = match mode {
+            EmbeddingBagMode::Sum => zeros.scatter_add(rows, 0, bag_index),
+            EmbeddingBagMode::Mean => zeros.scatter_add(rows, 0, bag_index).safe_div(counts, 0.0),
+            EmbeddingBagMode::Max => {
+                let in_bag = self
+                    .coord(bag_count)
+                    .value()
+                    .reshape([bag_count, 1, 1])
+                    .select_eq(
+                        bag_index.into_f32().reshape([1, index_count, 1]),
+                        rows.reshape([1, index_count, dim]),
+                        f32::MIN,
+                    )
+                    .reduce_max(1, false);
+                counts.select_eq(0.0, 0.0, in_bag)
+            }
+        }
+        .with_empty_grad();
+
+        // gradients of the pooled rows are scattered back to the rows that were used
+        let drows = match mode {
+            EmbeddingBagMode::Sum => doutput.gather(0, bag_index),
+            EmbeddingBagMode::Mean => doutput.safe_div(counts, 0.0).gather(0, bag_index),
+            EmbeddingBagMode::Max => {
+                output
+                    .gather(0, bag_index)
+                    .select_eq(rows, doutput.gather(0, bag_index), 0.0)
+            }
+        };
+        dtable.accumulate_owned(
+            self.literal(0.0)
+                .value()
+                .broadcast([row_count, dim])
+                .scatter_add(drows, 0, indices),
+        );
+
+        (output, doutput).into()
+    }
+
     fn input(&self, parameter: &Parameter) -> GraphInput {
         self.with_state(|state| {
             let parameter_id = parameter.checked_id(&state.parameters);
             let shape = state.parameters.borrow().get(parameter_id).unwrap().shape;
             let next_colour = state.next_colour;
             let ops = &mut state.ops;
+
+            // inputs belong to the scope, so each graph build starts from a new gradient
+            // node that only accumulates the gradients of this graph
             *state
                 .inputs
                 .entry(parameter_id)
@@ -1536,6 +2786,213 @@ impl Scope {
         })
     }
 
+    pub fn lr_schedule(&self, step: &Parameter, schedule: &impl Schedule) -> Array {
+        assert_eq!(step.shape(), Shape::from([1]));
+        schedule.graph_learning_rate(self.parameter_value(step))
+    }
+
+    fn new_op_with_views(&self, op: Op, shape: Shape, args: &[(OpNodeId, View)]) -> Array {
+        self.with_state(|state| {
+            let node_id = state.ops.new_node(state.next_colour, shape, op, &[]);
+            for (arg, (src_node_id, view)) in args.iter().copied().enumerate() {
+                state
+                    .ops
+                    .add_edge(src_node_id, node_id, OpEdge { arg, view });
+            }
+            Array {
+                node_id,
+                scope: self,
+            }
+        })
+    }
+
+    // Forward-mode differentiation: the tangent of each input is pushed through the ops
+    // that the output depends on, one new op per op in the common case, so the result
+    // is the Jacobian of the output applied to the input tangents.
+    pub fn jvp<'s>(&'s self, output: Array<'s>, inputs: &[(Array<'s>, Array<'s>)]) -> Array<'s> {
+        let mut tangents: HashMap<OpNodeId, OpNodeId> = HashMap::new();
+        for (x, t) in inputs.iter().copied() {
+            assert_eq!(x.shape(), t.shape(), "tangent shape must match its input");
+            tangents.insert(x.node_id, t.node_id);
+        }
+
+        // visit the ops that the output depends on in order
+        let node_ids: Vec<OpNodeId> = self.with_state(|state| {
+            let mut ancestors = HashSet::new();
+            let mut dfs = Dfs::new(Reversed(&state.ops), output.node_id);
+            while let Some(node_id) = dfs.next(Reversed(&state.ops)) {
+                ancestors.insert(node_id);
+            }
+            let mut topo = Topo::new(&state.ops);
+            let mut node_ids = Vec::new();
+            while let Some(node_id) = topo.next(&state.ops) {
+                if ancestors.contains(&node_id) {
+                    node_ids.push(node_id);
+                }
+            }
+            node_ids
+        });
+
+        for node_id in node_ids {
+            if tangents.contains_key(&node_id) {
+                continue;
+            }
+            let (op, shape, mut args) = self.with_state(|state| {
+                let args: Vec<(usize, OpNodeId, View)> = state
+                    .ops
+                    .edges_directed(node_id, Incoming)
+                    .map(|edge_ref| {
+                        (
+                            edge_ref.weight().arg,
+                            edge_ref.source(),
+                            edge_ref.weight().view,
+                        )
+                    })
+                    .collect();
+                (state.ops[node_id].op, state.ops[node_id].shape, args)
+            });
+            args.sort_by_key(|(arg, _, _)| *arg);
+            let args: Vec<(OpNodeId, View)> = args
+                .into_iter()
+                .map(|(_, src_node_id, view)| (src_node_id, view))
+                .collect();
+            let arg_tangents: Vec<Option<(OpNodeId, View)>> = args
+                .iter()
+                .map(|&(src_node_id, view)| {
+                    tangents
+                        .get(&src_node_id)
+                        .map(|&tangent_id| (tangent_id, view))
+                })
+                .collect();
+            if arg_tangents.iter().all(Option::is_none) {
+                continue;
+            }
+
+            let value = |index: usize| {
+                let (src_node_id, view) = args[index];
+                self.new_op_with_views(
+                    Op::Unary(UnaryOp::Mov),
+                    view.output_shape,
+                    &[(src_node_id, view)],
+                )
+            };
+            let tangent = |index: usize| match arg_tangents[index] {
+                Some((tangent_id, view)) => self.new_op_with_views(
+                    Op::Unary(UnaryOp::Mov),
+                    view.output_shape,
+                    &[(tangent_id, view)],
+                ),
+                None => self
+                    .literal(0.0)
+                    .value()
+                    .broadcast(args[index].1.output_shape),
+            };
+            let y = Array {
+                node_id,
+                scope: self,
+            };
+
+            // ops that are linear in some args are rebuilt with the tangents of those args
+            let linear = |linear_args: &[usize]| {
+                let new_args: Vec<(OpNodeId, View)> = args
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .map(|(index, (src_node_id, view))| {
+                        if linear_args.contains(&index) {
+                            arg_tangents[index].unwrap_or_else(|| {
+                                let zero = self.literal(0.0).value().broadcast(view.input_shape);
+                                (zero.node_id, view)
+                            })
+                        } else {
+                            (src_node_id, view)
+                        }
+                    })
+                    .collect();
+                self.new_op_with_views(op, shape, &new_args)
+            };
+
+            // products sum the terms from each arg that has a tangent
+            let bilinear = || {
+                (0..2)
+                    .filter_map(|index| {
+                        arg_tangents[index].map(|tangent_arg| {
+                            let mut new_args = args.clone();
+                            new_args[index] = tangent_arg;
+                            self.new_op_with_views(op, shape, &new_args)
+                        })
+                    })
+                    .reduce(|a, b| a + b)
+                    .unwrap()
+            };
+
+            let result = match op {
+                Op::Input { .. } | Op::Output { .. } | Op::Literal(_) | Op::BuiltIn(_) => None,
+                Op::Unary(unary_op) => match unary_op {
+                    UnaryOp::Mov | UnaryOp::Neg => Some(linear(&[0])),
+                    UnaryOp::Sqrt => Some(tangent(0) * 0.5 / y),
+                    UnaryOp::Rsqrt => Some(tangent(0) * -0.5 * y / value(0)),
+                    UnaryOp::Exp => Some(tangent(0) * y),
+                    UnaryOp::Log => Some(tangent(0) / value(0)),
+                    UnaryOp::Sin => Some(tangent(0) * value(0).cos()),
+                    UnaryOp::Cos => Some(-tangent(0) * value(0).sin()),
+                    UnaryOp::FloatToUint
+                    | UnaryOp::UintToFloat
+                    | UnaryOp::FloatToHalf
+                    | UnaryOp::HalfToFloat => None,
+                },
+                Op::Binary(binary_op) => match binary_op {
+                    BinaryOp::Add | BinaryOp::Sub => Some(linear(&[0, 1])),
+                    BinaryOp::Mul => Some(bilinear()),
+                    BinaryOp::Div => Some((tangent(0) - tangent(1) * y) / value(1)),
+                    BinaryOp::Pow => {
+                        let (a, b) = (value(0), value(1));
+                        let da = arg_tangents[0].map(|_| tangent(0) * b * a.pow(b - 1.0));
+                        let db = arg_tangents[1].map(|_| tangent(1) * a.log() * y);
+                        da.into_iter().chain(db).reduce(|a, b| a + b)
+                    }
+                    _ => {
+                        assert!(binary_op.is_uint());
+                        None
+                    }
+                },
+                Op::CompareAndSelect(_) => {
+                    if arg_tangents[2].is_some() || arg_tangents[3].is_some() {
+                        Some(linear(&[2, 3]))
+                    } else {
+                        None
+                    }
+                }
+                Op::MatMul { .. } => Some(bilinear()),
+                Op::Reduce { reduce_op, axis } => match reduce_op {
+                    ReduceOp::Sum => Some(linear(&[0])),
+                    // ties pass on the sum of their tangents
+                    ReduceOp::Max => Some(
+                        value(0)
+                            .select_eq(y, tangent(0), 0.0)
+                            .reduce_op(ReduceOp::Sum, axis),
+                    ),
+                    ReduceOp::USum => None,
+                },
+                Op::Unpad { .. } | Op::WindowsToImage { .. } | Op::Gather { .. } => {
+                    Some(linear(&[0]))
+                }
+                Op::ScatterAdd { .. } => Some(linear(&[0, 1])),
+            };
+            if let Some(result) = result {
+                tangents.insert(node_id, result.node_id);
+            }
+        }
+
+        match tangents.get(&output.node_id) {
+            Some(&node_id) => Array {
+                node_id,
+                scope: self,
+            },
+            None => self.literal(0.0).value().broadcast(output.shape()),
+        }
+    }
+
     pub fn trainable_parameters(&self) -> Vec<Parameter> {
         self.with_state(|state| {
             let mut v = Vec::new();
@@ -1551,12 +3008,119 @@ impl Scope {
         })
     }
 
+    // groups are in order of first use, for building a ParameterGroup from each
+    pub fn trainable_parameter_groups(
+        &self,
+        group_name: impl Fn(&Parameter) -> String,
+    ) -> Vec<(String, Vec<Parameter>)> {
+        let mut groups: Vec<(String, Vec<Parameter>)> = Vec::new();
+        for parameter in self.trainable_parameters() {
+            let name = group_name(&parameter);
+            match groups.iter_mut().find(|(group, _)| *group == name) {
+                Some((_, parameters)) => parameters.push(parameter),
+                None => groups.push((name, vec![parameter])),
+            }
+        }
+        groups
+    }
+
+    // Experimental: runs a per-element kernel that only consumes the result of a matrix
+    // multiply (such as the bias add and activation of a linear layer) as an epilogue
+    // of the matrix multiply kernel instead of as a separate kernel.
+    pub fn set_fuse_matmul_epilogues(&self, fuse: bool) {
+        self.with_state(|state| state.fuse_matmul_epilogues = fuse);
+    }
+
     pub fn build_graph(self) -> Graph {
         self.with_state(|state| {
             Graph::new(
                 SharedParameters::clone(&state.parameters),
                 state.ops.clone(),
+                state.fuse_matmul_epilogues,
             )
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broadcast_same_shape() {
+        let scope = Scope::new(Default::default());
+
+        let x = scope.coord(4).value().reshape([1, 4]);
+        assert_eq!(x.broadcast(x.shape()).node_id, x.node_id);
+        assert_ne!(x.broadcast([3, 4]).node_id, x.node_id);
+    }
+
+    #[test]
+    fn literal_grads_are_not_accumulated() {
+        let scope = Scope::new(Default::default());
+
+        let mut x = scope.coord(20).reshape([4, 5]);
+        for _ in 0..5 {
+            x = x * 2.0 + 1.0;
+        }
+
+        // without skipping the literal gradients this builds about 100 nodes
+        let node_count = scope.with_state(|state| state.ops.node_count());
+        assert!(node_count < 70, "{} nodes before optimization", node_count);
+    }
+
+    #[test]
+    fn accumulate_keeps_moves() {
+        let scope = Scope::new(Default::default());
+
+        let (_, dx) = scope.coord(6).into_inner();
+        let y = scope.coord(6).value().reshape([2, 3]).reshape([6]);
+        dx.accumulate(y);
+
+        // the caller may still use the accumulated array
+        assert!(scope.with_state(|state| state.ops.contains_node(y.node_id)));
+        assert_eq!(y.shape(), Shape::from([6]));
+    }
+
+    #[test]
+    fn gradient_moves_are_collapsed() {
+        use crate::{loss::*, module::*};
+
+        let mut env = Environment::new();
+
+        let m = 16;
+        let mlp = Mlp::new(&mut env, &[784, 300, 10], Activation::LeakyRelu(0.01));
+        let x_param = env.static_parameter([m, 784], "x");
+        let y_param = env.static_parameter([m, 1], "y");
+
+        let scope = env.scope();
+        let x = mlp.train(scope.parameter(&x_param));
+        softmax_cross_entropy_loss(x, &y_param).set_loss();
+
+        // each gradient is only accumulated once, so the reshapes on either side of
+        // the weight matmuls and the bias reductions do not leave moves behind
+        let parameters = scope.trainable_parameters();
+        assert_eq!(parameters.len(), 4);
+        for parameter in parameters.iter() {
+            let mut node_id = scope.parameter(parameter).loss_grad().node_id;
+            let move_count = scope.with_state(|state| {
+                let mut move_count = 0;
+                while state.ops[node_id].op == Op::Unary(UnaryOp::Mov) {
+                    move_count += 1;
+                    node_id = state
+                        .ops
+                        .neighbors_directed(node_id, Incoming)
+                        .next()
+                        .unwrap();
+                }
+                move_count
+            });
+            assert!(
+                move_count <= 2,
+                "gradient of {} is {} moves from its reduction",
+                parameter.name(),
+                move_count
+            );
+        }
+    }
+}
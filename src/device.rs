@@ -1,9 +1,10 @@
 pub mod buffer_heap;
 pub mod command_buffer;
 pub mod context;
+pub mod cpu;
 pub mod descriptor_pool;
 pub mod fence;
-mod heap;
+pub(crate) mod heap;
 pub mod staging;
 pub mod timestamp;
 pub(crate) mod common {
@@ -1,5 +1,5 @@
 use super::{common::*, heap::*};
-use spark::vk;
+use spark::{vk, Device};
 
 slotmap::new_key_type! {
     pub(crate) struct BufferId;
@@ -11,14 +11,31 @@ struct ChunkIndex(usize);
 struct Chunk {
     device_memory: vk::DeviceMemory,
     buffer: vk::Buffer,
+    size: usize,
+}
+
+impl Chunk {
+    fn destroy(self, device: &Device) {
+        unsafe {
+            device.destroy_buffer(Some(self.buffer), None);
+            device.free_memory(Some(self.device_memory), None);
+        }
+    }
 }
 
 pub(crate) struct BufferHeap {
     context: SharedContext,
-    chunks: Vec<Chunk>,
+    chunks: Vec<Option<Chunk>>,
     heap: Heap<BufferId, ChunkIndex>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufferReport {
+    pub buffer_count: usize,
+    pub total_bytes: usize,
+    pub chunks: Vec<HeapStats>,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct BufferInfo {
     pub(crate) buffer: vk::Buffer,
@@ -67,11 +84,22 @@ impl BufferHeap {
         };
         unsafe { device.bind_buffer_memory(buffer, device_memory, 0) }.unwrap();
 
-        let chunk_index = ChunkIndex(self.chunks.len());
-        self.chunks.push(Chunk {
+        // reuse the slot of a released chunk if there is one
+        let chunk = Chunk {
             device_memory,
             buffer,
-        });
+            size: chunk_size,
+        };
+        let chunk_index = match self.chunks.iter().position(Option::is_none) {
+            Some(index) => {
+                self.chunks[index] = Some(chunk);
+                ChunkIndex(index)
+            }
+            None => {
+                self.chunks.push(Some(chunk));
+                ChunkIndex(self.chunks.len() - 1)
+            }
+        };
 
         self.heap.extend_with(chunk_index, chunk_size);
     }
@@ -97,6 +125,10 @@ impl BufferHeap {
         }
     }
 
+    pub(crate) fn set_strategy(&mut self, strategy: HeapStrategy) {
+        self.heap.set_strategy(strategy);
+    }
+
     pub(crate) fn free(&mut self, id: BufferId) {
         self.heap.free(id);
     }
@@ -104,25 +136,63 @@ impl BufferHeap {
     pub(crate) fn info(&self, id: BufferId) -> BufferInfo {
         let info = self.heap.info(id);
         BufferInfo {
-            buffer: self.chunks[info.tag.0].buffer,
+            buffer: self.chunks[info.tag.0].as_ref().unwrap().buffer,
             range: info.range,
         }
     }
 
-    #[allow(dead_code)]
+    // frees the device memory of chunks that have no buffers allocated, returns the
+    // number of bytes released
+    pub(crate) fn release_empty_chunks(&mut self) -> usize {
+        let device = &self.context.device;
+        let mut size = 0;
+        for chunk_index in self.heap.try_release_empty_arenas() {
+            let chunk = self.chunks[chunk_index.0].take().unwrap();
+            size += chunk.size;
+            chunk.destroy(device);
+        }
+        size
+    }
+
     pub(crate) fn heap_stats(&self) -> HeapStats {
         self.heap.stats()
     }
+
+    pub(crate) fn heap_layout(&self) -> Vec<Vec<(HeapRange, bool)>> {
+        let mut chunks: Vec<_> = self
+            .heap
+            .arenas()
+            .map(|first_id| (self.heap.info(first_id).tag, first_id))
+            .collect();
+        chunks.sort_by_key(|(chunk_index, _)| chunk_index.0);
+        chunks
+            .into_iter()
+            .map(|(_, first_id)| {
+                self.heap
+                    .blocks_in_order(first_id)
+                    .map(|(_, range, is_free)| (range, is_free))
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub(crate) fn buffer_report(&self) -> BufferReport {
+        let mut chunks = self.heap.arena_stats();
+        chunks.sort_by_key(|(chunk_index, _)| chunk_index.0);
+        let chunks: Vec<HeapStats> = chunks.into_iter().map(|(_, stats)| stats).collect();
+        BufferReport {
+            buffer_count: chunks.iter().map(|stats| stats.alloc_count).sum(),
+            total_bytes: chunks.iter().map(|stats| stats.total_alloc_size).sum(),
+            chunks,
+        }
+    }
 }
 
 impl Drop for BufferHeap {
     fn drop(&mut self) {
         let device = &self.context.device;
-        for chunk in self.chunks.drain(..) {
-            unsafe {
-                device.destroy_buffer(Some(chunk.buffer), None);
-                device.free_memory(Some(chunk.device_memory), None);
-            }
+        for chunk in self.chunks.drain(..).flatten() {
+            chunk.destroy(device);
         }
     }
 }
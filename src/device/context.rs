@@ -16,9 +16,98 @@ impl PhysicalDeviceMemoryPropertiesExt for vk::PhysicalDeviceMemoryProperties {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelection {
+    Index(usize),
+    NameContains(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub index: usize,
+    pub name: String,
+    pub device_type: String,
+    pub device_local_memory: u64,
+}
+
+// device local memory in bytes, free memory is an estimate that includes other processes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryBudget {
+    pub total: u64,
+    pub free: u64,
+}
+
+fn create_instance(version: vk::Version) -> Instance {
+    let loader = Loader::new().unwrap();
+
+    let available_extensions = {
+        let extension_properties =
+            unsafe { loader.enumerate_instance_extension_properties_to_vec(None) }.unwrap();
+        InstanceExtensions::from_properties(version, &extension_properties)
+    };
+
+    let mut extensions = InstanceExtensions::new(version);
+    if available_extensions.supports_ext_debug_utils() {
+        extensions.enable_ext_debug_utils();
+    }
+    if available_extensions.supports_ext_shader_atomic_float() {
+        extensions.enable_ext_shader_atomic_float();
+    }
+    let extension_names = extensions.to_name_vec();
+
+    let app_info = vk::ApplicationInfo::builder()
+        .p_application_name(Some(CStr::from_bytes_with_nul(b"caldera\0").unwrap()))
+        .api_version(version);
+
+    let extension_name_ptrs: Vec<_> = extension_names.iter().map(|s| s.as_ptr()).collect();
+    let instance_create_info = vk::InstanceCreateInfo::builder()
+        .p_application_info(Some(&app_info))
+        .pp_enabled_extension_names(&extension_name_ptrs);
+    unsafe { loader.create_instance(&instance_create_info, None) }.unwrap()
+}
+
+fn device_infos(instance: &Instance) -> Vec<(vk::PhysicalDevice, DeviceInfo)> {
+    unsafe { instance.enumerate_physical_devices_to_vec() }
+        .unwrap()
+        .iter()
+        .copied()
+        .enumerate()
+        .map(|(index, physical_device)| {
+            let props = unsafe { instance.get_physical_device_properties(physical_device) };
+            let memory_props =
+                unsafe { instance.get_physical_device_memory_properties(physical_device) };
+            let device_local_memory = memory_props
+                .heaps()
+                .iter()
+                .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+                .map(|heap| heap.size)
+                .sum();
+            let info = DeviceInfo {
+                index,
+                name: unsafe { CStr::from_ptr(props.device_name.as_ptr()) }
+                    .to_string_lossy()
+                    .into_owned(),
+                device_type: format!("{}", props.device_type),
+                device_local_memory,
+            };
+            (physical_device, info)
+        })
+        .collect()
+}
+
+pub fn enumerate_devices() -> Vec<DeviceInfo> {
+    let instance = create_instance(vk::Version::default());
+    let infos = device_infos(&instance)
+        .into_iter()
+        .map(|(_, info)| info)
+        .collect();
+    unsafe { instance.destroy_instance(None) };
+    infos
+}
+
 pub(crate) struct Context {
     pub(crate) instance: Instance,
-    pub(crate) _physical_device: vk::PhysicalDevice,
+    pub(crate) physical_device: vk::PhysicalDevice,
     pub(crate) physical_device_properties: vk::PhysicalDeviceProperties,
     pub(crate) physical_device_memory_properties: vk::PhysicalDeviceMemoryProperties,
     pub(crate) queue_family_index: u32,
@@ -26,54 +115,34 @@ pub(crate) struct Context {
     pub(crate) queue: vk::Queue,
     pub(crate) device: Device,
     pub(crate) has_shader_atomic_float_add: bool,
+    pub(crate) has_memory_budget: bool,
 }
 
 pub(crate) type SharedContext = Rc<Context>;
 
 impl Context {
-    pub(crate) fn new() -> SharedContext {
+    // without a selection the first device is used
+    pub(crate) fn new(selection: Option<&DeviceSelection>) -> SharedContext {
         let version = vk::Version::default();
-        let instance = {
-            let loader = Loader::new().unwrap();
-
-            let available_extensions = {
-                let extension_properties =
-                    unsafe { loader.enumerate_instance_extension_properties_to_vec(None) }.unwrap();
-                InstanceExtensions::from_properties(version, &extension_properties)
-            };
-
-            let mut extensions = InstanceExtensions::new(version);
-            if available_extensions.supports_ext_debug_utils() {
-                extensions.enable_ext_debug_utils();
-            }
-            if available_extensions.supports_ext_shader_atomic_float() {
-                extensions.enable_ext_shader_atomic_float();
-            }
-            let extension_names = extensions.to_name_vec();
-
-            let app_info = vk::ApplicationInfo::builder()
-                .p_application_name(Some(CStr::from_bytes_with_nul(b"caldera\0").unwrap()))
-                .api_version(version);
-
-            let extension_name_ptrs: Vec<_> = extension_names.iter().map(|s| s.as_ptr()).collect();
-            let instance_create_info = vk::InstanceCreateInfo::builder()
-                .p_application_info(Some(&app_info))
-                .pp_enabled_extension_names(&extension_name_ptrs);
-            unsafe { loader.create_instance(&instance_create_info, None) }.unwrap()
-        };
+        let instance = create_instance(version);
 
         let physical_device = {
-            let physical_devices = unsafe { instance.enumerate_physical_devices_to_vec() }.unwrap();
-            for (i, physical_device) in physical_devices.iter().enumerate() {
-                let props = unsafe { instance.get_physical_device_properties(*physical_device) };
+            let devices = device_infos(&instance);
+            for (_, info) in devices.iter() {
                 println!(
                     "physical device {}: {:?} ({})",
-                    i,
-                    unsafe { CStr::from_ptr(props.device_name.as_ptr()) },
-                    props.device_type
+                    info.index, info.name, info.device_type
                 );
             }
-            physical_devices[0]
+            let (physical_device, _) = match selection {
+                None => devices.first(),
+                Some(DeviceSelection::Index(index)) => devices.get(*index),
+                Some(DeviceSelection::NameContains(name)) => devices
+                    .iter()
+                    .find(|(_, info)| info.name.contains(name.as_str())),
+            }
+            .unwrap_or_else(|| panic!("no physical device matches {:?}", selection));
+            *physical_device
         };
         let physical_device_properties =
             unsafe { instance.get_physical_device_properties(physical_device) };
@@ -112,6 +181,7 @@ impl Context {
         };
 
         let mut has_shader_atomic_float_add = false;
+        let mut has_memory_budget = false;
         let device = {
             let queue_priorities = [1.0];
             let device_queue_create_info = vk::DeviceQueueCreateInfo::builder()
@@ -136,6 +206,14 @@ impl Context {
                 shader_atomic_float_features.shader_buffer_float32_atomic_add = vk::TRUE;
                 has_shader_atomic_float_add = true;
             }
+            if available_extensions.supports_ext_memory_budget()
+                && instance
+                    .extensions
+                    .supports_khr_get_physical_device_properties2()
+            {
+                extensions.enable_ext_memory_budget();
+                has_memory_budget = true;
+            }
             let extension_names = extensions.to_name_vec();
 
             let extension_name_ptrs: Vec<_> = extension_names.iter().map(|s| s.as_ptr()).collect();
@@ -152,7 +230,7 @@ impl Context {
 
         SharedContext::new(Self {
             instance,
-            _physical_device: physical_device,
+            physical_device,
             physical_device_properties,
             physical_device_memory_properties,
             queue_family_index,
@@ -160,9 +238,44 @@ impl Context {
             queue,
             device,
             has_shader_atomic_float_add,
+            has_memory_budget,
         })
     }
 
+    // without VK_EXT_memory_budget the usage is unknown, so all memory is reported free
+    pub(crate) fn memory_budget(&self) -> MemoryBudget {
+        let mut budget_properties = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+        if self.has_memory_budget {
+            let mut properties = vk::PhysicalDeviceMemoryProperties2KHR::builder()
+                .insert_next(&mut budget_properties);
+            unsafe {
+                self.instance.get_physical_device_memory_properties2_khr(
+                    self.physical_device,
+                    properties.get_mut(),
+                );
+            }
+        }
+
+        let mut budget = MemoryBudget { total: 0, free: 0 };
+        for (index, heap) in self
+            .physical_device_memory_properties
+            .heaps()
+            .iter()
+            .enumerate()
+        {
+            if heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL) {
+                budget.total += heap.size;
+                budget.free += if self.has_memory_budget {
+                    let heap_budget = budget_properties.heap_budget[index].min(heap.size);
+                    heap_budget.saturating_sub(budget_properties.heap_usage[index])
+                } else {
+                    heap.size
+                };
+            }
+        }
+        budget
+    }
+
     pub(crate) fn get_memory_type_index(
         &self,
         type_filter: u32,
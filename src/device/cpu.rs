@@ -0,0 +1,629 @@
+use crate::{common::*, graph::Cluster};
+use petgraph::visit::{IntoNodeReferences, NodeIndexable, NodeRef};
+use rand::Rng;
+use slotmap::{SecondaryMap, SlotMap};
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
+
+fn grid_coord(mut remain: usize, shape: Shape) -> Vec<isize> {
+    let mut coord = vec![0; shape.len()];
+    for (c, n) in coord.iter_mut().zip(shape.iter().copied()).rev() {
+        *c = (remain % n) as isize;
+        remain /= n;
+    }
+    coord
+}
+
+fn load_index(view: &View, coord: &[isize]) -> usize {
+    let input_strides = view.input_shape.strides();
+    let mut index = 0;
+    for (input_index, input_stride) in input_strides.iter().copied().enumerate() {
+        let input_axis = Axis::from_index(input_index);
+        let mut c = view.input_offsets[input_index];
+        for (coord_index, mapping) in view.output_mapping.iter().copied().enumerate() {
+            match mapping {
+                AxisMapping::Source { axis, step } => {
+                    if axis == input_axis {
+                        c += step * coord[coord_index];
+                    }
+                }
+                AxisMapping::Broadcast => {}
+            }
+        }
+        if view.input_needs_clamp(input_axis) {
+            c = c.clamp(0, (view.input_shape[input_axis] - 1) as isize);
+        }
+        index += (c as usize) * input_stride;
+    }
+    index
+}
+
+fn pcg(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+fn rand_from_index(rand_seed: u32, uid: usize, index: usize) -> f32 {
+    let hash = pcg(pcg(index as u32)
+        .wrapping_add(rand_seed)
+        .wrapping_add(uid as u32));
+    (hash as f32) / (u32::MAX as f32)
+}
+
+fn run_per_element(
+    kernel: &PerElementKernel,
+    inputs: &[&[f32]],
+    outputs: &mut [Vec<f32>],
+    rand_seed: u32,
+) {
+    let mut tmp: Vec<f32> = Vec::with_capacity(kernel.ops.len());
+    for element_index in 0..kernel.element_count {
+        tmp.clear();
+        for op in kernel.ops.iter() {
+            let value = match *op {
+                PerElementKernelOp::Load { input_index } => {
+                    let view = &kernel.inputs[input_index];
+                    let coord = grid_coord(element_index, view.output_shape);
+                    inputs[input_index][load_index(view, &coord)]
+                }
+                PerElementKernelOp::Literal(value) => value.to_f32_bits(),
+                PerElementKernelOp::BuiltIn { op, view } => {
+                    let coord = grid_coord(element_index, view.output_shape);
+                    let index = load_index(&view, &coord);
+                    match op {
+                        BuiltInOp::Coord => index as f32,
+                        BuiltInOp::Rand { uid } => rand_from_index(rand_seed, uid, index),
+                    }
+                }
+                PerElementKernelOp::Unary { op, args } => op.eval(tmp[args]),
+                PerElementKernelOp::Binary { op, args } => op.eval(tmp[args[0]], tmp[args[1]]),
+                PerElementKernelOp::CompareAndSelect { compare_mode, args } => {
+                    let pass = compare_mode.eval(tmp[args[0]], tmp[args[1]]);
+                    tmp[args[if pass { 2 } else { 3 }]]
+                }
+                PerElementKernelOp::Gather {
+                    shape,
+                    axis,
+                    input_index,
+                    arg,
+                } => {
+                    let view = &kernel.inputs[input_index];
+                    let index = tmp[arg].to_bits() as usize;
+                    if index < view.output_shape[axis] {
+                        let mut coord = grid_coord(element_index, shape);
+                        coord[axis.index()] = index as isize;
+                        inputs[input_index][load_index(view, &coord)]
+                    } else {
+                        0.0
+                    }
+                }
+            };
+            tmp.push(value);
+        }
+        for (output, src_index) in outputs.iter_mut().zip(kernel.outputs.iter().copied()) {
+            output[element_index] = tmp[src_index];
+        }
+    }
+}
+
+fn pairwise_sum(values: &[f32]) -> f32 {
+    if values.len() == 1 {
+        values[0]
+    } else {
+        // split at a power of two to match the order of the GPU kernel
+        let mid = values.len().next_power_of_two() / 2;
+        pairwise_sum(&values[..mid]) + pairwise_sum(&values[mid..])
+    }
+}
+
+fn run_reduce(kernel: &ReduceKernel, input: &[f32], output: &mut [f32]) {
+    let k = kernel.input.output_shape[kernel.axis];
+    for (element_index, result) in output.iter_mut().enumerate() {
+        let mut coord = grid_coord(element_index, kernel.shape);
+        if kernel.pairwise {
+            let values: Vec<f32> = (0..k)
+                .map(|index| {
+                    coord[kernel.axis.index()] = index as isize;
+                    input[load_index(&kernel.input, &coord)]
+                })
+                .collect();
+            *result = pairwise_sum(&values);
+            continue;
+        }
+        *result = match kernel.reduce_op {
+            ReduceOp::Max => f32::NEG_INFINITY,
+            ReduceOp::Sum => 0.0,
+            ReduceOp::USum => f32::from_bits(0),
+        };
+        for index in 0..k {
+            coord[kernel.axis.index()] = index as isize;
+            let value = input[load_index(&kernel.input, &coord)];
+            match kernel.reduce_op {
+                ReduceOp::Max => *result = result.max(value),
+                ReduceOp::Sum => *result += value,
+                ReduceOp::USum => {
+                    *result = f32::from_bits(result.to_bits().wrapping_add(value.to_bits()))
+                }
+            }
+        }
+    }
+}
+
+fn run_matmul(kernel: &MatMulKernel, a: &[f32], b: &[f32], output: &mut [f32]) {
+    let m = kernel.m();
+    let n = kernel.n();
+    let k = kernel.k();
+    let k_chunk_count = kernel.k_chunk_count();
+    let k_chunk_size = k.div_round_up(k_chunk_count);
+    let batch_count = kernel.batch_count();
+    let load = |value: f32| match kernel.input_type {
+        StorageType::F32 => value,
+        StorageType::F16 => f16_bits_to_f32(value.to_bits() & 0xffff),
+    };
+    let (batch_stride, row_stride) = match kernel.output_mode {
+        MatMulOutputMode::Batches => (m * n, n),
+        MatMulOutputMode::Rows => (n, batch_count * n),
+    };
+    for k_chunk_index in 0..k_chunk_count {
+        let k_begin = k_chunk_index * k_chunk_size;
+        let k_end = (k_begin + k_chunk_size).min(k);
+        for batch_index in 0..batch_count {
+            for y in 0..m {
+                for x in 0..n {
+                    let (bi, yi, xi) = (batch_index as isize, y as isize, x as isize);
+                    let mut sum = 0.0;
+                    for k_index in (k_begin as isize)..(k_end as isize) {
+                        sum += load(a[load_index(&kernel.a, &[bi, yi, k_index])])
+                            * load(b[load_index(&kernel.b, &[bi, k_index, xi])]);
+                    }
+                    let index = k_chunk_index * batch_count * m * n
+                        + batch_index * batch_stride
+                        + y * row_stride
+                        + x;
+                    if kernel.accumulate {
+                        output[index] += sum;
+                    } else {
+                        output[index] = sum;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn run_unpad(kernel: &UnpadKernel, input: &[f32], output: &mut [f32]) {
+    let axis = kernel.axis.index();
+    let pad = kernel.pad as isize;
+    let last = (kernel.shape[kernel.axis] - 1) as isize;
+    for (element_index, result) in output.iter_mut().enumerate() {
+        let mut coord = grid_coord(element_index, kernel.shape);
+        let out_coord = coord[axis];
+        let in_coord = out_coord + pad;
+        let k_min = in_coord - if out_coord == 0 { pad } else { 0 };
+        let k_max = in_coord + if out_coord == last { pad } else { 0 };
+        *result = 0.0;
+        for k in k_min..=k_max {
+            coord[axis] = k;
+            *result += input[load_index(&kernel.input, &coord)];
+        }
+    }
+}
+
+fn run_windows_to_image(kernel: &WindowsToImageKernel, input: &[f32], output: &mut [f32]) {
+    let (_, suffix) = kernel.input.output_shape.rsplit_at(6);
+    let (out_h, out_w, filter_h, filter_w, group_nc) =
+        (suffix[0], suffix[1], suffix[3], suffix[4], suffix[5]);
+    let (stride_w, stride_h) = kernel.stride;
+    let batch_dims = kernel.shape.len() - 3;
+    for (element_index, result) in output.iter_mut().enumerate() {
+        let coord = grid_coord(element_index, kernel.shape);
+        let in_y = coord[batch_dims] as usize;
+        let in_x = coord[batch_dims + 1] as usize;
+        let in_c = coord[batch_dims + 2] as usize;
+        let group_index = in_c / group_nc;
+        let group_c = in_c % group_nc;
+
+        let mut in_coord = coord[..batch_dims].to_vec();
+        in_coord.resize(batch_dims + 6, 0);
+        *result = 0.0;
+        for filter_y in ((in_y % stride_h)..filter_h).step_by(stride_h) {
+            for filter_x in ((in_x % stride_w)..filter_w).step_by(stride_w) {
+                // skip windows that do not exist
+                if filter_y > in_y || filter_x > in_x {
+                    continue;
+                }
+                let out_y = (in_y - filter_y) / stride_h;
+                let out_x = (in_x - filter_x) / stride_w;
+                if out_y >= out_h || out_x >= out_w {
+                    continue;
+                }
+                in_coord[batch_dims] = out_y as isize;
+                in_coord[batch_dims + 1] = out_x as isize;
+                in_coord[batch_dims + 2] = group_index as isize;
+                in_coord[batch_dims + 3] = filter_y as isize;
+                in_coord[batch_dims + 4] = filter_x as isize;
+                in_coord[batch_dims + 5] = group_c as isize;
+                *result += input[load_index(&kernel.input, &in_coord)];
+            }
+        }
+    }
+}
+
+fn run_scatter_add(kernel: &ScatterAddKernel, values: &[f32], indices: &[f32], output: &mut [f32]) {
+    let axis = kernel.axis.index();
+    let output_view = kernel.shape.identity_view();
+    for element_index in 0..kernel.values.output_shape.element_count() {
+        let mut coord = grid_coord(element_index, kernel.values.output_shape);
+        let value = values[load_index(&kernel.values, &coord)];
+        let index = indices[load_index(&kernel.indices, &[coord[axis]])];
+        let index = (index.to_bits() as i32) as isize;
+
+        // out of range indices are dropped, as on the GPU
+        if index < 0 || index >= kernel.shape[kernel.axis] as isize {
+            continue;
+        }
+        coord[axis] = index;
+        output[load_index(&output_view, &coord)] += value;
+    }
+}
+
+fn run_kernel(kernel: &GenericKernel, inputs: &[&[f32]], outputs: &mut [Vec<f32>], rand_seed: u32) {
+    match kernel {
+        GenericKernel::Fill(kernel) => {
+            let value = kernel.value.to_f32_bits();
+            outputs[0].iter_mut().for_each(|x| *x = value);
+        }
+        GenericKernel::PerElement(kernel) => run_per_element(kernel, inputs, outputs, rand_seed),
+        GenericKernel::Reduce(kernel) => {
+            if let Some(prologue) = &kernel.prologue {
+                let mut result = vec![vec![0f32; prologue.element_count]];
+                run_per_element(prologue, inputs, &mut result, rand_seed);
+                run_reduce(kernel, &result[0], &mut outputs[0]);
+            } else if let Some(epilogue) = &kernel.epilogue {
+                let mut result = vec![0f32; kernel.shape.element_count()];
+                run_reduce(kernel, inputs[0], &mut result);
+                let mut epilogue_inputs: Vec<&[f32]> = inputs[1..].to_vec();
+                epilogue_inputs.insert(epilogue.input_index, &result);
+                run_per_element(&epilogue.kernel, &epilogue_inputs, outputs, rand_seed);
+            } else {
+                run_reduce(kernel, inputs[0], &mut outputs[0]);
+            }
+        }
+        GenericKernel::MatMul(kernel) => {
+            if let Some(epilogue) = &kernel.epilogue {
+                let mut result = vec![0f32; kernel.shape.element_count()];
+                run_matmul(kernel, inputs[0], inputs[1], &mut result);
+                let mut epilogue_inputs: Vec<&[f32]> = inputs[2..].to_vec();
+                epilogue_inputs.insert(epilogue.input_index, &result);
+                run_per_element(&epilogue.kernel, &epilogue_inputs, outputs, rand_seed);
+            } else {
+                run_matmul(kernel, inputs[0], inputs[1], &mut outputs[0]);
+            }
+        }
+        GenericKernel::Unpad(kernel) => {
+            if let Some(prologue) = &kernel.prologue {
+                let mut result = vec![vec![0f32; prologue.element_count]];
+                run_per_element(prologue, inputs, &mut result, rand_seed);
+                run_unpad(kernel, &result[0], &mut outputs[0]);
+            } else {
+                run_unpad(kernel, inputs[0], &mut outputs[0]);
+            }
+        }
+        GenericKernel::WindowsToImage(kernel) => {
+            run_windows_to_image(kernel, inputs[0], &mut outputs[0])
+        }
+        GenericKernel::ScatterAdd(kernel) => {
+            run_scatter_add(kernel, inputs[0], inputs[1], &mut outputs[0])
+        }
+    }
+}
+
+fn run_cluster(
+    ops: &OpGraph,
+    cluster: &Cluster,
+    node_buffers: &[Option<Vec<f32>>],
+    rand_seed: u32,
+) -> (Vec<Vec<f32>>, Duration) {
+    let mut outputs: Vec<Vec<f32>> = cluster
+        .outputs
+        .iter()
+        .map(|output| {
+            let element_count = ops[output.node_id].shape.element_count();
+            match output.initial_state {
+                InitialState::Undefined => vec![0f32; element_count],
+                InitialState::CopyFrom(src_node_id) => {
+                    if let Op::Literal(value) = ops[src_node_id].op {
+                        vec![value.to_f32_bits(); element_count]
+                    } else {
+                        node_buffers[src_node_id.index()].clone().unwrap()
+                    }
+                }
+            }
+        })
+        .collect();
+    let inputs: Vec<&[f32]> = cluster
+        .inputs
+        .iter()
+        .map(|node_id| node_buffers[node_id.index()].as_deref().unwrap())
+        .collect();
+    let start = Instant::now();
+    run_kernel(&cluster.kernel, &inputs, &mut outputs, rand_seed);
+    (outputs, start.elapsed())
+}
+
+pub struct CpuEnvironment {
+    parameters: SharedParameters,
+    buffers: SecondaryMap<ParameterId, Vec<f32>>,
+    parallel_clusters: bool,
+}
+
+impl Default for CpuEnvironment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuEnvironment {
+    pub fn new() -> Self {
+        Self {
+            parameters: Rc::new(RefCell::new(SlotMap::with_key())),
+            buffers: SecondaryMap::new(),
+            parallel_clusters: false,
+        }
+    }
+
+    // Runs independent clusters on separate threads, one wave of the cluster
+    // dependency graph at a time.
+    pub fn set_parallel_clusters(&mut self, parallel_clusters: bool) {
+        self.parallel_clusters = parallel_clusters;
+    }
+
+    fn parameter(
+        &mut self,
+        shape: impl Into<Shape>,
+        name: impl Into<String>,
+        reset_to: Option<Initializer>,
+    ) -> Parameter {
+        let shape = shape.into();
+        let name = name.into();
+        let parameter_id = self.parameters.borrow_mut().insert(ParameterStorage {
+            shape,
+            name,
+            reset_to,
+            buffer_id: None,
+            double_buffered: false,
+            back_buffer_id: None,
+            back_buffer_written: false,
+            frozen: false,
+        });
+        Parameter::new(parameter_id, &self.parameters)
+    }
+
+    fn run_impl(
+        &mut self,
+        graph: &Graph,
+        rand_seed: u32,
+        mut timings: Option<&mut Vec<(ClusterId, Duration)>>,
+    ) {
+        if let Err(err) = self.check_inputs(graph) {
+            panic!("{}", err);
+        }
+
+        // buffers are plain vectors, so copy instead of tracking usage
+        let mut node_buffers: Vec<Option<Vec<f32>>> = vec![None; graph.ops.node_bound()];
+        for node_ref in graph.ops.node_references() {
+            if let Op::Input { parameter_id } = node_ref.weight().op {
+                let buffer = self.buffers[parameter_id].clone();
+                node_buffers[node_ref.id().index()] = Some(buffer);
+            }
+        }
+
+        for wave in graph.cluster_waves() {
+            let results: Vec<(Vec<Vec<f32>>, Duration)> = if self.parallel_clusters {
+                let ops = &graph.ops;
+                let node_buffers = &node_buffers;
+                thread::scope(|s| {
+                    let handles: Vec<_> = wave
+                        .iter()
+                        .map(|cluster_id| {
+                            let cluster = &graph.clusters[*cluster_id];
+                            s.spawn(move || run_cluster(ops, cluster, node_buffers, rand_seed))
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .map(|handle| handle.join().unwrap())
+                        .collect()
+                })
+            } else {
+                wave.iter()
+                    .map(|cluster_id| {
+                        let cluster = &graph.clusters[*cluster_id];
+                        run_cluster(&graph.ops, cluster, &node_buffers, rand_seed)
+                    })
+                    .collect()
+            };
+            for (cluster_id, (outputs, duration)) in wave.iter().copied().zip(results) {
+                if let Some(timings) = timings.as_mut() {
+                    timings.push((cluster_id, duration));
+                }
+                let cluster = &graph.clusters[cluster_id];
+                for (output, buffer) in cluster.outputs.iter().zip(outputs) {
+                    node_buffers[output.node_id.index()] = Some(buffer);
+                }
+            }
+        }
+
+        for node_ref in graph.ops.node_references() {
+            if let Op::Output { parameter_id } = node_ref.weight().op {
+                let arg_sources = get_arg_sources(&graph.ops, node_ref.id());
+                assert_eq!(arg_sources.len(), 1);
+                let src0 = &arg_sources[0];
+                let buffer = node_buffers[src0.node_id.index()].clone().unwrap();
+                self.buffers.insert(parameter_id, buffer);
+            }
+        }
+    }
+
+    pub fn check_inputs(&self, graph: &Graph) -> Result<(), UnwrittenInputError> {
+        for (parameter, _) in graph.inputs() {
+            let parameter_id = parameter.checked_id(&self.parameters);
+            if !self.buffers.contains_key(parameter_id) {
+                return Err(UnwrittenInputError {
+                    name: parameter.name(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn try_run(&mut self, graph: &Graph, rand_seed: u32) -> Result<(), UnwrittenInputError> {
+        self.check_inputs(graph)?;
+        self.run_impl(graph, rand_seed, None);
+        Ok(())
+    }
+
+    pub fn static_parameter(
+        &mut self,
+        shape: impl Into<Shape>,
+        name: impl Into<String>,
+    ) -> Parameter {
+        self.parameter(shape, name, None)
+    }
+
+    pub fn trainable_parameter(
+        &mut self,
+        shape: impl Into<Shape>,
+        name: impl Into<String>,
+        reset_to: Initializer,
+    ) -> Parameter {
+        self.parameter(shape, name, Some(reset_to))
+    }
+
+    pub fn write_parameter(&mut self, parameter: &Parameter, data: &[f32]) {
+        let parameter_id = parameter.checked_id(&self.parameters);
+        assert_eq!(parameter.shape().element_count(), data.len());
+        self.buffers.insert(parameter_id, data.to_vec());
+    }
+
+    pub fn resize_parameter(&mut self, parameter: &Parameter, shape: impl Into<Shape>) {
+        let parameter_id = parameter.checked_id(&self.parameters);
+        let shape = shape.into();
+        let mut parameters = self.parameters.borrow_mut();
+        if parameters[parameter_id].shape != shape {
+            parameters[parameter_id].shape = shape;
+            self.buffers.remove(parameter_id);
+        }
+    }
+
+    pub fn reset_parameter(&mut self, parameter: &Parameter, rng: &mut impl Rng) {
+        let element_count = parameter.shape().element_count();
+        let data = match parameter.reset_to().unwrap() {
+            Initializer::Zero => vec![0f32; element_count],
+            Initializer::One => vec![1f32; element_count],
+            Initializer::RandNormal(scale) => {
+                let mut bytes = Vec::new();
+                write_rand_normal(&mut bytes, scale, element_count, rng);
+                bytemuck::pod_collect_to_vec(&bytes)
+            }
+            Initializer::RandUniform(scale) => {
+                let mut bytes = Vec::new();
+                write_rand_uniform(&mut bytes, scale, element_count, rng);
+                bytemuck::pod_collect_to_vec(&bytes)
+            }
+        };
+        self.write_parameter(parameter, &data);
+    }
+
+    pub fn read_parameter_to_vec(&mut self, parameter: &Parameter) -> Vec<f32> {
+        let parameter_id = parameter.checked_id(&self.parameters);
+        self.buffers[parameter_id].clone()
+    }
+
+    pub fn scope(&self) -> Scope {
+        Scope::new(SharedParameters::clone(&self.parameters))
+    }
+
+    pub fn run(&mut self, graph: &Graph, rand_seed: u32) {
+        self.run_impl(graph, rand_seed, None);
+    }
+
+    pub fn run_timed(&mut self, graph: &Graph, rand_seed: u32) -> Vec<(ClusterId, Duration)> {
+        let mut timings = Vec::new();
+        self.run_impl(graph, rand_seed, Some(&mut timings));
+        timings
+    }
+
+    pub fn static_parameter_with_data(
+        &mut self,
+        shape: impl Into<Shape>,
+        name: &str,
+        data: &[f32],
+    ) -> Parameter {
+        Backend::static_parameter_with_data(self, shape, name, data)
+    }
+
+    pub fn read_parameter_scalar(&mut self, parameter: &Parameter) -> f32 {
+        Backend::read_parameter_scalar(self, parameter)
+    }
+
+    pub fn build_graph<F: FnOnce(&Scope)>(&self, f: F) -> Graph {
+        Backend::build_graph(self, f)
+    }
+
+    pub fn set_batch_size(&mut self, graph: &DynamicGraph, batch_size: usize) {
+        Backend::set_batch_size(self, graph, batch_size)
+    }
+
+    pub fn run_dynamic(&mut self, graph: &mut DynamicGraph, rand_seed: u32) {
+        Backend::run_dynamic(self, graph, rand_seed)
+    }
+}
+
+impl Backend for CpuEnvironment {
+    fn static_parameter(&mut self, shape: impl Into<Shape>, name: impl Into<String>) -> Parameter {
+        CpuEnvironment::static_parameter(self, shape, name)
+    }
+
+    fn trainable_parameter(
+        &mut self,
+        shape: impl Into<Shape>,
+        name: impl Into<String>,
+        reset_to: Initializer,
+    ) -> Parameter {
+        CpuEnvironment::trainable_parameter(self, shape, name, reset_to)
+    }
+
+    fn write_parameter(&mut self, parameter: &Parameter, data: &[f32]) {
+        CpuEnvironment::write_parameter(self, parameter, data)
+    }
+
+    fn resize_parameter(&mut self, parameter: &Parameter, shape: impl Into<Shape>) {
+        CpuEnvironment::resize_parameter(self, parameter, shape)
+    }
+
+    fn reset_parameter(&mut self, parameter: &Parameter, rng: &mut impl Rng) {
+        CpuEnvironment::reset_parameter(self, parameter, rng)
+    }
+
+    fn read_parameter_to_vec(&mut self, parameter: &Parameter) -> Vec<f32> {
+        CpuEnvironment::read_parameter_to_vec(self, parameter)
+    }
+
+    fn scope(&self) -> Scope {
+        CpuEnvironment::scope(self)
+    }
+
+    fn run(&mut self, graph: &Graph, rand_seed: u32) {
+        CpuEnvironment::run(self, graph, rand_seed)
+    }
+
+    fn run_timed(&mut self, graph: &Graph, rand_seed: u32) -> Vec<(ClusterId, Duration)> {
+        CpuEnvironment::run_timed(self, graph, rand_seed)
+    }
+}
@@ -1,5 +1,5 @@
 use slotmap::{Key, SlotMap};
-use std::fmt::Debug;
+use std::{fmt::Debug, iter};
 use trait_set::trait_set;
 
 #[derive(Debug, Clone, Copy)]
@@ -18,9 +18,9 @@ impl<K: Key> BlockListNode<K> {
 }
 
 #[derive(Debug, Clone, Copy)]
-pub(crate) struct HeapRange {
-    pub(crate) begin: usize,
-    pub(crate) end: usize,
+pub struct HeapRange {
+    pub begin: usize,
+    pub end: usize,
 }
 
 impl HeapRange {
@@ -31,7 +31,7 @@ impl HeapRange {
         }
     }
 
-    pub(crate) fn size(&self) -> usize {
+    pub fn size(&self) -> usize {
         self.end - self.begin
     }
 
@@ -83,32 +83,82 @@ pub(crate) struct HeapAllocInfo<T: Tag> {
     pub(crate) range: HeapRange,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) struct HeapStats {
-    pub(crate) alloc_count: usize,
-    pub(crate) total_alloc_size: usize,
-    pub(crate) total_free_size: usize,
-    pub(crate) largest_free_size: usize,
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub alloc_count: usize,
+    pub free_block_count: usize,
+    pub total_alloc_size: usize,
+    pub total_free_size: usize,
+    pub largest_free_size: usize,
+}
+
+impl HeapStats {
+    pub fn capacity(&self) -> usize {
+        self.total_alloc_size + self.total_free_size
+    }
+
+    // 0 when all free space is in one block, approaches 1 as it is split into many
+    pub fn fragmentation(&self) -> f32 {
+        if self.total_free_size == 0 {
+            0.0
+        } else {
+            1.0 - (self.largest_free_size as f32) / (self.total_free_size as f32)
+        }
+    }
+
+    fn add_block(&mut self, size: usize, is_free: bool) {
+        if is_free {
+            self.free_block_count += 1;
+            self.total_free_size += size;
+            self.largest_free_size = self.largest_free_size.max(size);
+        } else {
+            self.alloc_count += 1;
+            self.total_alloc_size += size;
+        }
+    }
 }
 
 type BlockSlotMap<K, T> = SlotMap<K, Block<K, T>>;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapStrategy {
+    FirstFit,
+    BestFit,
+}
+
+impl Default for HeapStrategy {
+    fn default() -> Self {
+        Self::FirstFit
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Heap<K: Key, T: Tag> {
     blocks: BlockSlotMap<K, T>,
     free_lists: Vec<Option<K>>,
+    strategy: HeapStrategy,
 }
 
 impl<K: Key, T: Tag> Default for Heap<K, T> {
     fn default() -> Self {
+        Self::with_strategy(HeapStrategy::default())
+    }
+}
+
+impl<K: Key, T: Tag> Heap<K, T> {
+    pub(crate) fn with_strategy(strategy: HeapStrategy) -> Self {
         Self {
             blocks: BlockSlotMap::with_key(),
             free_lists: Vec::new(),
+            strategy,
         }
     }
-}
 
-impl<K: Key, T: Tag> Heap<K, T> {
+    // only affects allocations made afterwards
+    pub(crate) fn set_strategy(&mut self, strategy: HeapStrategy) {
+        self.strategy = strategy;
+    }
+
     fn free_list_index(size: usize) -> usize {
         (0usize.leading_zeros() - size.leading_zeros()) as usize
     }
@@ -224,7 +274,7 @@ impl<K: Key, T: Tag> Heap<K, T> {
         blocks.remove(append_id).unwrap();
     }
 
-    #[allow(dead_code)]
+    #[cfg(test)]
     fn print_state(&self) {
         println!("stats: {:?}", self.stats());
         for (index, first_block_id) in self.free_lists.iter().copied().enumerate() {
@@ -249,69 +299,139 @@ impl<K: Key, T: Tag> Heap<K, T> {
         }
     }
 
+    // the first block of each arena, arenas always begin at offset 0
+    pub fn arenas(&self) -> impl Iterator<Item = K> + '_ {
+        self.blocks
+            .iter()
+            .filter(|(_, block)| block.range.begin == 0)
+            .map(|(block_id, _)| block_id)
+    }
+
+    // walks the blocks of the arena containing the given block in address order,
+    // with whether each block is free
+    pub fn blocks_in_order(&self, block_id: K) -> impl Iterator<Item = (K, HeapRange, bool)> + '_ {
+        let mut first_id = block_id;
+        while self.blocks[first_id].range.begin != 0 {
+            first_id = self.blocks[first_id].tag_node.prev_id;
+        }
+        let mut next_id = Some(first_id);
+        iter::from_fn(move || {
+            let block_id = next_id?;
+            let block = &self.blocks[block_id];
+            next_id = Some(block.tag_node.next_id).filter(|&id| id != first_id);
+            Some((block_id, block.range, block.free_node.is_some()))
+        })
+    }
+
     pub(crate) fn stats(&self) -> HeapStats {
-        let mut stats = HeapStats {
-            alloc_count: 0,
-            total_alloc_size: 0,
-            total_free_size: 0,
-            largest_free_size: 0,
-        };
+        let mut stats = HeapStats::default();
         for block in self.blocks.values() {
-            let size = block.range.size();
-            if block.free_node.is_none() {
-                stats.alloc_count += 1;
-                stats.total_alloc_size += size;
-            } else {
-                stats.total_free_size += size;
-                stats.largest_free_size = stats.largest_free_size.max(size);
-            }
+            stats.add_block(block.range.size(), block.free_node.is_some());
         }
         stats
     }
 
-    pub(crate) fn alloc(&mut self, size: usize, align: usize) -> Option<K> {
-        let blocks = &mut self.blocks;
-        let free_lists = self.free_lists.as_mut_slice();
+    pub(crate) fn arena_stats(&self) -> Vec<(T, HeapStats)> {
+        self.arenas()
+            .map(|first_id| {
+                let mut stats = HeapStats::default();
+                for (_, range, is_free) in self.blocks_in_order(first_id) {
+                    stats.add_block(range.size(), is_free);
+                }
+                (self.blocks[first_id].tag.clone(), stats)
+            })
+            .collect()
+    }
 
-        let align_mask = align - 1;
+    fn find_free_block(&self, size: usize, align_mask: usize) -> Option<K> {
         let start_free_list_index = Self::free_list_index(size);
-        for first_block_id in free_lists
+        for first_block_id in self
+            .free_lists
             .get(start_free_list_index..)?
             .iter()
             .copied()
             .flatten()
         {
+            // blocks in later free lists are always larger, so the best fit is in the
+            // first free list that has any block that fits
+            let mut best_id: Option<K> = None;
             let mut block_id = first_block_id;
             loop {
-                let block_range = blocks[block_id].range;
+                let block_range = self.blocks[block_id].range;
                 let aligned_begin = (block_range.begin + align_mask) & !align_mask;
-                let aligned_end = aligned_begin + size;
-                if aligned_end <= block_range.end {
-                    Self::unregister_free_block(blocks, free_lists, block_id);
-                    if aligned_begin != block_range.begin {
-                        let aligned_id = Self::truncate_block(
-                            blocks,
-                            block_id,
-                            aligned_begin - block_range.begin,
-                        );
-                        Self::register_free_block(blocks, free_lists, block_id);
-                        block_id = aligned_id;
+                if aligned_begin + size <= block_range.end {
+                    match self.strategy {
+                        HeapStrategy::FirstFit => return Some(block_id),
+                        HeapStrategy::BestFit => {
+                            if best_id.map_or(true, |best_id| {
+                                block_range.size() < self.blocks[best_id].range.size()
+                            }) {
+                                best_id = Some(block_id);
+                            }
+                        }
                     }
-                    if aligned_end != block_range.end {
-                        let unused_id = Self::truncate_block(blocks, block_id, size);
-                        Self::register_free_block(blocks, free_lists, unused_id);
-                    }
-                    return Some(block_id);
                 }
-                block_id = blocks[block_id].free_node.unwrap().next_id;
+                block_id = self.blocks[block_id].free_node.unwrap().next_id;
                 if block_id == first_block_id {
                     break;
                 }
             }
+            if best_id.is_some() {
+                return best_id;
+            }
         }
         None
     }
 
+    pub(crate) fn alloc(&mut self, size: usize, align: usize) -> Option<K> {
+        // alignment uses bit masks
+        assert!(
+            align.is_power_of_two(),
+            "heap alignment {} is not a power of two",
+            align
+        );
+        let align_mask = align - 1;
+        let mut block_id = self.find_free_block(size, align_mask)?;
+
+        let blocks = &mut self.blocks;
+        let free_lists = self.free_lists.as_mut_slice();
+
+        let block_range = blocks[block_id].range;
+        let aligned_begin = (block_range.begin + align_mask) & !align_mask;
+        let aligned_end = aligned_begin + size;
+        Self::unregister_free_block(blocks, free_lists, block_id);
+        if aligned_begin != block_range.begin {
+            let aligned_id =
+                Self::truncate_block(blocks, block_id, aligned_begin - block_range.begin);
+            Self::register_free_block(blocks, free_lists, block_id);
+            block_id = aligned_id;
+        }
+        if aligned_end != block_range.end {
+            let unused_id = Self::truncate_block(blocks, block_id, size);
+            Self::register_free_block(blocks, free_lists, unused_id);
+        }
+        Some(block_id)
+    }
+
+    // removes arenas that are entirely free, returns their tags so that the caller can
+    // release the underlying memory
+    pub(crate) fn try_release_empty_arenas(&mut self) -> Vec<T> {
+        // free blocks are merged with their neighbours, so an empty arena is a single
+        // free block that is alone in its tag list
+        let empty_ids: Vec<K> = self
+            .blocks
+            .iter()
+            .filter(|(id, block)| block.free_node.is_some() && block.tag_node.next_id == *id)
+            .map(|(id, _)| id)
+            .collect();
+        let mut tags = Vec::new();
+        for id in empty_ids {
+            Self::unregister_free_block(&mut self.blocks, self.free_lists.as_mut_slice(), id);
+            tags.push(self.blocks.remove(id).unwrap().tag);
+        }
+        tags
+    }
+
     pub(crate) fn info(&self, id: K) -> HeapAllocInfo<T> {
         let block = &self.blocks[id];
         HeapAllocInfo {
@@ -381,4 +501,155 @@ mod tests {
         let ei = heap.alloc(1000, 4).unwrap();
         heap.free(ei);
     }
+
+    #[test]
+    fn heap_best_fit() {
+        for strategy in [HeapStrategy::FirstFit, HeapStrategy::BestFit] {
+            let mut heap = Heap::with_strategy(strategy);
+            heap.extend_with(0usize, 600);
+            heap.extend_with(1usize, 1000);
+
+            // both arenas are in the same free list, the larger one was added last
+            let ai: Id = heap.alloc(520, 4).unwrap();
+            let large_alloc = heap.alloc(900, 4);
+            match strategy {
+                HeapStrategy::FirstFit => {
+                    assert_eq!(heap.info(ai).tag, 1);
+                    assert!(large_alloc.is_none());
+                }
+                HeapStrategy::BestFit => {
+                    assert_eq!(heap.info(ai).tag, 0);
+                    assert_eq!(heap.info(large_alloc.unwrap()).tag, 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn heap_release_empty_arenas() {
+        let mut heap = Heap::default();
+        heap.extend_with(0usize, 1000);
+        heap.extend_with(1usize, 1000);
+
+        // the large allocations need one arena each, the small one shares with c
+        let bi: Id = heap.alloc(800, 4).unwrap();
+        let ci: Id = heap.alloc(800, 4).unwrap();
+        let ai: Id = heap.alloc(100, 4).unwrap();
+        let ci_tag = heap.info(ci).tag;
+        assert_ne!(heap.info(bi).tag, ci_tag);
+        assert_eq!(heap.info(ai).tag, ci_tag);
+        assert!(heap.try_release_empty_arenas().is_empty());
+
+        heap.free(ai);
+        heap.free(ci);
+        let released = heap.try_release_empty_arenas();
+        assert_eq!(released, vec![ci_tag]);
+        assert_eq!(heap.stats().capacity(), 1000);
+        assert!(heap.try_release_empty_arenas().is_empty());
+
+        // the remaining arena still works
+        heap.free(bi);
+        let di: Id = heap.alloc(1000, 4).unwrap();
+        assert_ne!(heap.info(di).tag, ci_tag);
+    }
+
+    #[test]
+    fn heap_stats() {
+        let mut heap = Heap::default();
+        heap.extend_with(0usize, 1000);
+
+        let ids: Vec<Id> = (0..4).map(|_| heap.alloc(250, 1).unwrap()).collect();
+        let stats = heap.stats();
+        assert_eq!(stats.capacity(), 1000);
+        assert_eq!(stats.free_block_count, 0);
+        assert_eq!(stats.fragmentation(), 0.0);
+
+        // free alternate blocks so that no two free blocks are adjacent
+        heap.free(ids[0]);
+        heap.free(ids[2]);
+        let stats = heap.stats();
+        assert_eq!(stats.alloc_count, 2);
+        assert_eq!(stats.total_alloc_size, 500);
+        assert_eq!(stats.free_block_count, 2);
+        assert_eq!(stats.largest_free_size, 250);
+        assert_eq!(stats.fragmentation(), 0.5);
+
+        // freeing the block in between merges everything before it
+        heap.free(ids[1]);
+        let stats = heap.stats();
+        assert_eq!(stats.free_block_count, 1);
+        assert_eq!(stats.fragmentation(), 0.0);
+
+        heap.free(ids[3]);
+        assert_eq!(heap.stats().largest_free_size, 1000);
+    }
+
+    #[test]
+    fn heap_alignment() {
+        let mut heap = Heap::default();
+        heap.extend_with(0usize, 1000);
+
+        let _ai: Id = heap.alloc(3, 1).unwrap();
+        for align in [2, 16, 256] {
+            let bi: Id = heap.alloc(10, align).unwrap();
+            assert_eq!(heap.info(bi).range.begin % align, 0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "not a power of two")]
+    fn heap_alignment_not_power_of_two() {
+        let mut heap = Heap::default();
+        heap.extend_with(0usize, 1000);
+        let _ai: Id = heap.alloc(10, 12).unwrap();
+    }
+
+    #[test]
+    fn heap_best_fit_fragmentation() {
+        let largest_free_size = |strategy| {
+            let mut heap = Heap::with_strategy(strategy);
+            heap.extend_with(0usize, 820);
+
+            // free blocks of 300 and 500 in the same free list, the 500 block is the head
+            let ai: Id = heap.alloc(300, 4).unwrap();
+            let _xi = heap.alloc(12, 4).unwrap();
+            let bi = heap.alloc(500, 4).unwrap();
+            let _yi = heap.alloc(8, 4).unwrap();
+            heap.free(ai);
+            heap.free(bi);
+
+            let _ci = heap.alloc(280, 4).unwrap();
+            heap.stats().largest_free_size
+        };
+        assert_eq!(largest_free_size(HeapStrategy::FirstFit), 300);
+        assert_eq!(largest_free_size(HeapStrategy::BestFit), 500);
+    }
+
+    #[test]
+    fn heap_blocks_in_order() {
+        let mut heap = Heap::default();
+        heap.extend_with(0usize, 1000);
+
+        let ai: Id = heap.alloc(100, 4).unwrap();
+        let bi = heap.alloc(200, 4).unwrap();
+        heap.free(ai);
+        heap.extend_with(1usize, 500);
+
+        let arena_id = heap
+            .arenas()
+            .find(|&block_id| heap.info(block_id).tag == 0)
+            .unwrap();
+        let layout: Vec<(usize, usize, bool)> = heap
+            .blocks_in_order(arena_id)
+            .map(|(_, range, is_free)| (range.begin, range.end, is_free))
+            .collect();
+        assert_eq!(
+            layout,
+            &[(0, 100, true), (100, 300, false), (300, 1000, true)]
+        );
+
+        // starting from any block of the arena gives the same order
+        assert_eq!(heap.blocks_in_order(bi).count(), 3);
+        assert_eq!(heap.arenas().count(), 2);
+    }
 }
@@ -3,7 +3,7 @@ use ordered_float::NotNan;
 use spark::vk;
 use std::{
     collections::{BinaryHeap, HashMap, VecDeque},
-    mem,
+    io, mem,
 };
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
@@ -68,10 +68,18 @@ impl TimestampEntry {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+struct TimestampSpan {
+    id: NameId,
+    start: f32,
+    duration: f32,
+}
+
 struct TimestampAccumulator {
     context: SharedContext,
     time_total: f32,
     time_per_id: Vec<TimestampEntry>,
+    last_spans: Vec<TimestampSpan>,
     counter: u32,
     timestamp_valid_mask: u64,
     timestamp_period: f32,
@@ -83,6 +91,7 @@ impl TimestampAccumulator {
             context: SharedContext::clone(context),
             time_total: 0.0,
             time_per_id: Vec::new(),
+            last_spans: Vec::new(),
             counter: 0,
             timestamp_valid_mask: 1u64
                 .checked_shl(context.queue_family_properties.timestamp_valid_bits)
@@ -123,6 +132,26 @@ impl TimestampAccumulator {
             let total_time =
                 (query_deltas.iter().copied().sum::<u64>() as f32) * self.timestamp_period;
 
+            // keep the spans of the most recent run for trace output, starts are
+            // measured relative to the first timestamp of the run
+            self.last_spans.clear();
+            for ((id, duration), query_result) in set
+                .timestamp_ids
+                .iter()
+                .copied()
+                .zip(query_times.iter().copied())
+                .zip(query_results.iter().copied())
+            {
+                let start = ((query_result.wrapping_sub(query_results[0])
+                    & self.timestamp_valid_mask) as f32)
+                    * self.timestamp_period;
+                self.last_spans.push(TimestampSpan {
+                    id,
+                    start,
+                    duration,
+                });
+            }
+
             if self.time_per_id.len() == query_times.len()
                 && self
                     .time_per_id
@@ -178,6 +207,25 @@ impl TimestampAccumulator {
         }
     }
 
+    fn write_trace(&self, w: &mut impl io::Write, names: &[String]) -> io::Result<()> {
+        writeln!(w, "{{\"traceEvents\":[")?;
+        for (index, span) in self.last_spans.iter().enumerate() {
+            let name: String = names[span.id.index as usize]
+                .chars()
+                .filter(|&c| c != '"' && c != '\\')
+                .collect();
+            writeln!(
+                w,
+                "{{\"name\":\"{}\",\"cat\":\"kernel\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":0,\"tid\":0}}{}",
+                name,
+                span.start * 1_000_000.0,
+                span.duration * 1_000_000.0,
+                if index + 1 < self.last_spans.len() { "," } else { "" }
+            )?;
+        }
+        writeln!(w, "]}}")
+    }
+
     fn reset_timings(&mut self) {
         self.time_total = 0.0;
         self.time_per_id.clear();
@@ -233,6 +281,30 @@ impl TimestampSets {
         self.accumulator.reset_timings();
     }
 
+    pub(crate) fn write_trace(
+        &mut self,
+        w: &mut impl io::Write,
+        fences: &FenceSet,
+    ) -> io::Result<()> {
+        for set in self.sets.iter_mut() {
+            self.accumulator
+                .accumulate_timings(set.get_mut_when_signaled(fences));
+        }
+        self.accumulator.write_trace(w, &self.names)
+    }
+
+    pub(crate) fn last_durations(&mut self, fences: &FenceSet) -> Vec<f32> {
+        for set in self.sets.iter_mut() {
+            self.accumulator
+                .accumulate_timings(set.get_mut_when_signaled(fences));
+        }
+        self.accumulator
+            .last_spans
+            .iter()
+            .map(|span| span.duration)
+            .collect()
+    }
+
     pub(crate) fn acquire(
         &mut self,
         cmd: vk::CommandBuffer,
@@ -1,3 +1,9 @@
+pub use crate::device::{
+    buffer_heap::BufferReport,
+    context::{enumerate_devices, DeviceInfo, DeviceSelection, MemoryBudget},
+    heap::{HeapRange, HeapStats, HeapStrategy},
+};
+
 use crate::{common::*, device::common::*};
 use petgraph::visit::{IntoNodeReferences, NodeIndexable, NodeRef};
 use rand::{distributions::Open01, Rng};
@@ -6,18 +12,29 @@ use spark::{vk, Builder, Device};
 use std::{
     cell::RefCell,
     collections::HashSet,
+    convert::TryInto,
+    error,
     f32::consts::PI,
     ffi::CString,
+    fmt,
+    fs::File,
     io::{self, prelude::*},
+    iter, mem,
     rc::Rc,
     slice,
+    time::Duration,
 };
 
 fn normal_from_uniform(u1: f32, u2: f32) -> f32 {
     (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
 }
 
-fn write_rand_normal(mut writer: impl Write, scale: f32, element_count: usize, rng: &mut impl Rng) {
+pub(crate) fn write_rand_normal(
+    mut writer: impl Write,
+    scale: f32,
+    element_count: usize,
+    rng: &mut impl Rng,
+) {
     for _ in 0..element_count {
         let u1: f32 = rng.sample(Open01);
         let u2: f32 = rng.sample(Open01);
@@ -26,7 +43,7 @@ fn write_rand_normal(mut writer: impl Write, scale: f32, element_count: usize, r
     }
 }
 
-fn write_rand_uniform(
+pub(crate) fn write_rand_uniform(
     mut writer: impl Write,
     scale: f32,
     element_count: usize,
@@ -39,6 +56,17 @@ fn write_rand_uniform(
     }
 }
 
+fn write_u32(mut w: impl Write, n: usize) -> io::Result<()> {
+    assert!(n <= u32::MAX as usize);
+    w.write_all(&(n as u32).to_le_bytes())
+}
+
+fn read_u32(mut r: impl Read) -> io::Result<usize> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes) as usize)
+}
+
 pub struct ParameterWriter<'a>(StagingWriter<'a>);
 
 impl<'a> ParameterWriter<'a> {
@@ -76,6 +104,107 @@ impl<'a> io::BufRead for ParameterReader<'a> {
     }
 }
 
+// completion handle for a graph submitted with Environment::submit
+#[derive(Clone, Copy)]
+pub struct RunHandle(FenceId);
+
+// how Environment::run_chunked combines the value of an output over all chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkReduction {
+    Sum,
+    Mean,
+    Concat,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnwrittenInputError {
+    pub name: String,
+}
+
+impl fmt::Display for UnwrittenInputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "parameter \"{}\" is read by the graph but has not been written or reset",
+            self.name
+        )
+    }
+}
+
+impl error::Error for UnwrittenInputError {}
+
+// The parameter and run interface shared by the GPU environment and the CPU reference
+// backend, so that tests can be written once and run on either.
+pub trait Backend {
+    fn static_parameter(&mut self, shape: impl Into<Shape>, name: impl Into<String>) -> Parameter;
+
+    fn trainable_parameter(
+        &mut self,
+        shape: impl Into<Shape>,
+        name: impl Into<String>,
+        reset_to: Initializer,
+    ) -> Parameter;
+
+    fn write_parameter(&mut self, parameter: &Parameter, data: &[f32]);
+
+    // the contents of the parameter are discarded if the shape changes
+    fn resize_parameter(&mut self, parameter: &Parameter, shape: impl Into<Shape>);
+
+    fn reset_parameter(&mut self, parameter: &Parameter, rng: &mut impl Rng);
+
+    fn read_parameter_to_vec(&mut self, parameter: &Parameter) -> Vec<f32>;
+
+    fn scope(&self) -> Scope;
+
+    fn run(&mut self, graph: &Graph, rand_seed: u32);
+
+    fn run_timed(&mut self, graph: &Graph, rand_seed: u32) -> Vec<(ClusterId, Duration)>;
+
+    fn static_parameter_with_data(
+        &mut self,
+        shape: impl Into<Shape>,
+        name: &str,
+        data: &[f32],
+    ) -> Parameter {
+        let param = self.static_parameter(shape, name);
+        self.write_parameter(&param, data);
+        param
+    }
+
+    fn read_parameter_scalar(&mut self, parameter: &Parameter) -> f32 {
+        let data = self.read_parameter_to_vec(parameter);
+        assert_eq!(data.len(), 1);
+        data[0]
+    }
+
+    fn build_graph<F: FnOnce(&Scope)>(&self, f: F) -> Graph {
+        let scope = self.scope();
+        f(&scope);
+        scope.build_graph()
+    }
+
+    // resizes the leading axis of the batched parameters of the graph, ready to be
+    // written before the graph is run
+    fn set_batch_size(&mut self, graph: &DynamicGraph, batch_size: usize) {
+        for parameter in graph.batched_parameters.iter() {
+            let shape = parameter
+                .shape()
+                .resize_axis(Axis::from_index(0), batch_size);
+            self.resize_parameter(parameter, shape);
+        }
+    }
+
+    fn run_dynamic(&mut self, graph: &mut DynamicGraph, rand_seed: u32) {
+        let batch_size = graph.batch_size();
+        let build = &graph.build;
+        let g = graph
+            .graphs
+            .entry(batch_size)
+            .or_insert_with(|| self.build_graph(|scope| build(scope)));
+        self.run(g, rand_seed);
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 struct OpNodeStorage {
     usage_count: usize,
@@ -92,6 +221,7 @@ pub struct Environment {
     kernel_cache: KernelCache,
     descriptor_pools: DescriptorPools,
     timestamps: TimestampSets,
+    deterministic: bool,
 }
 
 impl Default for Environment {
@@ -102,7 +232,15 @@ impl Default for Environment {
 
 impl Environment {
     pub fn new() -> Self {
-        let context = Context::new();
+        Self::new_with_selection(None)
+    }
+
+    pub fn with_device(selection: DeviceSelection) -> Self {
+        Self::new_with_selection(Some(&selection))
+    }
+
+    fn new_with_selection(selection: Option<&DeviceSelection>) -> Self {
+        let context = Context::new(selection);
         let fences = FenceSet::new(&context);
         let command_buffers = CommandBuffers::new(&context, &fences);
         let buffer_heap = BufferHeap::new(&context);
@@ -120,9 +258,73 @@ impl Environment {
             kernel_cache,
             descriptor_pools,
             timestamps,
+            deterministic: false,
+        }
+    }
+
+    // Reductions, matrix multiplies and kernel launch order are already fixed by the
+    // graph, only scatter-add depends on the order of atomic adds.  In deterministic mode
+    // scatter-add runs one invocation per output element that loops over all indices, so
+    // costs a read of the full index array per output element instead of one atomic add
+    // per value.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    // Writes to a double buffered parameter go to a second buffer, which replaces the
+    // current buffer when the next graph that reads the parameter is submitted.  Reads
+    // of the parameter only see the new data after that submission.
+    pub fn set_double_buffered(&mut self, parameter: &Parameter, double_buffered: bool) {
+        let parameter_id = parameter.checked_id(&self.parameters);
+        let mut parameters = self.parameters.borrow_mut();
+        let param = parameters.get_mut(parameter_id).unwrap();
+        param.double_buffered = double_buffered;
+        if !double_buffered {
+            if param.back_buffer_written {
+                mem::swap(&mut param.buffer_id, &mut param.back_buffer_id);
+                param.back_buffer_written = false;
+            }
+            if let Some(buffer_id) = param.back_buffer_id.take() {
+                self.buffer_heap.free(buffer_id);
+            }
         }
     }
 
+    pub fn memory_budget(&self) -> MemoryBudget {
+        self.context.memory_budget()
+    }
+
+    // Best fit allocates from the smallest free block that fits, which keeps large free
+    // blocks intact for later allocations at the cost of searching the whole free list.
+    pub fn set_heap_strategy(&mut self, strategy: HeapStrategy) {
+        self.buffer_heap.set_strategy(strategy);
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.buffer_heap.heap_stats()
+    }
+
+    // The blocks of each chunk of device memory in address order, with whether each
+    // block is free, for inspecting how buffers are laid out.
+    pub fn heap_layout(&self) -> Vec<Vec<(HeapRange, bool)>> {
+        self.buffer_heap.heap_layout()
+    }
+
+    // Returns chunks of device memory that no longer hold any buffers to the device,
+    // and the number of bytes released.  Buffers are freed while queued work may still
+    // use them, so this waits for the GPU first.
+    pub fn release_unused_memory(&mut self) -> usize {
+        unsafe { self.context.device.device_wait_idle() }.unwrap();
+        self.buffer_heap.release_empty_chunks()
+    }
+
+    // Buffers that are still allocated, per chunk of device memory.  Between runs only
+    // parameter buffers should remain, so a report that grows across training steps
+    // points at a leak.
+    pub fn buffer_report(&self) -> BufferReport {
+        self.buffer_heap.buffer_report()
+    }
+
     fn parameter(
         &mut self,
         shape: impl Into<Shape>,
@@ -136,36 +338,35 @@ impl Environment {
             name,
             reset_to,
             buffer_id: None,
+            double_buffered: false,
+            back_buffer_id: None,
+            back_buffer_written: false,
+            frozen: false,
         });
         Parameter::new(parameter_id, &self.parameters)
     }
 
-    pub fn static_parameter(
-        &mut self,
-        shape: impl Into<Shape>,
-        name: impl Into<String>,
-    ) -> Parameter {
-        self.parameter(shape, name, None)
-    }
-
-    pub fn trainable_parameter(
-        &mut self,
-        shape: impl Into<Shape>,
-        name: impl Into<String>,
-        reset_to: Initializer,
-    ) -> Parameter {
-        self.parameter(shape, name, Some(reset_to))
-    }
-
     pub fn writer(&mut self, parameter: &Parameter) -> ParameterWriter {
         let parameter_id = parameter.checked_id(&self.parameters);
         let mut parameters = self.parameters.borrow_mut();
         let param = parameters.get_mut(parameter_id).unwrap();
-        if let Some(buffer_id) = param.buffer_id.take() {
-            self.buffer_heap.free(buffer_id);
-        }
-        let buffer_id = self.buffer_heap.alloc(param.shape.buffer_size()).unwrap();
-        param.buffer_id = Some(buffer_id);
+        let buffer_id = if param.double_buffered {
+            // leave the current buffer to any graph that is still reading it
+            let buffer_id = match param.back_buffer_id {
+                Some(buffer_id) => buffer_id,
+                None => self.buffer_heap.alloc(param.shape.buffer_size()).unwrap(),
+            };
+            param.back_buffer_id = Some(buffer_id);
+            param.back_buffer_written = true;
+            buffer_id
+        } else {
+            if let Some(buffer_id) = param.buffer_id.take() {
+                self.buffer_heap.free(buffer_id);
+            }
+            let buffer_id = self.buffer_heap.alloc(param.shape.buffer_size()).unwrap();
+            param.buffer_id = Some(buffer_id);
+            buffer_id
+        };
         ParameterWriter(StagingWriter::new(
             &mut self.staging_buffer,
             &mut self.command_buffers,
@@ -187,55 +388,164 @@ impl Environment {
         ))
     }
 
-    pub fn reset_parameter(&mut self, parameter: &Parameter, rng: &mut impl Rng) {
-        let shape = parameter.shape();
-        let writer = self.writer(parameter);
-        match parameter.reset_to().unwrap() {
-            Initializer::Zero => writer.zero_fill(),
-            Initializer::RandNormal(scale) => {
-                write_rand_normal(writer, scale, shape.element_count(), rng)
-            }
-            Initializer::RandUniform(scale) => {
-                write_rand_uniform(writer, scale, shape.element_count(), rng)
-            }
+    pub fn copy_parameter(&mut self, src: &Parameter, dst: &Parameter) {
+        let src_id = src.checked_id(&self.parameters);
+        let dst_id = dst.checked_id(&self.parameters);
+        let mut parameters = self.parameters.borrow_mut();
+        assert_eq!(parameters[src_id].shape, parameters[dst_id].shape);
+        if src_id == dst_id {
+            return;
         }
-    }
 
-    pub fn static_parameter_with_data(
-        &mut self,
-        shape: impl Into<Shape>,
-        name: &str,
-        data: &[f32],
-    ) -> Parameter {
-        let param = self.static_parameter(shape, name);
-        self.writer(&param)
-            .write_all(bytemuck::cast_slice(data))
-            .unwrap();
-        param
+        let src_buffer_id = parameters[src_id].buffer_id.unwrap_or_else(|| {
+            panic!(
+                "parameter \"{}\" was copied before it was written",
+                parameters[src_id].name
+            )
+        });
+        if let Some(buffer_id) = parameters[dst_id].buffer_id.take() {
+            self.buffer_heap.free(buffer_id);
+        }
+        let size = parameters[dst_id].shape.buffer_size();
+        let dst_buffer_id = self.buffer_heap.alloc(size).unwrap();
+        parameters[dst_id].buffer_id = Some(dst_buffer_id);
+
+        let src_info = self.buffer_heap.info(src_buffer_id);
+        let dst_info = self.buffer_heap.info(dst_buffer_id);
+        let device = &self.context.device;
+        let cmd = self.command_buffers.acquire(&self.fences);
+        {
+            let region = vk::BufferCopy {
+                src_offset: src_info.range.begin as vk::DeviceSize,
+                dst_offset: dst_info.range.begin as vk::DeviceSize,
+                size: size as vk::DeviceSize,
+            };
+            unsafe {
+                device.cmd_copy_buffer(
+                    cmd.get(),
+                    src_info.buffer,
+                    dst_info.buffer,
+                    slice::from_ref(&region),
+                )
+            };
+        }
+        {
+            // ensure the copy is visible to later kernels
+            let memory_barrier = vk::MemoryBarrier {
+                src_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                dst_access_mask: vk::AccessFlags::SHADER_READ | vk::AccessFlags::TRANSFER_READ,
+                ..Default::default()
+            };
+            unsafe {
+                device.cmd_pipeline_barrier(
+                    cmd.get(),
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    slice::from_ref(&memory_barrier),
+                    &[],
+                    &[],
+                );
+            }
+        }
+        cmd.submit(&mut self.fences);
     }
 
-    pub fn read_parameter_to_vec(&mut self, parameter: &Parameter) -> Vec<f32> {
-        let mut r = self.reader(parameter);
-        let mut bytes = Vec::new();
-        r.read_to_end(&mut bytes).unwrap();
-        bytemuck::cast_slice(&bytes).to_vec() // TODO: avoid deep copy
+    // Pads each sequence to max_len for upload as a [seqs.len(), max_len] batch, also
+    // returns the length of each sequence for masking out the padding.
+    pub fn pad_sequence(seqs: &[Vec<f32>], max_len: usize, pad_value: f32) -> (Vec<f32>, Vec<u32>) {
+        let mut data = Vec::with_capacity(seqs.len() * max_len);
+        let mut lengths = Vec::with_capacity(seqs.len());
+        for seq in seqs {
+            assert!(
+                seq.len() <= max_len,
+                "sequence length {} exceeds max length {}",
+                seq.len(),
+                max_len
+            );
+            data.extend_from_slice(seq);
+            data.extend(iter::repeat(pad_value).take(max_len - seq.len()));
+            lengths.push(seq.len().try_into().unwrap());
+        }
+        (data, lengths)
     }
 
-    pub fn read_parameter_scalar(&mut self, parameter: &Parameter) -> f32 {
-        let mut r = self.reader(parameter);
-        let mut bytes = Vec::new();
-        r.read_to_end(&mut bytes).unwrap();
-        *bytemuck::from_bytes(&bytes)
+    pub fn save_parameters(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+        parameters: &[Parameter],
+    ) -> io::Result<()> {
+        let mut w = io::BufWriter::new(File::create(path)?);
+        write_u32(&mut w, parameters.len())?;
+        for parameter in parameters.iter() {
+            let name = parameter.name();
+            write_u32(&mut w, name.len())?;
+            w.write_all(name.as_bytes())?;
+            let shape = parameter.shape();
+            write_u32(&mut w, shape.len())?;
+            for n in shape.iter().copied() {
+                write_u32(&mut w, n)?;
+            }
+            let data = self.read_parameter_to_vec(parameter);
+            w.write_all(bytemuck::cast_slice(&data))?;
+        }
+        w.flush()
     }
 
-    pub fn scope(&self) -> Scope {
-        Scope::new(SharedParameters::clone(&self.parameters))
+    pub fn load_parameters(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+        parameters: &[Parameter],
+    ) -> io::Result<()> {
+        let invalid_data = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut r = io::BufReader::new(File::open(path)?);
+        let count = read_u32(&mut r)?;
+        if count != parameters.len() {
+            return Err(invalid_data(format!(
+                "expected {} parameters, file has {}",
+                parameters.len(),
+                count
+            )));
+        }
+        for parameter in parameters.iter() {
+            let mut name = vec![0u8; read_u32(&mut r)?];
+            r.read_exact(&mut name)?;
+            let name = String::from_utf8_lossy(&name);
+            if name != parameter.name() {
+                return Err(invalid_data(format!(
+                    "parameter name mismatch: expected \"{}\", file has \"{}\"",
+                    parameter.name(),
+                    name
+                )));
+            }
+            let rank = read_u32(&mut r)?;
+            if rank > MAX_DIM {
+                return Err(invalid_data(format!(
+                    "parameter \"{}\" has rank {} in file",
+                    name, rank
+                )));
+            }
+            let shape = (0..rank)
+                .map(|_| read_u32(&mut r))
+                .collect::<io::Result<Shape>>()?;
+            if shape != parameter.shape() {
+                return Err(invalid_data(format!(
+                    "parameter \"{}\" shape mismatch: expected {}, file has {}",
+                    name,
+                    parameter.shape(),
+                    shape
+                )));
+            }
+            let mut data = vec![0u8; shape.buffer_size()];
+            r.read_exact(&mut data)?;
+            self.writer(parameter).write_all(&data)?;
+        }
+        Ok(())
     }
 
-    pub fn build_graph<F: FnOnce(&Scope)>(&self, f: F) -> Graph {
-        let scope = self.scope();
-        f(&scope);
-        scope.build_graph()
+    #[cfg(feature = "serialize")]
+    pub fn load_graph(&self, path: impl AsRef<std::path::Path>) -> io::Result<Graph> {
+        Graph::load(SharedParameters::clone(&self.parameters), path)
     }
 
     fn run_kernel(
@@ -301,29 +611,50 @@ impl Environment {
             );
             device.cmd_dispatch(cmd, module.group_count as u32, 1, 1);
         }
+    }
 
-        {
-            // ensure that compute results are visible for next kernel
-            let memory_barrier = vk::MemoryBarrier {
-                src_access_mask: vk::AccessFlags::SHADER_WRITE,
-                dst_access_mask: vk::AccessFlags::SHADER_READ,
-                ..Default::default()
-            };
-            unsafe {
-                device.cmd_pipeline_barrier(
-                    cmd,
-                    vk::PipelineStageFlags::COMPUTE_SHADER,
-                    vk::PipelineStageFlags::COMPUTE_SHADER,
-                    vk::DependencyFlags::empty(),
-                    slice::from_ref(&memory_barrier),
-                    &[],
-                    &[],
-                );
-            }
+    fn compute_barrier(device: &Device, cmd: vk::CommandBuffer) {
+        // ensure that compute results are visible for next kernel
+        let memory_barrier = vk::MemoryBarrier {
+            src_access_mask: vk::AccessFlags::SHADER_WRITE,
+            dst_access_mask: vk::AccessFlags::SHADER_READ,
+            ..Default::default()
+        };
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                slice::from_ref(&memory_barrier),
+                &[],
+                &[],
+            );
         }
     }
 
-    pub fn run(&mut self, graph: &Graph, rand_seed: u32) {
+    pub fn kernel_compile_count(&self) -> usize {
+        self.kernel_cache.compile_count()
+    }
+
+    // Returns once the work is queued, so the next batch can be prepared while the GPU
+    // runs.  Input parameters can be written straight away: the writer allocates a new
+    // buffer, and buffers freed by the graph are only reused by work queued after it.
+    // Reading an output waits for the graph to complete.  Only two submissions are in
+    // flight at once, a third waits for the oldest to complete.
+    pub fn submit(&mut self, graph: &Graph, rand_seed: u32) -> RunHandle {
+        self.submit_waves(graph, graph.cluster_waves(), rand_seed)
+    }
+
+    fn submit_waves(
+        &mut self,
+        graph: &Graph,
+        waves: Vec<&[ClusterId]>,
+        rand_seed: u32,
+    ) -> RunHandle {
+        if let Err(err) = self.check_inputs(graph) {
+            panic!("{}", err);
+        }
         let mut parameters = self.parameters.borrow_mut();
 
         // collect input and output parameters
@@ -368,6 +699,16 @@ impl Environment {
             node_storage[node_id.index()].usage_count += 1;
         }
 
+        // double buffered inputs switch to the buffer written since their last run
+        for node_id in inputs.iter().copied() {
+            let parameter_id = graph.ops[node_id].op.input_parameter_id().unwrap();
+            let param = &mut parameters[parameter_id];
+            if param.back_buffer_written {
+                mem::swap(&mut param.buffer_id, &mut param.back_buffer_id);
+                param.back_buffer_written = false;
+            }
+        }
+
         // copy inputs to node, increment usage when parameter is not an output, to preserve the buffer
         for node_id in inputs.iter().copied() {
             let parameter_id = graph.ops[node_id].op.input_parameter_id().unwrap();
@@ -399,105 +740,146 @@ impl Environment {
         let cmd = self.command_buffers.acquire(&self.fences);
         let descriptor_pool = self.descriptor_pools.acquire(&self.fences);
         let mut timestamps = self.timestamps.acquire(cmd.get(), &self.fences);
-        for cluster_id in graph.clusters_sorted.iter().copied() {
-            let cluster = &graph.clusters[cluster_id];
+        // clusters within a wave are independent, so they share a single barrier
+        for wave in waves {
+            for cluster_id in wave.iter().copied() {
+                let cluster = &graph.clusters[cluster_id];
 
-            for node_id in cluster.inputs.iter().copied() {
-                node_storage[node_id.index()].usage_count -= 1;
-            }
-            for output in cluster.outputs.iter() {
-                let shape = graph.ops[output.node_id].shape;
-                let buffer_id = match output.initial_state {
-                    InitialState::Undefined => self.buffer_heap.alloc(shape.buffer_size()).unwrap(),
-                    InitialState::CopyFrom(src_node_id) => {
-                        let src_node_storage = &mut node_storage[src_node_id.index()];
-                        if src_node_storage.usage_count == 0 {
-                            if let Some(buffer_id) =
-                                node_storage[src_node_id.index()].buffer_id.take()
-                            {
-                                buffer_id
-                            } else if let Op::Literal(value) = graph.ops[src_node_id].op {
-                                let buffer_id =
-                                    self.buffer_heap.alloc(shape.buffer_size()).unwrap();
-                                let kernel = GenericKernel::Fill(FillKernel {
-                                    value,
-                                    element_count: shape.element_count(),
-                                });
-                                Self::run_kernel(
-                                    &kernel,
-                                    &[buffer_id],
-                                    device,
-                                    &mut self.kernel_cache,
-                                    &mut self.buffer_heap,
-                                    cmd.get(),
-                                    descriptor_pool.get(),
-                                    rand_seed,
-                                );
-                                buffer_id
+                for node_id in cluster.inputs.iter().copied() {
+                    node_storage[node_id.index()].usage_count -= 1;
+                }
+                for output in cluster.outputs.iter() {
+                    let shape = graph.ops[output.node_id].shape;
+                    let buffer_id = match output.initial_state {
+                        InitialState::Undefined => {
+                            self.buffer_heap.alloc(shape.buffer_size()).unwrap()
+                        }
+                        InitialState::CopyFrom(src_node_id) => {
+                            let src_node_storage = &mut node_storage[src_node_id.index()];
+                            if src_node_storage.usage_count == 0 {
+                                if let Some(buffer_id) =
+                                    node_storage[src_node_id.index()].buffer_id.take()
+                                {
+                                    buffer_id
+                                } else if let Op::Literal(value) = graph.ops[src_node_id].op {
+                                    let buffer_id =
+                                        self.buffer_heap.alloc(shape.buffer_size()).unwrap();
+                                    let kernel = GenericKernel::Fill(FillKernel {
+                                        value,
+                                        element_count: shape.element_count(),
+                                    });
+                                    Self::run_kernel(
+                                        &kernel,
+                                        &[buffer_id],
+                                        device,
+                                        &mut self.kernel_cache,
+                                        &mut self.buffer_heap,
+                                        cmd.get(),
+                                        descriptor_pool.get(),
+                                        rand_seed,
+                                    );
+                                    Self::compute_barrier(device, cmd.get());
+                                    buffer_id
+                                } else {
+                                    panic!("cannot copy-on-write node")
+                                }
                             } else {
-                                panic!("cannot copy-on-write node")
+                                unimplemented!("TODO deep copy buffer")
                             }
-                        } else {
-                            unimplemented!("TODO deep copy buffer")
                         }
-                    }
-                };
-                let node_state = &mut node_storage[output.node_id.index()];
-                assert!(node_state.buffer_id.is_none());
-                node_state.buffer_id = Some(buffer_id);
-            }
+                    };
+                    let node_state = &mut node_storage[output.node_id.index()];
+                    assert!(node_state.buffer_id.is_none());
+                    node_state.buffer_id = Some(buffer_id);
+                }
 
-            let label_name = cluster.kernel.label_name();
-            timestamps.write_timestamp(cmd.get(), &label_name);
-            if instance.extensions.ext_debug_utils {
-                let label_name = CString::new(label_name).unwrap();
-                let label = vk::DebugUtilsLabelEXT {
-                    p_label_name: label_name.as_bytes_with_nul().as_ptr() as *const i8,
-                    ..Default::default()
-                };
-                unsafe {
-                    self.context
-                        .instance
-                        .cmd_begin_debug_utils_label_ext(cmd.get(), &label);
+                let label_name = cluster.kernel.label_name();
+                timestamps.write_timestamp(cmd.get(), &label_name);
+                if instance.extensions.ext_debug_utils {
+                    let label_name = CString::new(label_name).unwrap();
+                    let label = vk::DebugUtilsLabelEXT {
+                        p_label_name: label_name.as_bytes_with_nul().as_ptr() as *const i8,
+                        ..Default::default()
+                    };
+                    unsafe {
+                        self.context
+                            .instance
+                            .cmd_begin_debug_utils_label_ext(cmd.get(), &label);
+                    }
                 }
-            }
 
-            let buffer_ids: Vec<_> = cluster
-                .inputs
-                .iter()
-                .copied()
-                .chain(cluster.outputs.iter().map(|output| output.node_id))
-                .map(|node_id| node_storage[node_id.index()].buffer_id.unwrap())
-                .collect();
-            Self::run_kernel(
-                &cluster.kernel,
-                &buffer_ids,
-                device,
-                &mut self.kernel_cache,
-                &mut self.buffer_heap,
-                cmd.get(),
-                descriptor_pool.get(),
-                rand_seed,
-            );
+                let deterministic_kernel;
+                let kernel = match &cluster.kernel {
+                    GenericKernel::ScatterAdd(kernel) if self.deterministic => {
+                        deterministic_kernel = GenericKernel::ScatterAdd(ScatterAddKernel {
+                            deterministic: true,
+                            ..kernel.clone()
+                        });
+                        &deterministic_kernel
+                    }
+                    kernel => kernel,
+                };
+                let buffer_ids: Vec<_> = cluster
+                    .inputs
+                    .iter()
+                    .copied()
+                    .chain(cluster.outputs.iter().map(|output| output.node_id))
+                    .map(|node_id| node_storage[node_id.index()].buffer_id.unwrap())
+                    .collect();
+                Self::run_kernel(
+                    kernel,
+                    &buffer_ids,
+                    device,
+                    &mut self.kernel_cache,
+                    &mut self.buffer_heap,
+                    cmd.get(),
+                    descriptor_pool.get(),
+                    rand_seed,
+                );
 
-            if instance.extensions.ext_debug_utils {
-                unsafe {
-                    self.context
-                        .instance
-                        .cmd_end_debug_utils_label_ext(cmd.get());
+                if instance.extensions.ext_debug_utils {
+                    unsafe {
+                        self.context
+                            .instance
+                            .cmd_end_debug_utils_label_ext(cmd.get());
+                    }
                 }
             }
+            Self::compute_barrier(device, cmd.get());
 
-            for node_id in cluster.inputs.iter().copied() {
-                let node_state = &mut node_storage[node_id.index()];
-                if node_state.usage_count == 0 {
-                    if let Some(buffer_id) = node_state.buffer_id.take() {
-                        self.buffer_heap.free(buffer_id);
+            // free buffers after the whole wave, so that clusters running concurrently
+            // never alias the same heap block
+            for cluster_id in wave.iter().copied() {
+                for node_id in graph.clusters[cluster_id].inputs.iter().copied() {
+                    let node_state = &mut node_storage[node_id.index()];
+                    if node_state.usage_count == 0 {
+                        if let Some(buffer_id) = node_state.buffer_id.take() {
+                            self.buffer_heap.free(buffer_id);
+                        }
                     }
                 }
             }
         }
         timestamps.end(cmd.get());
+        {
+            // later transfers may reuse buffers that were freed above
+            let memory_barrier = vk::MemoryBarrier {
+                src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                dst_access_mask: vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE,
+                ..Default::default()
+            };
+            unsafe {
+                device.cmd_pipeline_barrier(
+                    cmd.get(),
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    slice::from_ref(&memory_barrier),
+                    &[],
+                    &[],
+                );
+            }
+        }
         let fence_id = cmd.submit(&mut self.fences);
         descriptor_pool.recycle(fence_id);
         timestamps.recycle(fence_id);
@@ -513,11 +895,256 @@ impl Environment {
             assert!(source_storage.buffer_id.is_some());
             param.buffer_id = source_storage.buffer_id.take();
         }
+
+        RunHandle(fence_id)
+    }
+
+    // Parameters only hold data once written, reset or output by a graph, so reading an
+    // input before then would run on uninitialized memory.
+    pub fn check_inputs(&self, graph: &Graph) -> Result<(), UnwrittenInputError> {
+        let parameters = self.parameters.borrow();
+        for (parameter, _) in graph.inputs() {
+            let param = &parameters[parameter.checked_id(&self.parameters)];
+            if param.buffer_id.is_none() && !param.back_buffer_written {
+                return Err(UnwrittenInputError {
+                    name: param.name.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn try_run(&mut self, graph: &Graph, rand_seed: u32) -> Result<(), UnwrittenInputError> {
+        self.check_inputs(graph)?;
+        self.submit(graph, rand_seed);
+        Ok(())
+    }
+
+    pub fn wait(&self, handle: RunHandle) {
+        self.fences.wait_for_signal(handle.0);
+    }
+
+    // Runs the graph once per chunk of data written to the given parameter, the parameter
+    // shape gives the chunk size.  Returns the combined value of each requested output.
+    pub fn run_chunked(
+        &mut self,
+        graph: &Graph,
+        parameter: &Parameter,
+        data: &[f32],
+        outputs: &[(&Parameter, ChunkReduction)],
+        rand_seed: u32,
+    ) -> Vec<Vec<f32>> {
+        let chunk_len = parameter.shape().element_count();
+        assert_eq!(data.len() % chunk_len, 0);
+        let chunk_count = data.len() / chunk_len;
+
+        let mut results: Vec<Vec<f32>> = outputs
+            .iter()
+            .map(|(output, reduction)| {
+                let element_count = output.shape().element_count();
+                match reduction {
+                    ChunkReduction::Sum | ChunkReduction::Mean => vec![0f32; element_count],
+                    ChunkReduction::Concat => Vec::with_capacity(element_count * chunk_count),
+                }
+            })
+            .collect();
+        for chunk in data.chunks(chunk_len) {
+            self.writer(parameter)
+                .write_all(bytemuck::cast_slice(chunk))
+                .unwrap();
+            self.run(graph, rand_seed);
+            for ((output, reduction), result) in outputs.iter().zip(results.iter_mut()) {
+                let values = self.read_parameter_to_vec(output);
+                match reduction {
+                    ChunkReduction::Sum | ChunkReduction::Mean => {
+                        for (sum, value) in result.iter_mut().zip(values) {
+                            *sum += value;
+                        }
+                    }
+                    ChunkReduction::Concat => result.extend(values),
+                }
+            }
+        }
+
+        let scale = 1.0 / (chunk_count as f32);
+        for ((_, reduction), result) in outputs.iter().zip(results.iter_mut()) {
+            if *reduction == ChunkReduction::Mean {
+                result.iter_mut().for_each(|x| *x *= scale);
+            }
+        }
+        results
     }
 
     pub fn print_timings(&mut self, label: &str) {
         self.timestamps.print_timings(label, &self.fences);
     }
+
+    pub fn export_trace(&mut self, path: impl AsRef<std::path::Path>) -> io::Result<()> {
+        let mut w = io::BufWriter::new(File::create(path)?);
+        self.timestamps.write_trace(&mut w, &self.fences)?;
+        w.flush()
+    }
+
+    pub fn static_parameter(
+        &mut self,
+        shape: impl Into<Shape>,
+        name: impl Into<String>,
+    ) -> Parameter {
+        self.parameter(shape, name, None)
+    }
+
+    pub fn trainable_parameter(
+        &mut self,
+        shape: impl Into<Shape>,
+        name: impl Into<String>,
+        reset_to: Initializer,
+    ) -> Parameter {
+        self.parameter(shape, name, Some(reset_to))
+    }
+
+    pub fn write_parameter(&mut self, parameter: &Parameter, data: &[f32]) {
+        assert_eq!(parameter.shape().element_count(), data.len());
+        self.writer(parameter)
+            .write_all(bytemuck::cast_slice(data))
+            .unwrap();
+    }
+
+    pub fn resize_parameter(&mut self, parameter: &Parameter, shape: impl Into<Shape>) {
+        let parameter_id = parameter.checked_id(&self.parameters);
+        let shape = shape.into();
+        let mut parameters = self.parameters.borrow_mut();
+        let param = &mut parameters[parameter_id];
+        if param.shape != shape {
+            param.shape = shape;
+            param.back_buffer_written = false;
+            if let Some(buffer_id) = param.buffer_id.take() {
+                self.buffer_heap.free(buffer_id);
+            }
+            if let Some(buffer_id) = param.back_buffer_id.take() {
+                self.buffer_heap.free(buffer_id);
+            }
+        }
+    }
+
+    pub fn reset_parameter(&mut self, parameter: &Parameter, rng: &mut impl Rng) {
+        let shape = parameter.shape();
+        let mut writer = self.writer(parameter);
+        match parameter.reset_to().unwrap() {
+            Initializer::Zero => writer.zero_fill(),
+            Initializer::One => {
+                let data = vec![1f32; shape.element_count()];
+                writer.write_all(bytemuck::cast_slice(&data)).unwrap();
+            }
+            Initializer::RandNormal(scale) => {
+                write_rand_normal(writer, scale, shape.element_count(), rng)
+            }
+            Initializer::RandUniform(scale) => {
+                write_rand_uniform(writer, scale, shape.element_count(), rng)
+            }
+        }
+    }
+
+    pub fn read_parameter_to_vec(&mut self, parameter: &Parameter) -> Vec<f32> {
+        let mut r = self.reader(parameter);
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes).unwrap();
+        bytemuck::cast_slice(&bytes).to_vec() // TODO: avoid deep copy
+    }
+
+    pub fn scope(&self) -> Scope {
+        Scope::new(SharedParameters::clone(&self.parameters))
+    }
+
+    // kernels are cached by structure, so running a graph again with new input data
+    // does not recompile anything
+    pub fn run(&mut self, graph: &Graph, rand_seed: u32) {
+        self.submit(graph, rand_seed);
+    }
+
+    // Waits for the GPU, so only use this when timings are wanted.  Clusters within a
+    // wave overlap on the GPU, so timestamps between them would not measure anything
+    // useful: when timing each cluster runs as its own wave instead.
+    pub fn run_timed(&mut self, graph: &Graph, rand_seed: u32) -> Vec<(ClusterId, Duration)> {
+        let waves = graph.clusters_sorted.chunks(1).collect();
+        self.submit_waves(graph, waves, rand_seed);
+        let durations = self.timestamps.last_durations(&self.fences);
+
+        // the query pool is fixed size, so very large graphs only time a prefix of clusters
+        graph
+            .clusters_sorted
+            .iter()
+            .copied()
+            .zip(durations.iter().map(|&t| Duration::from_secs_f32(t)))
+            .collect()
+    }
+
+    pub fn static_parameter_with_data(
+        &mut self,
+        shape: impl Into<Shape>,
+        name: &str,
+        data: &[f32],
+    ) -> Parameter {
+        Backend::static_parameter_with_data(self, shape, name, data)
+    }
+
+    pub fn read_parameter_scalar(&mut self, parameter: &Parameter) -> f32 {
+        Backend::read_parameter_scalar(self, parameter)
+    }
+
+    pub fn build_graph<F: FnOnce(&Scope)>(&self, f: F) -> Graph {
+        Backend::build_graph(self, f)
+    }
+
+    pub fn set_batch_size(&mut self, graph: &DynamicGraph, batch_size: usize) {
+        Backend::set_batch_size(self, graph, batch_size)
+    }
+
+    pub fn run_dynamic(&mut self, graph: &mut DynamicGraph, rand_seed: u32) {
+        Backend::run_dynamic(self, graph, rand_seed)
+    }
+}
+
+impl Backend for Environment {
+    fn static_parameter(&mut self, shape: impl Into<Shape>, name: impl Into<String>) -> Parameter {
+        Environment::static_parameter(self, shape, name)
+    }
+
+    fn trainable_parameter(
+        &mut self,
+        shape: impl Into<Shape>,
+        name: impl Into<String>,
+        reset_to: Initializer,
+    ) -> Parameter {
+        Environment::trainable_parameter(self, shape, name, reset_to)
+    }
+
+    fn write_parameter(&mut self, parameter: &Parameter, data: &[f32]) {
+        Environment::write_parameter(self, parameter, data)
+    }
+
+    fn resize_parameter(&mut self, parameter: &Parameter, shape: impl Into<Shape>) {
+        Environment::resize_parameter(self, parameter, shape)
+    }
+
+    fn reset_parameter(&mut self, parameter: &Parameter, rng: &mut impl Rng) {
+        Environment::reset_parameter(self, parameter, rng)
+    }
+
+    fn read_parameter_to_vec(&mut self, parameter: &Parameter) -> Vec<f32> {
+        Environment::read_parameter_to_vec(self, parameter)
+    }
+
+    fn scope(&self) -> Scope {
+        Environment::scope(self)
+    }
+
+    fn run(&mut self, graph: &Graph, rand_seed: u32) {
+        Environment::run(self, graph, rand_seed)
+    }
+
+    fn run_timed(&mut self, graph: &Graph, rand_seed: u32) -> Vec<(ClusterId, Duration)> {
+        Environment::run_timed(self, graph, rand_seed)
+    }
 }
 
 impl Drop for Environment {
@@ -1,4 +1,4 @@
-use crate::common::*;
+use crate::{common::*, device::heap::Heap};
 use ordered_float::NotNan;
 use petgraph::{
     prelude::*,
@@ -8,11 +8,13 @@ use petgraph::{
 };
 use slotmap::{SecondaryMap, SlotMap};
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     convert::TryInto,
     fs::File,
     hash::{Hash, Hasher},
-    io, iter, path::PathBuf, process::Stdio,
+    io, iter,
+    path::{Path, PathBuf},
+    process::Stdio,
 };
 use tinyvec::ArrayVec as TinyVec;
 
@@ -53,13 +55,23 @@ pub(crate) fn get_arg_sources(
         .collect()
 }
 
+fn finite_literal(value: f32) -> Option<Literal> {
+    if value.is_finite() {
+        Some(Literal::F32(NotNan::new(value).unwrap()))
+    } else {
+        None
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum InitialState {
     Undefined,
     CopyFrom(OpNodeId),
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct ClusterOutput {
     pub(crate) node_id: OpNodeId,
     pub(crate) initial_state: InitialState,
@@ -82,6 +94,7 @@ impl ClusterOutput {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct Cluster {
     pub(crate) kernel: GenericKernel,
     pub(crate) inputs: Vec<OpNodeId>,
@@ -89,7 +102,23 @@ pub(crate) struct Cluster {
 }
 
 slotmap::new_key_type! {
-    pub(crate) struct ClusterId;
+    pub struct ClusterId;
+}
+
+slotmap::new_key_type! {
+    struct MemoryReportBufferId;
+}
+
+// typical min_storage_buffer_offset_alignment, the real value depends on the device
+const MEMORY_REPORT_ALIGNMENT: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryReport {
+    pub input_bytes: usize,
+    pub output_bytes: usize,
+    pub intermediate_bytes: usize,
+    pub heap_bytes: usize,
+    pub cluster_peak_bytes: Vec<usize>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -99,20 +128,62 @@ pub enum KernelDotOutput {
     Color,
 }
 
+// Builds a graph for each batch size on first use.  The leading axis of each batched
+// parameter is the batch axis, so a reshape can leave it to be inferred (reshape_infer
+// with -1) and the element count is only checked once the graph for a batch size is
+// built at run time.
+pub struct DynamicGraph<'f> {
+    pub(crate) batched_parameters: Vec<Parameter>,
+    pub(crate) build: Box<dyn Fn(&Scope) + 'f>,
+    pub(crate) graphs: HashMap<usize, Graph>,
+}
+
+impl<'f> DynamicGraph<'f> {
+    pub fn new(batched_parameters: &[&Parameter], build: impl Fn(&Scope) + 'f) -> Self {
+        assert!(!batched_parameters.is_empty());
+        Self {
+            batched_parameters: batched_parameters.iter().map(|&p| p.clone()).collect(),
+            build: Box::new(build),
+            graphs: HashMap::new(),
+        }
+    }
+
+    pub fn batch_size(&self) -> usize {
+        let batch_size = self.batched_parameters[0].shape()[0];
+        assert!(
+            self.batched_parameters
+                .iter()
+                .all(|parameter| parameter.shape()[0] == batch_size),
+            "batched parameters have different batch sizes"
+        );
+        batch_size
+    }
+
+    pub fn graph(&self, batch_size: usize) -> Option<&Graph> {
+        self.graphs.get(&batch_size)
+    }
+}
+
 pub struct Graph {
     pub(crate) parameters: SharedParameters,
     pub(crate) ops: OpGraph,
     pub(crate) ops_sorted: Vec<OpNodeId>,
+    ordering_dirty: bool,
     pub(crate) clusters: SlotMap<ClusterId, Cluster>,
     pub(crate) clusters_sorted: Vec<ClusterId>,
 }
 
 impl Graph {
-    pub(crate) fn new(parameters: SharedParameters, ops: OpGraph) -> Self {
+    pub(crate) fn new(
+        parameters: SharedParameters,
+        ops: OpGraph,
+        fuse_matmul_epilogues: bool,
+    ) -> Self {
         let mut graph = Self {
             parameters,
             ops,
             ops_sorted: Vec::new(),
+            ordering_dirty: true,
             clusters: SlotMap::with_key(),
             clusters_sorted: Vec::new(),
         };
@@ -126,19 +197,40 @@ impl Graph {
         graph.rebuild_ordering();
         graph.simplify_arithmetic();
 
+        graph.rebuild_ordering();
+        graph.fold_constants();
+
+        graph.rebuild_ordering();
+        if graph.replace_one_hot_matmuls() {
+            graph.rebuild_ordering();
+            graph.eliminate_dead_code();
+        }
+
+        graph.rebuild_ordering();
+        graph.sink_broadcasts();
+
         graph.rebuild_ordering();
         graph.eliminate_common_subgraphs();
 
+        graph.rebuild_ordering();
+        graph.fuse_matmul_accumulates();
+
         graph.rebuild_ordering();
         graph.make_built_ins_and_literals_unique();
 
         graph.rebuild_ordering();
-        graph.build_clusters();
+        graph.build_clusters(fuse_matmul_epilogues);
 
         graph
     }
 
+    // Passes set the dirty flag when they add or remove nodes or edges, changes to ops,
+    // shapes or views keep the existing order valid.
     fn rebuild_ordering(&mut self) {
+        if !self.ordering_dirty {
+            return;
+        }
+        self.ordering_dirty = false;
         self.ops_sorted.clear();
         let mut topo = Topo::new(&self.ops);
         while let Some(node_id) = topo.next(&self.ops) {
@@ -161,15 +253,29 @@ impl Graph {
                 }
             }
         }
+        let node_count = self.ops.node_count();
         self.ops.retain_nodes(|_, index| live.is_visited(&index));
+        if self.ops.node_count() != node_count {
+            self.ordering_dirty = true;
+        }
     }
 
     fn eliminate_common_subgraphs(&mut self) {
         let mut hashes = vec![0u64; self.ops.node_bound()];
         let mut ids_from_hash = HashMap::new();
+        let get_canonical_arg_sources = |ops: &OpGraph, node_id| {
+            let mut arg_sources = get_arg_sources(ops, node_id);
+            for arg_source in arg_sources
+                .iter_mut()
+                .filter(|arg_source| !arg_source.is_gather)
+            {
+                arg_source.view = arg_source.view.canonical();
+            }
+            arg_sources
+        };
         for node_id in self.ops_sorted.iter().copied() {
             let node = &self.ops[node_id];
-            let arg_sources = get_arg_sources(&self.ops, node_id);
+            let arg_sources = get_canonical_arg_sources(&self.ops, node_id);
             let hash = {
                 let mut hasher = DefaultHasher::new();
                 for arg_source in arg_sources.iter() {
@@ -184,9 +290,10 @@ impl Graph {
                 let ids = ids_from_hash.entry(hash).or_insert_with(Vec::new);
                 if let Some(other_id) = ids.iter().copied().find(|&id| {
                     let other_node = &self.ops[id];
-                    let other_arg_sources = get_arg_sources(&self.ops, id);
+                    let other_arg_sources = get_canonical_arg_sources(&self.ops, id);
                     node.shape == other_node.shape
                         && node.op == other_node.op
+                        && node.no_fuse == other_node.no_fuse
                         && arg_sources == other_arg_sources
                 }) {
                     let mut edges = self.ops.neighbors_directed(node_id, Outgoing).detach();
@@ -195,6 +302,7 @@ impl Graph {
                         self.ops.add_edge(other_id, dst_id, edge);
                     }
                     self.ops.remove_node(node_id);
+                    self.ordering_dirty = true;
                 } else {
                     ids.push(node_id);
                 }
@@ -214,6 +322,7 @@ impl Graph {
                     self.ops.add_edge(new_node_id, out_node_id, new_edge);
                 }
                 self.ops.remove_node(node_id);
+                self.ordering_dirty = true;
             }
         }
     }
@@ -228,6 +337,35 @@ impl Graph {
                 Op::Binary(BinaryOp::UAdd) => Some(Literal::U32(0)),
                 _ => None,
             };
+            let self_literal = match &self.ops[node_id].op {
+                Op::Binary(BinaryOp::Sub) => Some(Literal::F32(NotNan::new(0.0).unwrap())),
+                Op::Binary(BinaryOp::Div) => Some(Literal::F32(NotNan::new(1.0).unwrap())),
+                _ => None,
+            };
+            if let Some(self_literal) = self_literal {
+                // x - x and x/x are constant when reading the same elements, the literal
+                // must be consumed by per-element ops since it has no buffer
+                let arg_sources = get_arg_sources(&self.ops, node_id);
+                if arg_sources[0] == arg_sources[1]
+                    && self
+                        .ops
+                        .neighbors_directed(node_id, Outgoing)
+                        .all(|dst_node_id| {
+                            matches!(
+                                self.ops[dst_node_id].op,
+                                Op::Unary(_) | Op::Binary(_) | Op::CompareAndSelect(_)
+                            )
+                        })
+                {
+                    let mut in_edges = self.ops.neighbors_directed(node_id, Incoming).detach();
+                    while let Some(edge_id) = in_edges.next_edge(&self.ops) {
+                        self.ops.remove_edge(edge_id);
+                    }
+                    self.ops[node_id].op = Op::Literal(self_literal);
+                    self.ordering_dirty = true;
+                    continue;
+                }
+            }
             if let Some(skip_literal) = skip_literal {
                 let arg_edge_ids = get_arg_edge_ids(&self.ops, node_id);
                 let skip_literal_edge_id = arg_edge_ids.iter().copied().find(|&edge_id| {
@@ -243,6 +381,7 @@ impl Graph {
                             self.ops[edge_id].arg = 0;
                         }
                     }
+                    self.ordering_dirty = true;
                     mov_added = true;
                 }
             }
@@ -252,6 +391,440 @@ impl Graph {
         }
     }
 
+    fn fold_constants(&mut self) {
+        for node_id in self.ops_sorted.iter().copied() {
+            // only fold when the result can still be read inline by per-element kernels
+            let node = &self.ops[node_id];
+            if !matches!(
+                node.op,
+                Op::Unary(_) | Op::Binary(_) | Op::CompareAndSelect(_)
+            ) || !self
+                .ops
+                .neighbors_directed(node_id, Outgoing)
+                .all(|dst_node_id| {
+                    matches!(
+                        self.ops[dst_node_id].op,
+                        Op::Unary(_) | Op::Binary(_) | Op::CompareAndSelect(_)
+                    )
+                })
+            {
+                continue;
+            }
+
+            // literals have the same value everywhere, so their views do not matter
+            let args: TinyVec<[Literal; MAX_OP_ARGS]> = match get_arg_sources(&self.ops, node_id)
+                .iter()
+                .map(|src| match self.ops[src.node_id].op {
+                    Op::Literal(value) => Some(value),
+                    _ => None,
+                })
+                .collect()
+            {
+                Some(args) => args,
+                None => continue,
+            };
+            let value = match node.op {
+                Op::Unary(op) => {
+                    let value = op.eval(args[0].to_f32_bits());
+                    match (op, args[0]) {
+                        (UnaryOp::FloatToUint, _)
+                        | (UnaryOp::FloatToHalf, _)
+                        | (UnaryOp::Mov, Literal::U32(_)) => Some(Literal::U32(value.to_bits())),
+                        _ => finite_literal(value),
+                    }
+                }
+                Op::Binary(op) => {
+                    let value = op.eval(args[0].to_f32_bits(), args[1].to_f32_bits());
+                    if op.is_uint() {
+                        Some(Literal::U32(value.to_bits()))
+                    } else {
+                        finite_literal(value)
+                    }
+                }
+                Op::CompareAndSelect(compare_mode) => {
+                    let pass = compare_mode.eval(args[0].to_f32_bits(), args[1].to_f32_bits());
+                    Some(args[if pass { 2 } else { 3 }])
+                }
+                _ => unreachable!(),
+            };
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+
+            // replace with the new literal, removing any literals that are no longer used
+            let mut in_edges = self.ops.neighbors_directed(node_id, Incoming).detach();
+            while let Some((edge_id, src_node_id)) = in_edges.next(&self.ops) {
+                self.ops.remove_edge(edge_id);
+                if self
+                    .ops
+                    .edges_directed(src_node_id, Outgoing)
+                    .next()
+                    .is_none()
+                {
+                    self.ops.remove_node(src_node_id);
+                }
+            }
+            self.ops[node_id].op = Op::Literal(value);
+            self.ordering_dirty = true;
+        }
+    }
+
+    fn match_one_hot(&self, node_id: OpNodeId) -> Option<OpEdgeId> {
+        // matches select_eq(coord, index, 1.0, 0.0) along the last axis, returns the index edge
+        let node = &self.ops[node_id];
+        if node.op != Op::CompareAndSelect(CompareMode::Eq) {
+            return None;
+        }
+        let count = *node.shape.last()?;
+        let arg_edge_ids = get_arg_edge_ids(&self.ops, node_id);
+        let is_literal = |edge_id: OpEdgeId, value: f32| {
+            let src_node_id = self.ops.edge_endpoints(edge_id).unwrap().0;
+            self.ops[src_node_id].op == Op::Literal(Literal::F32(NotNan::new(value).unwrap()))
+        };
+        if !is_literal(arg_edge_ids[2], 1.0) || !is_literal(arg_edge_ids[3], 0.0) {
+            return None;
+        }
+        let is_coord = |edge_id: OpEdgeId| {
+            let src_node_id = self.ops.edge_endpoints(edge_id).unwrap().0;
+            let view = &self.ops[edge_id].view;
+            self.ops[src_node_id].op == Op::BuiltIn(BuiltInOp::Coord)
+                && view.input_shape == Shape::from([count])
+                && view.is_broadcast()
+        };
+        let index_edge_id = if is_coord(arg_edge_ids[0]) {
+            arg_edge_ids[1]
+        } else if is_coord(arg_edge_ids[1]) {
+            arg_edge_ids[0]
+        } else {
+            return None;
+        };
+        let index_view = &self.ops[index_edge_id].view;
+        if index_view.output_mapping.last() != Some(&AxisMapping::Broadcast) {
+            return None;
+        }
+        Some(index_edge_id)
+    }
+
+    fn replace_one_hot_matmuls(&mut self) -> bool {
+        let mut replaced = false;
+        for node_id in self.ops_sorted.iter().copied() {
+            if self.ops[node_id].op
+                != (Op::MatMul {
+                    output_mode: MatMulOutputMode::Batches,
+                    input_type: StorageType::F32,
+                })
+            {
+                continue;
+            }
+            let arg_edge_ids = get_arg_edge_ids(&self.ops, node_id);
+            let (a_edge_id, b_edge_id) = (arg_edge_ids[0], arg_edge_ids[1]);
+            let a_node_id = self.ops.edge_endpoints(a_edge_id).unwrap().0;
+            let b_node_id = self.ops.edge_endpoints(b_edge_id).unwrap().0;
+            let index_edge_id = match self.match_one_hot(a_node_id) {
+                Some(edge_id) => edge_id,
+                None => continue,
+            };
+            let index_node_id = self.ops.edge_endpoints(index_edge_id).unwrap().0;
+
+            // the gather replaces the sum over k chunks (if any)
+            let shape = self.ops[node_id].shape;
+            let target_node_id = if shape[0] == 1 {
+                node_id
+            } else {
+                match self.ops.edges_directed(node_id, Outgoing).only() {
+                    Some(edge_ref)
+                        if self.ops[edge_ref.target()].op
+                            == (Op::Reduce {
+                                reduce_op: ReduceOp::Sum,
+                                axis: Axis::from_index(0),
+                            })
+                            && edge_ref.weight().view == shape.identity_view() =>
+                    {
+                        edge_ref.target()
+                    }
+                    _ => continue,
+                }
+            };
+            let gather_shape = self.ops[target_node_id].shape;
+
+            // read rows of b directly, using the one-hot index as the row
+            let a_view = self.ops[a_edge_id].view;
+            let b_view = self.ops[b_edge_id].view;
+            let index_view = self.ops[index_edge_id].view;
+            let b_can_reshape = self.ops[b_node_id].op.can_reshape();
+            let b_outer_view = View::broadcast(
+                b_view.output_shape,
+                gather_shape.resize_axis(Axis::from_index(2), b_view.output_shape[1]),
+            );
+            if !a_view.is_broadcast()
+                || a_view.input_shape.element_count() != a_view.output_shape.element_count()
+                || !index_view.can_view_through(&a_view, false)
+                || !b_view.can_view_through(&b_outer_view, b_can_reshape)
+            {
+                continue;
+            }
+            let mut index_inner_view = index_view.through(&a_view, false);
+            index_inner_view.output_shape[SignedIndex(-1)] = gather_shape[SignedIndex(-1)];
+            let index_outer_view = View::broadcast(index_inner_view.output_shape, gather_shape);
+            let index_view = index_inner_view.through(&index_outer_view, false);
+            let values_view = b_view.through(&b_outer_view, b_can_reshape);
+
+            // one_hot of an index outside [0, count) is a row of zeros, so move negative
+            // indices past the end where the gather reads them as zero
+            let colour = self.ops[target_node_id].colour;
+            let index_shape = self.ops[index_node_id].shape;
+            let count = b_view.output_shape[1];
+            let literal_view = View::broadcast(Shape::from([1]), index_shape);
+            let range_node_id = self.ops.new_node(
+                colour,
+                index_shape,
+                Op::CompareAndSelect(CompareMode::Lt),
+                &[],
+            );
+            for (arg, value) in [None, Some(0.0), Some(count as f32), None]
+                .iter()
+                .copied()
+                .enumerate()
+            {
+                let (src_node_id, view) = match value {
+                    Some(value) => (
+                        self.ops.new_node(
+                            colour,
+                            [1],
+                            Op::Literal(Literal::F32(NotNan::new(value).unwrap())),
+                            &[],
+                        ),
+                        literal_view,
+                    ),
+                    None => (index_node_id, index_shape.identity_view()),
+                };
+                self.ops
+                    .add_edge(src_node_id, range_node_id, OpEdge { arg, view });
+            }
+            let uint_node_id = self.ops.new_node(
+                colour,
+                index_shape,
+                Op::Unary(UnaryOp::FloatToUint),
+                &[range_node_id],
+            );
+            let mut in_edges = self
+                .ops
+                .neighbors_directed(target_node_id, Incoming)
+                .detach();
+            while let Some(edge_id) = in_edges.next_edge(&self.ops) {
+                self.ops.remove_edge(edge_id);
+            }
+            self.ops[target_node_id].op = Op::Gather {
+                axis: Axis::from_index(2),
+            };
+            self.ops.add_edge(
+                b_node_id,
+                target_node_id,
+                OpEdge {
+                    arg: 0,
+                    view: values_view,
+                },
+            );
+            self.ops.add_edge(
+                uint_node_id,
+                target_node_id,
+                OpEdge {
+                    arg: 1,
+                    view: index_view,
+                },
+            );
+            self.ordering_dirty = true;
+            replaced = true;
+        }
+        replaced
+    }
+
+    fn fuse_matmul_accumulates(&mut self) {
+        // find adds of a matmul result to some other buffer, the matmul can add into
+        // that buffer directly as part of its store
+        for node_id in self.ops_sorted.iter().copied() {
+            if !self.ops.contains_node(node_id)
+                || !matches!(self.ops[node_id].op, Op::MatMul { .. })
+                || self.ops[node_id].shape[0] != 1
+                || get_arg_edge_ids(&self.ops, node_id).len() != 2
+            {
+                continue;
+            }
+            let (mm_out_edge_id, add_node_id) =
+                match self.ops.edges_directed(node_id, Outgoing).only() {
+                    Some(edge_ref) => (edge_ref.id(), edge_ref.target()),
+                    None => continue,
+                };
+            let add_node = &self.ops[add_node_id];
+            if add_node.op != Op::Binary(BinaryOp::Add)
+                || add_node.no_fuse
+                || self.ops[node_id].no_fuse
+                || add_node.shape.element_count() != self.ops[node_id].shape.element_count()
+                || !self.ops[mm_out_edge_id].view.is_contiguous()
+            {
+                continue;
+            }
+            let acc_edge_id = match get_arg_edge_ids(&self.ops, add_node_id)
+                .iter()
+                .copied()
+                .find(|&edge_id| edge_id != mm_out_edge_id)
+            {
+                Some(edge_id) => edge_id,
+                None => continue,
+            };
+            let acc_node_id = self.ops.edge_endpoints(acc_edge_id).unwrap().0;
+            let acc_shape = self.ops[acc_node_id].shape;
+            if acc_node_id == node_id
+                || matches!(
+                    self.ops[acc_node_id].op,
+                    Op::Input { .. } | Op::Literal(_) | Op::BuiltIn(_)
+                )
+                || self.ops.edges_directed(acc_node_id, Outgoing).count() != 1
+                || !self.ops[acc_edge_id].view.is_contiguous()
+            {
+                continue;
+            }
+            let acc_view = match View::try_from_reshape(acc_shape, self.ops[node_id].shape) {
+                Some(view) => view,
+                None => continue,
+            };
+
+            // accumulate into the other buffer as the third matmul argument
+            let mm_out_view = self.ops[mm_out_edge_id].view;
+            let mut out_edges = self.ops.neighbors_directed(add_node_id, Outgoing).detach();
+            while let Some((out_edge_id, out_node_id)) = out_edges.next(&self.ops) {
+                let out_edge = &self.ops[out_edge_id];
+                let new_edge = OpEdge {
+                    arg: out_edge.arg,
+                    view: mm_out_view.through(&out_edge.view, true),
+                };
+                self.ops.add_edge(node_id, out_node_id, new_edge);
+            }
+            self.ops.remove_node(add_node_id);
+            self.ops.add_edge(
+                acc_node_id,
+                node_id,
+                OpEdge {
+                    arg: 2,
+                    view: acc_view,
+                },
+            );
+            self.ordering_dirty = true;
+        }
+    }
+
+    fn sink_broadcasts(&mut self) {
+        // find per-element ops that only read broadcasts of a smaller shape, these
+        // could be computed at the smaller shape with the broadcast moved to their uses
+        let mut small_shapes: HashMap<OpNodeId, Shape> = HashMap::new();
+        for node_id in self.ops_sorted.iter().copied() {
+            let node = &self.ops[node_id];
+            if !matches!(
+                node.op,
+                Op::Unary(_) | Op::Binary(_) | Op::CompareAndSelect(_)
+            ) {
+                continue;
+            }
+            let mut small_shape = None;
+            let mut is_candidate = true;
+            for edge_ref in self.ops.edges_directed(node_id, Incoming) {
+                let src_node_id = edge_ref.source();
+                let view = &edge_ref.weight().view;
+                let src_shape = if let Some(shape) = small_shapes.get(&src_node_id) {
+                    if *view != node.shape.identity_view() {
+                        is_candidate = false;
+                        break;
+                    }
+                    Some(*shape)
+                } else if view.is_broadcast() {
+                    let src_shape = self.ops[src_node_id].shape;
+                    if src_shape.element_count() > 1 {
+                        Some(src_shape)
+                    } else {
+                        None
+                    }
+                } else {
+                    is_candidate = false;
+                    break;
+                };
+                if let Some(src_shape) = src_shape {
+                    if *small_shape.get_or_insert(src_shape) != src_shape {
+                        is_candidate = false;
+                        break;
+                    }
+                }
+            }
+            if let Some(small_shape) = small_shape.filter(|_| is_candidate) {
+                let single_elements_fit =
+                    self.ops
+                        .neighbors_directed(node_id, Incoming)
+                        .all(|src_node_id| {
+                            small_shapes.contains_key(&src_node_id)
+                                || self.ops[src_node_id].shape.len() <= small_shape.len()
+                        });
+                if small_shape.element_count() < node.shape.element_count() && single_elements_fit {
+                    small_shapes.insert(node_id, small_shape);
+                }
+            }
+        }
+
+        // only sink when every use is either also sunk or a reduction that can
+        // read through the broadcast view (otherwise we would split a kernel)
+        let mut sunk: HashMap<OpNodeId, Shape> = HashMap::new();
+        for node_id in self.ops_sorted.iter().rev().copied() {
+            if let Some(small_shape) = small_shapes.get(&node_id).copied() {
+                let node = &self.ops[node_id];
+                let broadcast_view = View::broadcast(small_shape, node.shape);
+                let can_reshape = node.op.can_reshape();
+                let has_reduce = self
+                    .ops
+                    .edges_directed(node_id, Outgoing)
+                    .any(|edge_ref| matches!(self.ops[edge_ref.target()].op, Op::Reduce { .. }));
+                let can_sink = self.ops.edges_directed(node_id, Outgoing).all(|edge_ref| {
+                    let dst_node_id = edge_ref.target();
+                    sunk.contains_key(&dst_node_id)
+                        || (matches!(self.ops[dst_node_id].op, Op::Reduce { .. })
+                            && broadcast_view
+                                .can_view_through(&edge_ref.weight().view, can_reshape))
+                });
+                let feeds_sunk = self
+                    .ops
+                    .neighbors_directed(node_id, Outgoing)
+                    .any(|dst_node_id| sunk.contains_key(&dst_node_id));
+                if can_sink && (has_reduce || feeds_sunk) {
+                    sunk.insert(node_id, small_shape);
+                }
+            }
+        }
+
+        // compute the sunk ops at the small shape and broadcast on the way out
+        for node_id in self.ops_sorted.iter().copied() {
+            if let Some(small_shape) = sunk.get(&node_id).copied() {
+                let big_shape = self.ops[node_id].shape;
+                let can_reshape = self.ops[node_id].op.can_reshape();
+                let mut in_edges = self.ops.neighbors_directed(node_id, Incoming).detach();
+                while let Some((edge_id, src_node_id)) = in_edges.next(&self.ops) {
+                    let src_shape = if sunk.contains_key(&src_node_id) {
+                        small_shape
+                    } else {
+                        self.ops[src_node_id].shape
+                    };
+                    self.ops[edge_id].view = View::broadcast(src_shape, small_shape);
+                }
+                let broadcast_view = View::broadcast(small_shape, big_shape);
+                let mut out_edges = self.ops.neighbors_directed(node_id, Outgoing).detach();
+                while let Some((edge_id, dst_node_id)) = out_edges.next(&self.ops) {
+                    if !sunk.contains_key(&dst_node_id) {
+                        let view = &mut self.ops[edge_id].view;
+                        *view = broadcast_view.through(view, can_reshape);
+                    }
+                }
+                self.ops[node_id].shape = small_shape;
+            }
+        }
+    }
+
     fn eliminate_moves(&mut self) {
         for node_id in self.ops_sorted.iter().copied() {
             if let Op::Unary(UnaryOp::Mov) = &self.ops[node_id].op {
@@ -296,6 +869,7 @@ impl Graph {
                             self.ops.add_edge(in_node_id, out_node_id, new_edge);
                         }
                         self.ops.remove_node(node_id);
+                        self.ordering_dirty = true;
                     }
                 }else{
                     eprintln!("Cannot eliminate move node {:?} with no incoming edges: {:?}", node_id, &self.ops[node_id]);
@@ -348,7 +922,7 @@ impl Graph {
     }
 
     #[allow(clippy::blocks_in_if_conditions)]
-    fn build_clusters(&mut self) {
+    fn build_clusters(&mut self, fuse_matmul_epilogues: bool) {
         // first gather per-element nodes into kernels
         for first_node_id in self.ops_sorted.iter().copied() {
             let first_node = &self.ops[first_node_id];
@@ -357,6 +931,7 @@ impl Graph {
             }
             if first_node.op.is_per_element() {
                 let element_count = first_node.shape.element_count();
+                let no_fuse = first_node.no_fuse;
 
                 let cluster_id = Some(self.clusters.insert(Cluster {
                     kernel: GenericKernel::PerElement(PerElementKernel {
@@ -371,11 +946,15 @@ impl Graph {
                 self.ops[first_node_id].cluster_id = cluster_id;
 
                 'outer: loop {
+                    if no_fuse {
+                        break 'outer;
+                    }
                     'inner: for other_node_id in self.ops_sorted.iter().copied() {
                         let other_node = &self.ops[other_node_id];
 
                         // check this node has no cluster and matches element count
                         let can_include = other_node.cluster_id.is_none()
+                            && !other_node.no_fuse
                             && other_node.op.is_per_element()
                             && other_node.shape.element_count() == element_count;
                         if !can_include {
@@ -559,25 +1138,41 @@ impl Graph {
                                 input: src0.view,
                                 reduce_op,
                                 axis,
+                                pairwise: reduce_op == ReduceOp::Sum
+                                    && src0.view.output_shape[axis] >= REDUCE_PAIRWISE_MIN_K_SIZE,
+                                prologue: None,
+                                epilogue: None,
                             }),
                             inputs: vec![src0.node_id],
                             outputs: vec![ClusterOutput::new(node_id)],
                         }));
                     }
-                    Op::MatMul { output_mode } => {
+                    Op::MatMul {
+                        output_mode,
+                        input_type,
+                    } => {
                         let arg_sources = get_arg_sources(&self.ops, node_id);
-                        assert_eq!(arg_sources.len(), 2);
+                        assert!(matches!(arg_sources.len(), 2 | 3));
                         let a = &arg_sources[0];
                         let b = &arg_sources[1];
+                        let output = if let Some(acc) = arg_sources.get(2) {
+                            assert!(acc.view.is_contiguous());
+                            ClusterOutput::copy(node_id, acc.node_id)
+                        } else {
+                            ClusterOutput::new(node_id)
+                        };
                         self.ops[node_id].cluster_id = Some(self.clusters.insert(Cluster {
                             kernel: GenericKernel::MatMul(MatMulKernel {
                                 shape: node.shape,
                                 output_mode,
+                                input_type,
                                 a: a.view,
                                 b: b.view,
+                                accumulate: arg_sources.len() == 3,
+                                epilogue: None,
                             }),
                             inputs: vec![a.node_id, b.node_id],
-                            outputs: vec![ClusterOutput::new(node_id)],
+                            outputs: vec![output],
                         }));
                     }
                     Op::Unpad { axis, pad } => {
@@ -590,6 +1185,7 @@ impl Graph {
                                 input: src0.view,
                                 axis,
                                 pad,
+                                prologue: None,
                             }),
                             inputs: vec![src0.node_id],
                             outputs: vec![ClusterOutput::new(node_id)],
@@ -625,6 +1221,7 @@ impl Graph {
                                 values: values.view,
                                 axis,
                                 indices: indices.view,
+                                deterministic: false,
                             }),
                             inputs: vec![values.node_id, indices.node_id],
                             outputs: vec![ClusterOutput::copy(node_id, acc.node_id)],
@@ -639,6 +1236,13 @@ impl Graph {
             }
         }
 
+        self.fuse_unpad_prologues();
+        self.fuse_reduce_epilogues();
+        self.fuse_reduce_prologues();
+        if fuse_matmul_epilogues {
+            self.fuse_matmul_epilogues();
+        }
+
         // make cluster ordering
         let mut cluster_graph = StableDiGraph::<ClusterId, (), usize>::default();
         let mut cluster_node_ids = SecondaryMap::new();
@@ -664,19 +1268,583 @@ impl Graph {
         assert_eq!(self.clusters_sorted.len(), self.clusters.len());
     }
 
+    // Splits the cluster order into waves of clusters that do not depend on each other,
+    // so that the clusters of a wave can run concurrently.  A cluster that writes over
+    // a node in place also waits for the other clusters that read that node.
+    pub(crate) fn cluster_waves(&self) -> Vec<&[ClusterId]> {
+        let mut readers: HashMap<OpNodeId, Vec<ClusterId>> = HashMap::new();
+        for (cluster_id, cluster) in self.clusters.iter() {
+            for node_id in cluster.inputs.iter().copied() {
+                readers.entry(node_id).or_default().push(cluster_id);
+            }
+        }
+
+        let mut waves = Vec::new();
+        let mut wave_begin = 0;
+        let mut wave_members = HashSet::new();
+        for (index, cluster_id) in self.clusters_sorted.iter().copied().enumerate() {
+            let cluster = &self.clusters[cluster_id];
+            let in_place_node_ids: Vec<_> = cluster
+                .outputs
+                .iter()
+                .filter_map(|output| match output.initial_state {
+                    InitialState::CopyFrom(node_id) => Some(node_id),
+                    InitialState::Undefined => None,
+                })
+                .collect();
+            let reads_wave = cluster
+                .inputs
+                .iter()
+                .chain(in_place_node_ids.iter())
+                .filter_map(|node_id| self.ops[*node_id].cluster_id)
+                .any(|other_id| wave_members.contains(&other_id));
+            let overwrites_wave = in_place_node_ids
+                .iter()
+                .filter_map(|node_id| readers.get(node_id))
+                .flatten()
+                .any(|other_id| wave_members.contains(other_id));
+            if reads_wave || overwrites_wave {
+                waves.push(&self.clusters_sorted[wave_begin..index]);
+                wave_begin = index;
+                wave_members.clear();
+            }
+            wave_members.insert(cluster_id);
+        }
+        if wave_begin < self.clusters_sorted.len() {
+            waves.push(&self.clusters_sorted[wave_begin..]);
+        }
+        waves
+    }
+
+    fn cluster_has_no_fuse(&self, cluster_id: ClusterId) -> bool {
+        self.ops
+            .node_weights()
+            .any(|node| node.cluster_id == Some(cluster_id) && node.no_fuse)
+    }
+
+    fn fuse_unpad_prologues(&mut self) {
+        // A per-element kernel whose only output is only read by an unpad can be evaluated
+        // inside the unpad kernel at each element it reads, which avoids writing the padded
+        // intermediate to memory.
+        let unpad_cluster_ids: Vec<_> = self
+            .clusters
+            .iter()
+            .filter(|(_, cluster)| matches!(cluster.kernel, GenericKernel::Unpad(_)))
+            .map(|(cluster_id, _)| cluster_id)
+            .collect();
+        for unpad_cluster_id in unpad_cluster_ids {
+            let unpad_node_id = self.clusters[unpad_cluster_id].outputs[0].node_id;
+            let src_node_id = self.clusters[unpad_cluster_id].inputs[0];
+            let src_cluster_id = match self.ops[src_node_id].cluster_id {
+                Some(cluster_id) => cluster_id,
+                None => continue,
+            };
+
+            // the source must be the single output of a per-element kernel
+            let src_cluster = &self.clusters[src_cluster_id];
+            if self.cluster_has_no_fuse(src_cluster_id)
+                || self.ops[unpad_node_id].no_fuse
+                || !matches!(src_cluster.kernel, GenericKernel::PerElement(_))
+                || src_cluster.outputs.len() != 1
+                || src_cluster.outputs[0].node_id != src_node_id
+                || !matches!(
+                    src_cluster.outputs[0].initial_state,
+                    InitialState::Undefined
+                )
+            {
+                continue;
+            }
+            if self
+                .ops
+                .neighbors_directed(src_node_id, Outgoing)
+                .any(|node_id| node_id != unpad_node_id)
+            {
+                continue;
+            }
+
+            // move the per-element kernel into the unpad cluster
+            let src_cluster = self.clusters.remove(src_cluster_id).unwrap();
+            let kernel = match src_cluster.kernel {
+                GenericKernel::PerElement(kernel) => kernel,
+                _ => unreachable!(),
+            };
+            let unpad_cluster = &mut self.clusters[unpad_cluster_id];
+            match &mut unpad_cluster.kernel {
+                GenericKernel::Unpad(unpad_kernel) => unpad_kernel.prologue = Some(kernel),
+                _ => unreachable!(),
+            }
+            unpad_cluster.inputs = src_cluster.inputs;
+            for node in self.ops.node_weights_mut() {
+                if node.cluster_id == Some(src_cluster_id) {
+                    node.cluster_id = Some(unpad_cluster_id);
+                }
+            }
+        }
+    }
+
+    fn fuse_reduce_prologues(&mut self) {
+        // A per-element kernel whose only output is only read by a reduction can be evaluated
+        // inside the reduction loop, which avoids writing the reduction input to memory.
+        // Only reductions without an epilogue are considered, so that the input buffers
+        // of the two per-element kernels do not need to be renamed.
+        let reduce_cluster_ids: Vec<_> = self
+            .clusters
+            .iter()
+            .filter(|(_, cluster)| {
+                matches!(&cluster.kernel, GenericKernel::Reduce(kernel) if kernel.epilogue.is_none())
+            })
+            .map(|(cluster_id, _)| cluster_id)
+            .collect();
+        for reduce_cluster_id in reduce_cluster_ids {
+            let reduce_node_id = self.clusters[reduce_cluster_id].outputs[0].node_id;
+            let src_node_id = self.clusters[reduce_cluster_id].inputs[0];
+            let src_cluster_id = match self.ops[src_node_id].cluster_id {
+                Some(cluster_id) => cluster_id,
+                None => continue,
+            };
+
+            // the source must be the single output of a per-element kernel
+            let src_cluster = &self.clusters[src_cluster_id];
+            if self.cluster_has_no_fuse(src_cluster_id)
+                || self.ops[reduce_node_id].no_fuse
+                || !matches!(src_cluster.kernel, GenericKernel::PerElement(_))
+                || src_cluster.outputs.len() != 1
+                || src_cluster.outputs[0].node_id != src_node_id
+                || !matches!(
+                    src_cluster.outputs[0].initial_state,
+                    InitialState::Undefined
+                )
+            {
+                continue;
+            }
+            if self
+                .ops
+                .neighbors_directed(src_node_id, Outgoing)
+                .any(|node_id| node_id != reduce_node_id)
+            {
+                continue;
+            }
+
+            // move the per-element kernel into the reduction cluster
+            let src_cluster = self.clusters.remove(src_cluster_id).unwrap();
+            let kernel = match src_cluster.kernel {
+                GenericKernel::PerElement(kernel) => kernel,
+                _ => unreachable!(),
+            };
+            let reduce_cluster = &mut self.clusters[reduce_cluster_id];
+            match &mut reduce_cluster.kernel {
+                GenericKernel::Reduce(reduce_kernel) => reduce_kernel.prologue = Some(kernel),
+                _ => unreachable!(),
+            }
+            reduce_cluster.inputs = src_cluster.inputs;
+            for node in self.ops.node_weights_mut() {
+                if node.cluster_id == Some(src_cluster_id) {
+                    node.cluster_id = Some(reduce_cluster_id);
+                }
+            }
+        }
+    }
+
+    fn fuse_reduce_epilogues(&mut self) {
+        // A per-element kernel that is the only consumer of a reduction can run at the end
+        // of the reduction kernel.  Each invocation has the complete reduction result for
+        // its element once the reduction loop finishes, so the per-element ops can run
+        // from the value in registers without a round trip through memory.
+        let reduce_cluster_ids: Vec<_> = self
+            .clusters
+            .iter()
+            .filter(|(_, cluster)| matches!(cluster.kernel, GenericKernel::Reduce(_)))
+            .map(|(cluster_id, _)| cluster_id)
+            .collect();
+        for reduce_cluster_id in reduce_cluster_ids {
+            let reduce_node_id = self.clusters[reduce_cluster_id].outputs[0].node_id;
+            let reduce_shape = self.ops[reduce_node_id].shape;
+
+            // check all uses are in a single per-element kernel
+            let mut dst_cluster_ids = self
+                .ops
+                .neighbors_directed(reduce_node_id, Outgoing)
+                .map(|node_id| self.ops[node_id].cluster_id);
+            let dst_cluster_id = match dst_cluster_ids.next() {
+                Some(Some(cluster_id)) => cluster_id,
+                _ => continue,
+            };
+            if !dst_cluster_ids.all(|cluster_id| cluster_id == Some(dst_cluster_id)) {
+                continue;
+            }
+            if self.cluster_has_no_fuse(dst_cluster_id) || self.ops[reduce_node_id].no_fuse {
+                continue;
+            }
+            let dst_cluster = &self.clusters[dst_cluster_id];
+            let kernel = match &dst_cluster.kernel {
+                GenericKernel::PerElement(kernel) => kernel,
+                _ => continue,
+            };
+
+            // the kernel must read the result once per element at the same index
+            let mut input_indices = dst_cluster
+                .inputs
+                .iter()
+                .enumerate()
+                .filter(|(_, node_id)| **node_id == reduce_node_id)
+                .map(|(input_index, _)| input_index);
+            let input_index = match (input_indices.next(), input_indices.next()) {
+                (Some(input_index), None) => input_index,
+                _ => continue,
+            };
+            if kernel.element_count != reduce_shape.element_count()
+                || kernel.inputs[input_index] != reduce_shape.identity_view()
+                || kernel.ops.iter().any(|op| match op {
+                    PerElementKernelOp::Gather {
+                        input_index: index, ..
+                    } => *index == input_index,
+                    _ => false,
+                })
+            {
+                continue;
+            }
+
+            // move the per-element kernel into the reduction cluster
+            let dst_cluster = self.clusters.remove(dst_cluster_id).unwrap();
+            let kernel = match dst_cluster.kernel {
+                GenericKernel::PerElement(kernel) => kernel,
+                _ => unreachable!(),
+            };
+            let reduce_cluster = &mut self.clusters[reduce_cluster_id];
+            match &mut reduce_cluster.kernel {
+                GenericKernel::Reduce(reduce_kernel) => {
+                    reduce_kernel.epilogue = Some(PerElementEpilogue {
+                        kernel,
+                        input_index,
+                    })
+                }
+                _ => unreachable!(),
+            }
+            reduce_cluster.inputs.extend(
+                dst_cluster
+                    .inputs
+                    .iter()
+                    .copied()
+                    .filter(|&node_id| node_id != reduce_node_id),
+            );
+            reduce_cluster.outputs = dst_cluster.outputs;
+            for node in self.ops.node_weights_mut() {
+                if node.cluster_id == Some(dst_cluster_id) {
+                    node.cluster_id = Some(reduce_cluster_id);
+                }
+            }
+        }
+    }
+
+    fn fuse_matmul_epilogues(&mut self) {
+        // A per-element kernel that is the only consumer of a matrix multiply can run as
+        // each result is stored, such as the bias add and activation of a linear layer.
+        // Only results that are complete in a single k chunk are considered.
+        let matmul_cluster_ids: Vec<_> = self
+            .clusters
+            .iter()
+            .filter(|(_, cluster)| {
+                matches!(&cluster.kernel, GenericKernel::MatMul(kernel)
+                    if !kernel.accumulate && kernel.k_chunk_count() == 1)
+            })
+            .map(|(cluster_id, _)| cluster_id)
+            .collect();
+        for matmul_cluster_id in matmul_cluster_ids {
+            let matmul_node_id = self.clusters[matmul_cluster_id].outputs[0].node_id;
+            let matmul_shape = self.ops[matmul_node_id].shape;
+
+            // check all uses are in a single per-element kernel
+            let mut dst_cluster_ids = self
+                .ops
+                .neighbors_directed(matmul_node_id, Outgoing)
+                .map(|node_id| self.ops[node_id].cluster_id);
+            let dst_cluster_id = match dst_cluster_ids.next() {
+                Some(Some(cluster_id)) => cluster_id,
+                _ => continue,
+            };
+            if !dst_cluster_ids.all(|cluster_id| cluster_id == Some(dst_cluster_id)) {
+                continue;
+            }
+            if self.cluster_has_no_fuse(dst_cluster_id) || self.ops[matmul_node_id].no_fuse {
+                continue;
+            }
+            let dst_cluster = &self.clusters[dst_cluster_id];
+            let kernel = match &dst_cluster.kernel {
+                GenericKernel::PerElement(kernel) => kernel,
+                _ => continue,
+            };
+
+            // the kernel must read the result once per element in memory order
+            let mut input_indices = dst_cluster
+                .inputs
+                .iter()
+                .enumerate()
+                .filter(|(_, node_id)| **node_id == matmul_node_id)
+                .map(|(input_index, _)| input_index);
+            let input_index = match (input_indices.next(), input_indices.next()) {
+                (Some(input_index), None) => input_index,
+                _ => continue,
+            };
+            let input_view = &kernel.inputs[input_index];
+            if kernel.element_count != matmul_shape.element_count()
+                || input_view.input_shape != matmul_shape
+                || !input_view.is_contiguous()
+                || kernel.ops.iter().any(|op| match op {
+                    PerElementKernelOp::Gather {
+                        input_index: index, ..
+                    } => *index == input_index,
+                    _ => false,
+                })
+            {
+                continue;
+            }
+
+            // move the per-element kernel into the matrix multiply cluster
+            let dst_cluster = self.clusters.remove(dst_cluster_id).unwrap();
+            let kernel = match dst_cluster.kernel {
+                GenericKernel::PerElement(kernel) => kernel,
+                _ => unreachable!(),
+            };
+            let matmul_cluster = &mut self.clusters[matmul_cluster_id];
+            match &mut matmul_cluster.kernel {
+                GenericKernel::MatMul(matmul_kernel) => {
+                    matmul_kernel.epilogue = Some(PerElementEpilogue {
+                        kernel,
+                        input_index,
+                    })
+                }
+                _ => unreachable!(),
+            }
+            matmul_cluster.inputs.extend(
+                dst_cluster
+                    .inputs
+                    .iter()
+                    .copied()
+                    .filter(|&node_id| node_id != matmul_node_id),
+            );
+            matmul_cluster.outputs = dst_cluster.outputs;
+            for node in self.ops.node_weights_mut() {
+                if node.cluster_id == Some(dst_cluster_id) {
+                    node.cluster_id = Some(matmul_cluster_id);
+                }
+            }
+        }
+    }
+
+    pub fn memory_report(&self) -> MemoryReport {
+        let mut report = MemoryReport {
+            input_bytes: 0,
+            output_bytes: 0,
+            intermediate_bytes: 0,
+            heap_bytes: 0,
+            cluster_peak_bytes: Vec::new(),
+        };
+        for node in self.ops.node_weights() {
+            match node.op {
+                Op::Input { .. } => report.input_bytes += node.shape.buffer_size(),
+                Op::Output { .. } => report.output_bytes += node.shape.buffer_size(),
+                _ => {}
+            }
+        }
+
+        // replay the intermediate buffer allocations made by Environment::run
+        let mut heap = Heap::<MemoryReportBufferId, ()>::default();
+        heap.extend_with((), usize::MAX >> 1);
+        let mut usage_counts = vec![0usize; self.ops.node_bound()];
+        for node_id in self
+            .clusters
+            .values()
+            .flat_map(|cluster| cluster.inputs.iter())
+        {
+            usage_counts[node_id.index()] += 1;
+        }
+        let mut buffer_ids = vec![None; self.ops.node_bound()];
+        let mut live_bytes = 0;
+        for wave in self.cluster_waves() {
+            for cluster_id in wave.iter().copied() {
+                let cluster = &self.clusters[cluster_id];
+                for node_id in cluster.inputs.iter().copied() {
+                    usage_counts[node_id.index()] -= 1;
+                }
+                for output in cluster.outputs.iter() {
+                    let buffer_id = match output.initial_state {
+                        // inputs are updated in place in the parameter buffer
+                        InitialState::CopyFrom(src_node_id)
+                            if matches!(self.ops[src_node_id].op, Op::Input { .. }) =>
+                        {
+                            continue
+                        }
+                        InitialState::CopyFrom(src_node_id) => {
+                            buffer_ids[src_node_id.index()].take()
+                        }
+                        InitialState::Undefined => None,
+                    };
+                    let buffer_id = buffer_id.unwrap_or_else(|| {
+                        let size = self.ops[output.node_id].shape.buffer_size();
+                        let buffer_id = heap.alloc(size, MEMORY_REPORT_ALIGNMENT).unwrap();
+                        live_bytes += size;
+                        report.intermediate_bytes += size;
+                        report.heap_bytes = report.heap_bytes.max(heap.info(buffer_id).range.end);
+                        buffer_id
+                    });
+                    buffer_ids[output.node_id.index()] = Some(buffer_id);
+                }
+                report.cluster_peak_bytes.push(live_bytes);
+            }
+
+            // buffers are only freed once the whole wave has run
+            for cluster_id in wave.iter().copied() {
+                for node_id in self.clusters[cluster_id].inputs.iter().copied() {
+                    if usage_counts[node_id.index()] == 0 {
+                        if let Some(buffer_id) = buffer_ids[node_id.index()].take() {
+                            live_bytes -= heap.info(buffer_id).range.size();
+                            heap.free(buffer_id);
+                        }
+                    }
+                }
+            }
+        }
+
+        report
+    }
+
+    pub fn inputs(&self) -> Vec<(Parameter, Shape)> {
+        self.parameters_of(|op| op.input_parameter_id())
+    }
+
+    pub fn outputs(&self) -> Vec<(Parameter, Shape)> {
+        self.parameters_of(|op| op.output_parameter_id())
+    }
+
+    fn parameters_of(&self, f: impl Fn(&Op) -> Option<ParameterId>) -> Vec<(Parameter, Shape)> {
+        let mut visited = HashSet::new();
+        self.ops
+            .node_weights()
+            .filter_map(|node| {
+                f(&node.op)
+                    .filter(|&parameter_id| visited.insert(parameter_id))
+                    .map(|parameter_id| {
+                        (Parameter::new(parameter_id, &self.parameters), node.shape)
+                    })
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "serialize")]
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        // store the graph after optimization, so that loading skips all passes
+        let w = io::BufWriter::new(File::create(path)?);
+        serde_json::to_writer(
+            w,
+            &(
+                &self.ops,
+                &self.ops_sorted,
+                &self.clusters,
+                &self.clusters_sorted,
+            ),
+        )?;
+        Ok(())
+    }
+
+    #[cfg(feature = "serialize")]
+    pub(crate) fn load(parameters: SharedParameters, path: impl AsRef<Path>) -> io::Result<Self> {
+        let r = io::BufReader::new(File::open(path)?);
+        let (ops, ops_sorted, clusters, clusters_sorted): (OpGraph, _, _, _) =
+            serde_json::from_reader(r)?;
+
+        // parameters are stored by id, so must be created in the same order as when saved
+        {
+            let parameters = parameters.borrow();
+            for node in ops.node_weights() {
+                if let Some(parameter_id) = node
+                    .op
+                    .input_parameter_id()
+                    .or_else(|| node.op.output_parameter_id())
+                {
+                    let shape = parameters.get(parameter_id).map(|storage| storage.shape);
+                    if shape != Some(node.shape) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "parameter does not match the saved graph",
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            parameters,
+            ops,
+            ops_sorted,
+            ordering_dirty: false,
+            clusters,
+            clusters_sorted,
+        })
+    }
+
+    pub fn dump_kernels(&self) -> String {
+        let mut s = String::new();
+        for (index, cluster_id) in self.clusters_sorted.iter().copied().enumerate() {
+            s.push_str(&format!("// kernel {}\n", index));
+            s.push_str(&self.clusters[cluster_id].kernel.dump_source());
+        }
+        s
+    }
+
     pub fn write_dot_file(&self, kernel_output: KernelDotOutput, path: &str) {
         let mut w = io::BufWriter::new(File::create(path).unwrap());
-        self.write_dot(kernel_output, &mut w).unwrap();
+        self.write_dot(kernel_output, None, &mut w).unwrap();
     }
 
-    fn write_dot(&self, kernel_output: KernelDotOutput, w: &mut impl io::Write) -> io::Result<()> {
+    // cluster times are in milliseconds in execution order, and label the cluster subgraphs
+    pub fn write_dot_file_profiled(
+        &self,
+        kernel_output: KernelDotOutput,
+        cluster_times: &[f32],
+        path: &str,
+    ) {
+        let mut w = io::BufWriter::new(File::create(path).unwrap());
+        self.write_dot(kernel_output, Some(cluster_times), &mut w)
+            .unwrap();
+    }
+
+    pub(crate) fn write_dot(
+        &self,
+        kernel_output: KernelDotOutput,
+        cluster_times: Option<&[f32]>,
+        w: &mut impl io::Write,
+    ) -> io::Result<()> {
+        let cluster_times: Option<HashMap<ClusterId, f32>> = cluster_times.map(|times| {
+            assert_eq!(times.len(), self.clusters_sorted.len());
+            self.clusters_sorted
+                .iter()
+                .copied()
+                .zip(times.iter().copied())
+                .collect()
+        });
+        let total_time: f32 = cluster_times
+            .as_ref()
+            .map_or(0.0, |times| times.values().sum());
+
         writeln!(w, "digraph G {{")?;
         for (index, cluster_id) in iter::once(None)
             .chain(self.clusters.keys().map(Some))
             .enumerate()
         {
             if kernel_output == KernelDotOutput::Cluster && cluster_id.is_some() {
-                writeln!(w, "subgraph cluster{} {{ style=filled;", index)?;
+                write!(w, "subgraph cluster{} {{ style=filled;", index)?;
+                if let Some(time) = cluster_times
+                    .as_ref()
+                    .map(|times| times[&cluster_id.unwrap()])
+                {
+                    let percent = if total_time > 0.0 {
+                        100.0 * time / total_time
+                    } else {
+                        0.0
+                    };
+                    write!(w, " label=\"{:.3}ms ({:.1}%)\";", time, percent)?;
+                }
+                writeln!(w)?;
             }
             for node_ref in self
                 .ops
@@ -773,3 +1941,37 @@ impl Graph {
         writeln!(w, "}}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchanged_passes_keep_ordering() {
+        let mut env = CpuEnvironment::new();
+        let x_param = env.static_parameter_with_data([4], "x", &[1.0, 2.0, 3.0, 4.0]);
+        let y_param = env.static_parameter([4], "y");
+        let mut g = env.build_graph(|scope| {
+            let x = scope.parameter_value(&x_param);
+            scope.write_parameter_value(&y_param, (x * 2.0).exp());
+        });
+        assert!(!g.ordering_dirty);
+
+        // running the passes again on the optimized graph changes nothing
+        g.eliminate_dead_code();
+        g.eliminate_moves();
+        g.simplify_arithmetic();
+        g.fold_constants();
+        g.sink_broadcasts();
+        g.eliminate_common_subgraphs();
+        g.fuse_matmul_accumulates();
+        assert!(!g.ordering_dirty);
+
+        // literals are replaced by new nodes, so the order must be rebuilt
+        g.make_built_ins_and_literals_unique();
+        assert!(g.ordering_dirty);
+        g.rebuild_ordering();
+        assert!(!g.ordering_dirty);
+        assert_eq!(g.ops_sorted.len(), g.ops.node_count());
+    }
+}
@@ -5,6 +5,7 @@ use std::{collections::HashMap, convert::TryInto, ffi::CStr, fmt, fmt::Write, me
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum PerElementKernelOp {
     Load {
         input_index: usize,
@@ -77,8 +78,17 @@ fn generate_output_buffer(
 }
 
 fn generate_coord(name: &str, shape: Shape, w: &mut impl Write) -> fmt::Result {
+    generate_coord_from_index(name, "gl_GlobalInvocationID.x", shape, w)
+}
+
+fn generate_coord_from_index(
+    name: &str,
+    index_name: &str,
+    shape: Shape,
+    w: &mut impl Write,
+) -> fmt::Result {
     writeln!(w, "int {}[{}];", name, shape.len())?;
-    write!(w, "compute_grid_coord(gl_GlobalInvocationID.x, {}", name)?;
+    write!(w, "compute_grid_coord({}, {}", index_name, name)?;
     for &n in shape.iter() {
         write!(w, ", {}", n)?;
     }
@@ -143,6 +153,7 @@ pub(crate) trait Kernel {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct FillKernel {
     pub(crate) value: Literal,
     pub(crate) element_count: usize,
@@ -193,6 +204,7 @@ impl Kernel for FillKernel {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct PerElementKernel {
     pub(crate) element_count: usize,
     pub(crate) inputs: Vec<View>,
@@ -200,30 +212,58 @@ pub(crate) struct PerElementKernel {
     pub(crate) ops: Vec<PerElementKernelOp>,
 }
 
-impl Kernel for PerElementKernel {
-    fn generate_source(&self) -> Result<String, fmt::Error> {
-        let mut src = String::new();
-        let w = &mut src;
-
-        let mut binding_index = 0;
-        for input_index in 0..self.inputs.len() {
-            generate_input_buffer(binding_index, input_index, w)?;
-            binding_index += 1;
+impl PerElementKernel {
+    fn dump_ops(&self, w: &mut String) -> fmt::Result {
+        writeln!(w, "per_element elements={}", self.element_count)?;
+        for (input_index, view) in self.inputs.iter().enumerate() {
+            writeln!(w, "  input{}: {:?}", input_index, view)?;
         }
-        for output_index in 0..self.outputs.len() {
-            generate_output_buffer(binding_index, output_index, w)?;
-            binding_index += 1;
+        for (op_index, op) in self.ops.iter().enumerate() {
+            write!(w, "  %{} = ", op_index)?;
+            match op {
+                PerElementKernelOp::Load { input_index } => write!(w, "load input{}", input_index)?,
+                PerElementKernelOp::Literal(value) => match value {
+                    Literal::F32(value) => write!(w, "literal {:E}", value.into_inner())?,
+                    Literal::U32(value) => write!(w, "literal {}u", value)?,
+                },
+                PerElementKernelOp::BuiltIn { op, view } => write!(w, "{:?} {:?}", op, view)?,
+                PerElementKernelOp::Unary { op, args } => write!(w, "{:?} %{}", op, args)?,
+                PerElementKernelOp::Binary { op, args } => {
+                    write!(w, "{:?} %{} %{}", op, args[0], args[1])?
+                }
+                PerElementKernelOp::CompareAndSelect { compare_mode, args } => write!(
+                    w,
+                    "Select{:?} %{} %{} %{} %{}",
+                    compare_mode, args[0], args[1], args[2], args[3]
+                )?,
+                PerElementKernelOp::Gather {
+                    shape,
+                    axis,
+                    input_index,
+                    arg,
+                } => write!(
+                    w,
+                    "gather input{} {} axis={} %{}",
+                    input_index,
+                    shape,
+                    axis.index(),
+                    arg
+                )?,
+            }
+            writeln!(w)?;
         }
+        for (output_index, op_index) in self.outputs.iter().enumerate() {
+            writeln!(w, "  store output{} %{}", output_index, op_index)?;
+        }
+        Ok(())
+    }
 
-        writeln!(w, "layout(local_size_x = 64) in;")?;
-        writeln!(w, "void main() {{")?;
-
-        writeln!(
-            w,
-            "if (gl_GlobalInvocationID.x >= {}) {{ return; }}",
-            self.element_count
-        )?;
-
+    fn generate_ops(
+        &self,
+        index_name: &str,
+        replace_input: Option<(usize, &str)>,
+        w: &mut String,
+    ) -> fmt::Result {
         let mut coord_set_names = HashMap::new();
         let get_coord_set_name =
             |names: &mut HashMap<Shape, String>, shape: Shape, w: &mut String| {
@@ -232,7 +272,7 @@ impl Kernel for PerElementKernel {
                     .entry(shape)
                     .or_insert_with(|| {
                         let name = format!("coord{}", next_index);
-                        generate_coord(&name, shape, w).unwrap();
+                        generate_coord_from_index(&name, index_name, shape, w).unwrap();
                         name
                     })
                     .clone()
@@ -240,6 +280,12 @@ impl Kernel for PerElementKernel {
 
         for (op_index, op) in self.ops.iter().enumerate() {
             match op {
+                PerElementKernelOp::Load { input_index }
+                    if replace_input.map(|(index, _)| index) == Some(*input_index) =>
+                {
+                    let (_, name) = replace_input.unwrap();
+                    writeln!(w, "float tmp{} = {};", op_index, name)?;
+                }
                 PerElementKernelOp::Load { input_index } => {
                     let view = &self.inputs[*input_index];
                     let coord_shape = view.output_shape;
@@ -247,7 +293,7 @@ impl Kernel for PerElementKernel {
 
                     write!(w, "float tmp{} = input{}[", op_index, input_index)?;
                     if *view == coord_shape.identity_view() {
-                        write!(w, "gl_GlobalInvocationID.x")?
+                        write!(w, "{}", index_name)?
                     } else {
                         generate_load_index(view, &coord_name, w)?;
                     }
@@ -271,7 +317,7 @@ impl Kernel for PerElementKernel {
                         BuiltInOp::Rand { uid } => {
                             write!(w, "float tmp{} = rand_from_index({}, ", op_index, uid)?;
                             if *view == coord_shape.identity_view() {
-                                write!(w, "int(gl_GlobalInvocationID.x)")?
+                                write!(w, "int({})", index_name)?
                             } else {
                                 generate_load_index(view, &coord_name, w)?;
                             }
@@ -285,12 +331,17 @@ impl Kernel for PerElementKernel {
                         UnaryOp::Mov => write!(w, "tmp{}", args)?,
                         UnaryOp::Neg => write!(w, "-tmp{}", args)?,
                         UnaryOp::Sqrt => write!(w, "sqrt(tmp{})", args)?,
+                        UnaryOp::Rsqrt => write!(w, "inversesqrt(tmp{})", args)?,
                         UnaryOp::Exp => write!(w, "exp(tmp{})", args)?,
                         UnaryOp::Log => write!(w, "log(tmp{})", args)?,
                         UnaryOp::Sin => write!(w, "sin(tmp{})", args)?,
                         UnaryOp::Cos => write!(w, "cos(tmp{})", args)?,
                         UnaryOp::UintToFloat => write!(w, "float(F2U(tmp{}))", args)?,
                         UnaryOp::FloatToUint => write!(w, "U2F(uint(tmp{}))", args)?,
+                        UnaryOp::FloatToHalf => {
+                            write!(w, "U2F(packHalf2x16(vec2(tmp{}, 0.0)))", args)?
+                        }
+                        UnaryOp::HalfToFloat => write!(w, "unpackHalf2x16(F2U(tmp{})).x", args)?,
                     }
                     writeln!(w, ";")?;
                 }
@@ -308,30 +359,49 @@ impl Kernel for PerElementKernel {
                         BinaryOp::UMul => {
                             write!(w, "U2F(F2U(tmp{}) * F2U(tmp{}))", args[0], args[1])?
                         }
+                        BinaryOp::UDiv => {
+                            write!(w, "U2F(F2U(tmp{}) / F2U(tmp{}))", args[0], args[1])?
+                        }
                         BinaryOp::URem => {
                             write!(w, "U2F(F2U(tmp{}) % F2U(tmp{}))", args[0], args[1])?
                         }
                         BinaryOp::UBitXor => {
                             write!(w, "U2F(F2U(tmp{}) ^ F2U(tmp{}))", args[0], args[1])?
                         }
+                        BinaryOp::UBitAnd => {
+                            write!(w, "U2F(F2U(tmp{}) & F2U(tmp{}))", args[0], args[1])?
+                        }
+                        BinaryOp::UBitOr => {
+                            write!(w, "U2F(F2U(tmp{}) | F2U(tmp{}))", args[0], args[1])?
+                        }
+                        BinaryOp::UShl => {
+                            write!(w, "U2F(F2U(tmp{}) << (F2U(tmp{}) & 31u))", args[0], args[1])?
+                        }
+                        BinaryOp::UShr => {
+                            write!(w, "U2F(F2U(tmp{}) >> (F2U(tmp{}) & 31u))", args[0], args[1])?
+                        }
                     }
                     writeln!(w, ";")?;
                 }
                 PerElementKernelOp::CompareAndSelect { compare_mode, args } => {
-                    write!(w, "float tmp{} = ", op_index)?;
-                    match compare_mode {
-                        CompareMode::Eq => write!(
-                            w,
-                            "(tmp{} == tmp{}) ? tmp{} : tmp{}",
-                            args[0], args[1], args[2], args[3]
-                        )?,
-                        CompareMode::Gt => write!(
-                            w,
-                            "(tmp{} > tmp{}) ? tmp{} : tmp{}",
-                            args[0], args[1], args[2], args[3]
-                        )?,
-                    }
-                    writeln!(w, ";")?;
+                    let (lhs, rhs) = if compare_mode.is_uint() {
+                        (
+                            format!("F2U(tmp{})", args[0]),
+                            format!("F2U(tmp{})", args[1]),
+                        )
+                    } else {
+                        (format!("tmp{}", args[0]), format!("tmp{}", args[1]))
+                    };
+                    writeln!(
+                        w,
+                        "float tmp{} = ({} {} {}) ? tmp{} : tmp{};",
+                        op_index,
+                        lhs,
+                        compare_mode.glsl_operator(),
+                        rhs,
+                        args[2],
+                        args[3]
+                    )?;
                 }
                 PerElementKernelOp::Gather {
                     shape,
@@ -344,22 +414,74 @@ impl Kernel for PerElementKernel {
 
                     writeln!(w, "int save{} = {}[{}];", op_index, coord_name, axis.index())?;
                     writeln!(w, "{}[{}] = F2I(tmp{});", coord_name, axis.index(), arg)?;
-                    write!(w, "float tmp{} = input{}[", op_index, input_index)?;
+
+                    // indices past the end of the input read as zero
+                    writeln!(w, "float tmp{} = 0.0;", op_index)?;
+                    writeln!(
+                        w,
+                        "if (uint({}[{}]) < {}u) {{",
+                        coord_name,
+                        axis.index(),
+                        view.output_shape[*axis]
+                    )?;
+                    write!(w, "tmp{} = input{}[", op_index, input_index)?;
                     generate_load_index(view, &coord_name, w)?;
                     writeln!(w, "];")?;
+                    writeln!(w, "}}")?;
                     writeln!(w, "{}[{}] = save{};", coord_name, axis.index(), op_index)?;
                 }
             }
         }
 
+        Ok(())
+    }
+
+    fn generate_body(
+        &self,
+        index_name: &str,
+        replace_input: Option<(usize, &str)>,
+        w: &mut String,
+    ) -> fmt::Result {
+        self.generate_ops(index_name, replace_input, w)?;
+
         for (output_index, src_index) in self.outputs.iter().enumerate() {
             writeln!(
                 w,
-                "output{}[gl_GlobalInvocationID.x] = tmp{};",
-                output_index, src_index
+                "output{}[{}] = tmp{};",
+                output_index, index_name, src_index
             )?;
         }
 
+        Ok(())
+    }
+}
+
+impl Kernel for PerElementKernel {
+    fn generate_source(&self) -> Result<String, fmt::Error> {
+        let mut src = String::new();
+        let w = &mut src;
+
+        let mut binding_index = 0;
+        for input_index in 0..self.inputs.len() {
+            generate_input_buffer(binding_index, input_index, w)?;
+            binding_index += 1;
+        }
+        for output_index in 0..self.outputs.len() {
+            generate_output_buffer(binding_index, output_index, w)?;
+            binding_index += 1;
+        }
+
+        writeln!(w, "layout(local_size_x = 64) in;")?;
+        writeln!(w, "void main() {{")?;
+
+        writeln!(
+            w,
+            "if (gl_GlobalInvocationID.x >= {}) {{ return; }}",
+            self.element_count
+        )?;
+
+        self.generate_body("gl_GlobalInvocationID.x", None, w)?;
+
         writeln!(w, "}}")?;
 
         Ok(src)
@@ -383,11 +505,15 @@ impl Kernel for PerElementKernel {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct MatMulKernel {
     pub(crate) shape: Shape,
     pub(crate) output_mode: MatMulOutputMode,
+    pub(crate) input_type: StorageType,
     pub(crate) a: View,
     pub(crate) b: View,
+    pub(crate) accumulate: bool,
+    pub(crate) epilogue: Option<PerElementEpilogue>,
 }
 
 impl MatMulKernel {
@@ -396,20 +522,20 @@ impl MatMulKernel {
     const TILE_K: usize = 16;
     const GROUP_SIZE: usize = 64;
 
-    fn m(&self) -> usize {
+    pub(crate) fn m(&self) -> usize {
         self.a.output_shape[SignedIndex(-2)]
     }
-    fn n(&self) -> usize {
+    pub(crate) fn n(&self) -> usize {
         self.b.output_shape[SignedIndex(-1)]
     }
-    fn k(&self) -> usize {
+    pub(crate) fn k(&self) -> usize {
         self.a.output_shape[SignedIndex(-1)]
     }
 
-    fn k_chunk_count(&self) -> usize {
+    pub(crate) fn k_chunk_count(&self) -> usize {
         self.shape[0]
     }
-    fn batch_count(&self) -> usize {
+    pub(crate) fn batch_count(&self) -> usize {
         self.shape[match self.output_mode {
             MatMulOutputMode::Batches => 1,
             MatMulOutputMode::Rows => 2,
@@ -422,9 +548,32 @@ impl Kernel for MatMulKernel {
         let mut src = String::new();
         let w = &mut src;
 
-        generate_input_buffer(0, 0, w)?;
-        generate_input_buffer(1, 1, w)?;
-        generate_output_buffer(2, 0, w)?;
+        // with an epilogue the per-element kernel buffers follow the matrix inputs
+        let a_input_index = self
+            .epilogue
+            .as_ref()
+            .map_or(0, |epilogue| epilogue.kernel.inputs.len());
+        let b_input_index = a_input_index + 1;
+        generate_input_buffer(0, a_input_index, w)?;
+        generate_input_buffer(1, b_input_index, w)?;
+        if let Some(epilogue) = &self.epilogue {
+            assert!(!self.accumulate);
+            let mut binding_index = 2;
+            for input_index in 0..epilogue.kernel.inputs.len() {
+                if input_index != epilogue.input_index {
+                    generate_input_buffer(binding_index, input_index, w)?;
+                    binding_index += 1;
+                }
+            }
+            for output_index in 0..epilogue.kernel.outputs.len() {
+                generate_output_buffer(binding_index, output_index, w)?;
+                binding_index += 1;
+            }
+        } else if self.accumulate {
+            generate_atomic_buffer(2, 0, w)?;
+        } else {
+            generate_output_buffer(2, 0, w)?;
+        }
 
         assert_eq!(self.a.output_shape.len(), 3);
         assert_eq!(self.b.output_shape.len(), 3);
@@ -458,6 +607,11 @@ impl Kernel for MatMulKernel {
             writeln!(w, "; }}")?;
         }
 
+        // f16 inputs are converted on load, the sum is always accumulated in f32
+        let load_value = |index: &str| match self.input_type {
+            StorageType::F32 => index.to_owned(),
+            StorageType::F16 => format!("unpackHalf2x16(floatBitsToUint({})).x", index),
+        };
         writeln!(
             w,
             "\
@@ -467,11 +621,16 @@ impl Kernel for MatMulKernel {
                     int icoord[2];
                     icoord[0] = int(coord.y);
                     icoord[1] = int(coord.x);
-                    tmp = input0[load_index0(batch_index, coord)];
+                    tmp = {};
                 }}
                 return tmp;
             }}",
-            k, m,
+            k,
+            m,
+            load_value(&format!(
+                "input{}[load_index0(batch_index, coord)]",
+                a_input_index
+            )),
         )?;
         writeln!(
             w,
@@ -479,31 +638,55 @@ impl Kernel for MatMulKernel {
             float load_b(uint batch_index, uvec2 coord) {{
                 float tmp = 0.f;
                 if (coord.x < {} && coord.y < {}) {{
-                    tmp = input1[load_index1(batch_index, coord)];
+                    tmp = {};
                 }}
                 return tmp;
             }}",
-            n, k,
+            n,
+            k,
+            load_value(&format!(
+                "input{}[load_index1(batch_index, coord)]",
+                b_input_index
+            )),
         )?;
 
         let (batch_stride, row_stride) = match self.output_mode {
             MatMulOutputMode::Batches => (m * n, n),
             MatMulOutputMode::Rows => (n, batch_count * n),
         };
-        writeln!(
-            w,
-            "\
-            void store_c(uint k_chunk_index, uint batch_index, uvec2 coord, float value) {{
-                if (coord.x < {} && coord.y < {}) {{
-                    output0[k_chunk_index*{} + batch_index*{} + coord.y*{} + coord.x] = value;
-                }}
-            }}",
-            n,
-            m,
-            batch_count * m * n,
-            batch_stride,
-            row_stride
-        )?;
+        if let Some(epilogue) = &self.epilogue {
+            // the epilogue reads the result at the same index it would have been stored
+            writeln!(
+                w,
+                "\
+                void store_c(uint k_chunk_index, uint batch_index, uvec2 coord, float value) {{
+                    if (coord.x < {} && coord.y < {}) {{
+                        uint element_index = batch_index*{} + coord.y*{} + coord.x;",
+                n, m, batch_stride, row_stride,
+            )?;
+            epilogue.kernel.generate_body(
+                "element_index",
+                Some((epilogue.input_index, "value")),
+                w,
+            )?;
+            writeln!(w, "}} }}")?;
+        } else {
+            writeln!(
+                w,
+                "\
+                void store_c(uint k_chunk_index, uint batch_index, uvec2 coord, float value) {{
+                    if (coord.x < {} && coord.y < {}) {{
+                        output0[k_chunk_index*{} + batch_index*{} + coord.y*{} + coord.x] {}= value;
+                    }}
+                }}",
+                n,
+                m,
+                batch_count * m * n,
+                batch_stride,
+                row_stride,
+                if self.accumulate { "+" } else { "" }
+            )?;
+        }
 
         writeln!(w, "const uint M = {};", m)?;
         writeln!(w, "const uint N = {};", n)?;
@@ -541,7 +724,10 @@ impl Kernel for MatMulKernel {
     }
 
     fn buffer_count(&self) -> usize {
-        3
+        match &self.epilogue {
+            Some(epilogue) => epilogue.kernel.buffer_count() + 1,
+            None => 3,
+        }
     }
 
     fn group_count(&self) -> usize {
@@ -552,16 +738,41 @@ impl Kernel for MatMulKernel {
     }
 
     fn label_name(&self) -> String {
-        format!("MatMul (k={}) {}", self.k(), self.shape)
+        format!(
+            "MatMul{}{} (k={}) {}{}",
+            if self.input_type == StorageType::F16 {
+                "F16"
+            } else {
+                ""
+            },
+            if self.accumulate { "Acc" } else { "" },
+            self.k(),
+            self.shape,
+            match &self.epilogue {
+                Some(epilogue) => format!(" + {} ops", epilogue.kernel.ops.len()),
+                None => String::new(),
+            }
+        )
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct PerElementEpilogue {
+    pub(crate) kernel: PerElementKernel,
+    pub(crate) input_index: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct ReduceKernel {
     pub(crate) shape: Shape,
     pub(crate) input: View,
     pub(crate) reduce_op: ReduceOp,
     pub(crate) axis: Axis,
+    pub(crate) pairwise: bool,
+    pub(crate) prologue: Option<PerElementKernel>,
+    pub(crate) epilogue: Option<PerElementEpilogue>,
 }
 
 impl ReduceKernel {
@@ -575,8 +786,40 @@ impl Kernel for ReduceKernel {
         let mut src = String::new();
         let w = &mut src;
 
-        generate_input_buffer(0, 0, w)?;
-        generate_output_buffer(1, 0, w)?;
+        // with an epilogue the per-element kernel buffers follow the reduction input
+        let reduce_input_index = self
+            .epilogue
+            .as_ref()
+            .map_or(0, |epilogue| epilogue.kernel.inputs.len());
+        if let Some(prologue) = &self.prologue {
+            // the producing kernel is evaluated per input element instead of being stored
+            assert!(self.epilogue.is_none());
+            for input_index in 0..prologue.inputs.len() {
+                generate_input_buffer(input_index, input_index, w)?;
+            }
+            generate_output_buffer(prologue.inputs.len(), 0, w)?;
+
+            writeln!(w, "float prologue(uint element_index) {{")?;
+            prologue.generate_ops("element_index", None, w)?;
+            writeln!(w, "return tmp{};", prologue.outputs[0])?;
+            writeln!(w, "}}")?;
+        } else if let Some(epilogue) = &self.epilogue {
+            generate_input_buffer(0, reduce_input_index, w)?;
+            let mut binding_index = 1;
+            for input_index in 0..epilogue.kernel.inputs.len() {
+                if input_index != epilogue.input_index {
+                    generate_input_buffer(binding_index, input_index, w)?;
+                    binding_index += 1;
+                }
+            }
+            for output_index in 0..epilogue.kernel.outputs.len() {
+                generate_output_buffer(binding_index, output_index, w)?;
+                binding_index += 1;
+            }
+        } else {
+            generate_input_buffer(0, 0, w)?;
+            generate_output_buffer(1, 0, w)?;
+        }
 
         writeln!(w, "layout(local_size_x = 64) in;")?;
         writeln!(w, "void main() {{")?;
@@ -604,24 +847,57 @@ impl Kernel for ReduceKernel {
             match self.reduce_op {
                 ReduceOp::Max => "U2F(0xff800000)",
                 ReduceOp::Sum => "0.f",
+                ReduceOp::USum => "U2F(0u)",
             }
         )?;
+        if self.pairwise {
+            // partial sums of 2^i elements, merged like carries in a binary counter
+            writeln!(w, "float partial[32];")?;
+            writeln!(w, "int depth = 0;")?;
+        }
         writeln!(w, "for (int k = 0; k < {}; ++k) {{", k)?;
         writeln!(w, "in_coord[{}] = k;", self.axis.index())?;
-        write!(w, "float tmp = input0[")?;
-        generate_load_index(&self.input, "in_coord", w)?;
-        writeln!(w, "];")?;
-        writeln!(
-            w,
-            "{};",
-            match self.reduce_op {
-                ReduceOp::Max => "result = max(result, tmp)",
-                ReduceOp::Sum => "result += tmp",
-            }
-        )?;
+        if self.prologue.is_some() {
+            write!(w, "float tmp = prologue(uint(")?;
+            generate_load_index(&self.input, "in_coord", w)?;
+            writeln!(w, "));")?;
+        } else {
+            write!(w, "float tmp = input{}[", reduce_input_index)?;
+            generate_load_index(&self.input, "in_coord", w)?;
+            writeln!(w, "];")?;
+        }
+        if self.pairwise {
+            assert_eq!(self.reduce_op, ReduceOp::Sum);
+            writeln!(
+                w,
+                "for (uint j = uint(k) + 1u; (j & 1u) == 0u; j >>= 1) {{ tmp += partial[--depth]; }}"
+            )?;
+            writeln!(w, "partial[depth++] = tmp;")?;
+        } else {
+            writeln!(
+                w,
+                "{};",
+                match self.reduce_op {
+                    ReduceOp::Max => "result = max(result, tmp)",
+                    ReduceOp::Sum => "result += tmp",
+                    ReduceOp::USum => "result = U2F(F2U(result) + F2U(tmp))",
+                }
+            )?;
+        }
         writeln!(w, "}}")?;
+        if self.pairwise {
+            writeln!(w, "while (depth > 0) {{ result += partial[--depth]; }}")?;
+        }
 
-        writeln!(w, "output0[gl_GlobalInvocationID.x] = result;")?;
+        if let Some(epilogue) = &self.epilogue {
+            epilogue.kernel.generate_body(
+                "gl_GlobalInvocationID.x",
+                Some((epilogue.input_index, "result")),
+                w,
+            )?;
+        } else {
+            writeln!(w, "output0[gl_GlobalInvocationID.x] = result;")?;
+        }
 
         writeln!(w, "}}")?;
 
@@ -629,7 +905,11 @@ impl Kernel for ReduceKernel {
     }
 
     fn buffer_count(&self) -> usize {
-        2
+        match (&self.prologue, &self.epilogue) {
+            (Some(prologue), _) => prologue.inputs.len() + 1,
+            (None, Some(epilogue)) => epilogue.kernel.buffer_count(),
+            (None, None) => 2,
+        }
     }
 
     fn group_count(&self) -> usize {
@@ -637,16 +917,39 @@ impl Kernel for ReduceKernel {
     }
 
     fn label_name(&self) -> String {
-        format!("Reduce (k={}) {}", self.k(), self.shape)
+        let name = if self.pairwise {
+            "ReducePairwise"
+        } else {
+            "Reduce"
+        };
+        match (&self.prologue, &self.epilogue) {
+            (Some(prologue), _) => format!(
+                "{} ops + {} (k={}) {}",
+                prologue.ops.len(),
+                name,
+                self.k(),
+                self.shape
+            ),
+            (None, Some(epilogue)) => format!(
+                "{} (k={}) {} + {} ops",
+                name,
+                self.k(),
+                self.shape,
+                epilogue.kernel.ops.len()
+            ),
+            (None, None) => format!("{} (k={}) {}", name, self.k(), self.shape),
+        }
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct UnpadKernel {
     pub(crate) shape: Shape,
     pub(crate) input: View,
     pub(crate) axis: Axis,
     pub(crate) pad: usize,
+    pub(crate) prologue: Option<PerElementKernel>,
 }
 
 impl Kernel for UnpadKernel {
@@ -654,8 +957,21 @@ impl Kernel for UnpadKernel {
         let mut src = String::new();
         let w = &mut src;
 
-        generate_input_buffer(0, 0, w)?;
-        generate_output_buffer(1, 0, w)?;
+        if let Some(prologue) = &self.prologue {
+            // the producing kernel is evaluated per input element instead of being stored
+            for input_index in 0..prologue.inputs.len() {
+                generate_input_buffer(input_index, input_index, w)?;
+            }
+            generate_output_buffer(prologue.inputs.len(), 0, w)?;
+
+            writeln!(w, "float prologue(uint element_index) {{")?;
+            prologue.generate_ops("element_index", None, w)?;
+            writeln!(w, "return tmp{};", prologue.outputs[0])?;
+            writeln!(w, "}}")?;
+        } else {
+            generate_input_buffer(0, 0, w)?;
+            generate_output_buffer(1, 0, w)?;
+        }
 
         writeln!(w, "layout(local_size_x = 64) in;")?;
         writeln!(w, "void main() {{")?;
@@ -684,9 +1000,15 @@ impl Kernel for UnpadKernel {
         writeln!(w, "float sum = 0.f;")?;
         writeln!(w, "for (int k = k_min; k <= k_max; ++k) {{")?;
         writeln!(w, "coord[{}] = k;", self.axis.index())?;
-        write!(w, "sum += input0[")?;
-        generate_load_index(&self.input, "coord", w)?;
-        writeln!(w, "];")?;
+        if self.prologue.is_some() {
+            write!(w, "sum += prologue(uint(")?;
+            generate_load_index(&self.input, "coord", w)?;
+            writeln!(w, "));")?;
+        } else {
+            write!(w, "sum += input0[")?;
+            generate_load_index(&self.input, "coord", w)?;
+            writeln!(w, "];")?;
+        }
         writeln!(w, "}}")?;
 
         writeln!(w, "output0[gl_GlobalInvocationID.x] = sum;")?;
@@ -697,7 +1019,10 @@ impl Kernel for UnpadKernel {
     }
 
     fn buffer_count(&self) -> usize {
-        2
+        match &self.prologue {
+            Some(prologue) => prologue.inputs.len() + 1,
+            None => 2,
+        }
     }
 
     fn group_count(&self) -> usize {
@@ -705,11 +1030,15 @@ impl Kernel for UnpadKernel {
     }
 
     fn label_name(&self) -> String {
-        format!("Unpad {}", self.shape)
+        match &self.prologue {
+            Some(prologue) => format!("Unpad {} + {} ops", self.shape, prologue.ops.len()),
+            None => format!("Unpad {}", self.shape),
+        }
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct WindowsToImageKernel {
     pub(crate) shape: Shape,
     pub(crate) input: View,
@@ -810,15 +1139,68 @@ impl Kernel for WindowsToImageKernel {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct ScatterAddKernel {
     pub(crate) shape: Shape,
     pub(crate) values: View,
     pub(crate) axis: Axis,
     pub(crate) indices: View,
+    pub(crate) deterministic: bool,
+}
+
+impl ScatterAddKernel {
+    fn generate_deterministic_source(&self) -> Result<String, fmt::Error> {
+        let mut src = String::new();
+        let w = &mut src;
+
+        generate_input_buffer(0, 0, w)?;
+        generate_input_buffer(1, 1, w)?;
+        generate_atomic_buffer(2, 0, w)?;
+
+        writeln!(w, "layout(local_size_x = 64) in;")?;
+        writeln!(w, "void main() {{")?;
+
+        writeln!(
+            w,
+            "if (gl_GlobalInvocationID.x >= {}) {{ return; }}",
+            self.shape.element_count()
+        )?;
+
+        // one invocation per output element, sum matching values in index order
+        generate_coord("tmp_coord", self.shape, w)?;
+        writeln!(w, "int out_index = tmp_coord[{}];", self.axis.index())?;
+        writeln!(w, "float sum = 0.f;")?;
+        writeln!(w, "int in_coord1[1];")?;
+        writeln!(
+            w,
+            "for (int i = 0; i < {}; ++i) {{",
+            self.values.output_shape[self.axis]
+        )?;
+        writeln!(w, "in_coord1[0] = i;")?;
+        writeln!(w, "int scatter_index = F2I(input1[")?;
+        generate_load_index(&self.indices, "in_coord1", w)?;
+        writeln!(w, "]);")?;
+        writeln!(w, "if (scatter_index == out_index) {{")?;
+        writeln!(w, "tmp_coord[{}] = i;", self.axis.index())?;
+        writeln!(w, "sum += input0[")?;
+        generate_load_index(&self.values, "tmp_coord", w)?;
+        writeln!(w, "];")?;
+        writeln!(w, "}}")?;
+        writeln!(w, "}}")?;
+        writeln!(w, "output0[gl_GlobalInvocationID.x] += sum;")?;
+
+        writeln!(w, "}}")?;
+
+        Ok(src)
+    }
 }
 
 impl Kernel for ScatterAddKernel {
     fn generate_source(&self) -> Result<String, fmt::Error> {
+        if self.deterministic {
+            return self.generate_deterministic_source();
+        }
+
         let mut src = String::new();
         let w = &mut src;
 
@@ -845,6 +1227,11 @@ impl Kernel for ScatterAddKernel {
         writeln!(w, "int scatter_index = F2I(input1[")?;
         generate_load_index(&self.indices, "in_coord1", w)?;
         writeln!(w, "]);")?;
+        writeln!(
+            w,
+            "if (scatter_index < 0 || scatter_index >= {}) {{ return; }}",
+            self.shape[self.axis]
+        )?;
         writeln!(w, "tmp_coord[{}] = scatter_index;", self.axis.index())?;
 
         writeln!(w, "atomicAdd(output0[")?;
@@ -861,20 +1248,29 @@ impl Kernel for ScatterAddKernel {
     }
 
     fn group_count(&self) -> usize {
-        self.values.output_shape.element_count().div_round_up(64)
+        if self.deterministic {
+            self.shape.element_count().div_round_up(64)
+        } else {
+            self.values.output_shape.element_count().div_round_up(64)
+        }
     }
 
     fn label_name(&self) -> String {
-        format!("ScatterAdd {}", self.values.output_shape)
+        if self.deterministic {
+            format!("ScatterAdd (deterministic) {}", self.values.output_shape)
+        } else {
+            format!("ScatterAdd {}", self.values.output_shape)
+        }
     }
 
     fn requires_atomic_float(&self) -> bool {
-        true
+        !self.deterministic
     }
 }
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum GenericKernel {
     Fill(FillKernel),
     PerElement(PerElementKernel),
@@ -897,6 +1293,17 @@ impl GenericKernel {
             GenericKernel::ScatterAdd(kernel) => kernel,
         }
     }
+
+    pub(crate) fn dump_source(&self) -> String {
+        match self {
+            GenericKernel::PerElement(kernel) => {
+                let mut w = String::new();
+                kernel.dump_ops(&mut w).unwrap();
+                w
+            }
+            _ => self.generate_source().unwrap(),
+        }
+    }
 }
 
 impl Kernel for GenericKernel {
@@ -933,6 +1340,7 @@ pub(crate) struct KernelModule {
 struct KernelCacheWorker {
     context: SharedContext,
     compiler: Compiler,
+    compile_count: usize,
 }
 
 impl KernelCacheWorker {
@@ -940,10 +1348,12 @@ impl KernelCacheWorker {
         Self {
             context: SharedContext::clone(context),
             compiler: Compiler::new().unwrap(),
+            compile_count: 0,
         }
     }
 
     fn create_module(&mut self, kernel: &GenericKernel) -> KernelModule {
+        self.compile_count += 1;
         let device = &self.context.device;
 
         let mut source = kernel.generate_source().unwrap();
@@ -1053,6 +1463,12 @@ impl KernelCache {
             move || worker.create_module(kernel)
         })
     }
+
+    // counts the kernels actually compiled, which only matches the number of modules
+    // while structurally identical kernels share a cache entry
+    pub(crate) fn compile_count(&self) -> usize {
+        self.worker.compile_count
+    }
 }
 
 impl Drop for KernelCache {
@@ -1,23 +1,108 @@
 use crate::common::*;
+use std::convert::TryInto;
 
-#[allow(clippy::many_single_char_names)]
 pub fn softmax_cross_entropy_loss<'s>(z: DualArray<'s>, y: impl IntoArray<'s>) -> DualArray<'s> {
+    softmax_cross_entropy_loss_with_ignore_index(z, y, None)
+}
+
+// Samples labelled with ignore_index have zero loss and zero gradient.  They still count
+// towards the batch size when the loss is averaged by set_loss.
+#[allow(clippy::many_single_char_names)]
+pub fn softmax_cross_entropy_loss_with_ignore_index<'s>(
+    z: DualArray<'s>,
+    y: impl IntoArray<'s>,
+    ignore_index: Option<u32>,
+) -> DualArray<'s> {
     let (z, dz) = z.next_colour().into_inner();
     let y = y.into_array(z.scope());
 
-    // softmax
-    let t = (z - z.reduce_max(-1, true)).exp();
-    let p = t / t.reduce_sum(-1, true);
+    let p = z.softmax(-1);
+
+    let mask = |x: Array<'s>| match ignore_index {
+        Some(index) => y.select_eq(index as f32, 0.0, x),
+        None => x,
+    };
 
     // cross entropy loss
-    let (loss, dloss) = y
-        .select_eq(p.coord(-1), -p.log(), 0.0)
-        .reduce_sum(-1, true)
-        .with_empty_grad(); // TODO: pick element of p using value of y
+    let (loss, dloss) = mask(
+        y.select_eq(p.coord(-1), -p.log(), 0.0).reduce_sum(-1, true), // TODO: pick element of p using value of y
+    )
+    .with_empty_grad();
 
     // backprop (softmax with cross entropy directly)
     let n = p.shape()[SignedIndex(-1)];
-    dz.accumulate((p - y.one_hot(n)) * dloss);
+    dz.accumulate(mask((p - y.one_hot(n)) * dloss));
+
+    (loss, dloss).into()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegativeSampler {
+    Uniform,
+    // Zipfian, for vocabularies sorted by decreasing frequency
+    LogUniform,
+}
+
+impl NegativeSampler {
+    fn sample<'s>(&self, u: Array<'s>, n: usize) -> UArray<'s> {
+        let max_index = (n - 1) as f32;
+        match self {
+            Self::Uniform => (u * (n as f32)).clamp(0.0, max_index).into_u32(),
+            Self::LogUniform => ((u * ((n + 1) as f32).ln()).exp() - 1.0)
+                .clamp(0.0, max_index)
+                .into_u32(),
+        }
+    }
+
+    fn log_probability<'s>(&self, c: Array<'s>, n: usize) -> Array<'s> {
+        match self {
+            Self::Uniform => (-(n as f32).ln()).into_array(c.scope()),
+            Self::LogUniform => (((c + 2.0) / (c + 1.0)).log() / ((n + 1) as f32).ln()).log(),
+        }
+    }
+}
+
+// softmax cross entropy over the target class and negatives sampled once for the batch,
+// only the logits of these classes receive a gradient
+pub fn sampled_softmax_loss<'s>(
+    z: DualArray<'s>,
+    y: impl IntoArray<'s>,
+    num_sampled: usize,
+    sampler: NegativeSampler,
+) -> DualArray<'s> {
+    let (z, dz) = z.next_colour().into_inner();
+    let y = y.into_array(z.scope());
+    let scope = z.scope();
+    let [m, n]: [usize; 2] = z.shape().try_into().unwrap();
+    assert_eq!(y.shape(), Shape::from([m, 1]));
+
+    let sampled = sampler.sample(scope.rand([num_sampled]).value(), n);
+    let sampled_f32 = sampled.into_f32().reshape([1, num_sampled]);
+
+    // logits corrected by the log probability of being sampled, sampled classes that
+    // hit the target are excluded
+    let z_target =
+        y.select_eq(z.coord(-1), z, 0.0).reduce_sum(-1, true) - sampler.log_probability(y, n);
+    let z_sampled = z.gather(-1, sampled) - sampler.log_probability(sampled_f32, n);
+    let z_sampled = y.select_eq(sampled_f32, -1.0E30, z_sampled);
+    let logits = z_target.concat(z_sampled, -1);
+
+    // softmax cross entropy against the first column
+    let shifted = logits - logits.reduce_max(-1, true);
+    let t = shifted.exp();
+    let sum = t.reduce_sum(-1, true);
+    let p = t / sum;
+    let (loss, dloss) = (sum.log() - shifted.lock_axis(-1, 0, true)).with_empty_grad();
+
+    // backprop to the target and sampled logits only
+    let dlogits = (p - p.coord(-1).select_eq(0.0, 1.0, 0.0)) * dloss;
+    let dz_target = y.one_hot(n) * dlogits.lock_axis(-1, 0, true);
+    let dz_sampled = scope.literal(0.0).value().broadcast([m, n]).scatter_add(
+        dlogits.limit_axis(-1, 1..),
+        -1,
+        sampled,
+    );
+    dz.accumulate(dz_target + dz_sampled);
 
     (loss, dloss).into()
 }
@@ -32,3 +117,110 @@ pub fn softmax_cross_entropy_accuracy<'s>(z: DualArray<'s>, y: impl IntoArray<'s
     // set to 1 when correct, 0 when incorrect
     pred.select_eq(y, 1.0, 0.0)
 }
+
+pub fn huber_loss<'s>(x: DualArray<'s>, target: impl IntoArray<'s>, delta: f32) -> DualArray<'s> {
+    let (x, dx) = x.next_colour().into_inner();
+    let target = target.into_array(x.scope());
+
+    // quadratic within delta of the target, linear outside
+    let d = x - target;
+    let abs_d = d.select_gt(0.0, d, -d);
+    let (loss, dloss) = abs_d
+        .select_gt(delta, (abs_d - 0.5 * delta) * delta, 0.5 * d * d)
+        .reduce_sum(-1, true)
+        .with_empty_grad();
+
+    dx.accumulate(d.clamp(-delta, delta) * dloss);
+
+    (loss, dloss).into()
+}
+
+// smooth L1 with parameter beta is the Huber loss with delta = beta, scaled by 1/beta
+pub fn smooth_l1_loss<'s>(
+    x: DualArray<'s>,
+    target: impl IntoArray<'s>,
+    beta: f32,
+) -> DualArray<'s> {
+    let (x, dx) = x.next_colour().into_inner();
+    let target = target.into_array(x.scope());
+
+    let d = x - target;
+    let abs_d = d.select_gt(0.0, d, -d);
+    let (loss, dloss) = abs_d
+        .select_gt(beta, abs_d - 0.5 * beta, (0.5 / beta) * d * d)
+        .reduce_sum(-1, true)
+        .with_empty_grad();
+
+    dx.accumulate((d * (1.0 / beta)).clamp(-1.0, 1.0) * dloss);
+
+    (loss, dloss).into()
+}
+
+// mean over all elements, returned with shape [1] so that set_loss (which divides by the
+// size of the leading axis) applies no further scaling: the gradient is 2 * (x - target) / n
+pub fn mse_loss<'s>(x: DualArray<'s>, target: impl IntoArray<'s>) -> DualArray<'s> {
+    let (x, dx) = x.next_colour().into_inner();
+    let target = target.into_array(x.scope());
+
+    let d = x - target;
+    let n = d.shape().element_count();
+    let (loss, dloss) =
+        ((d * d).reshape([n]).reduce_sum(0, true) * (1.0 / (n as f32))).with_empty_grad();
+
+    dx.accumulate(d * (2.0 / (n as f32)) * dloss);
+
+    (loss, dloss).into()
+}
+
+// elementwise, stable for large |z|: max(z, 0) - y*z + log(1 + exp(-|z|))
+fn sigmoid_cross_entropy<'s>(z: Array<'s>, y: Array<'s>) -> Array<'s> {
+    z.select_gt(0.0, z, 0.0) - y * z + (1.0 + (-z.abs()).exp()).log()
+}
+
+pub fn sigmoid_cross_entropy_loss<'s>(z: DualArray<'s>, y: impl IntoArray<'s>) -> DualArray<'s> {
+    let (z, dz) = z.next_colour().into_inner();
+    let y = y.into_array(z.scope());
+
+    let (loss, dloss) = sigmoid_cross_entropy(z, y)
+        .reduce_sum(-1, true)
+        .with_empty_grad();
+
+    dz.accumulate((z.sigmoid() - y) * dloss);
+
+    (loss, dloss).into()
+}
+
+// sigmoid cross entropy scaled by (1 - p_t)^gamma, and optionally by alpha for positive
+// targets and 1 - alpha for negative targets, where p_t is the probability of the target
+pub fn focal_loss<'s>(
+    z: DualArray<'s>,
+    y: impl IntoArray<'s>,
+    gamma: f32,
+    alpha: Option<f32>,
+) -> DualArray<'s> {
+    let (z, dz) = z.next_colour().into_inner();
+    let y = y.into_array(z.scope());
+
+    let p = z.sigmoid();
+    let bce = sigmoid_cross_entropy(z, y);
+
+    // q = 1 - p_t, computed from sigmoid(-z) to keep precision when p is close to 1.  q is
+    // zero for a saturated logit, where pow is undefined for gamma <= 0 and q^(gamma - 1)
+    // is infinite for gamma < 1 even though dq is also zero, so keep q above zero.
+    let q = (y * (-z).sigmoid() + (1.0 - y) * p).clamp(1.0E-12, 1.0);
+    let m = q.pow(gamma);
+    let dq = (1.0 - 2.0 * y) * p * (1.0 - p);
+    let dm = gamma * q.pow(gamma - 1.0) * dq;
+    let mut loss = m * bce;
+    let mut grad = dm * bce + m * (p - y);
+    if let Some(alpha) = alpha {
+        let alpha_t = y * alpha + (1.0 - y) * (1.0 - alpha);
+        loss = loss * alpha_t;
+        grad = grad * alpha_t;
+    }
+    let (loss, dloss) = loss.reduce_sum(-1, true).with_empty_grad();
+
+    dz.accumulate(grad * dloss);
+
+    (loss, dloss).into()
+}
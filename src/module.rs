@@ -89,6 +89,54 @@ impl Module for Dense {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+pub enum Activation {
+    None,
+    LeakyRelu(f32),
+    Sigmoid,
+    Tanh,
+    Sin,
+}
+
+impl Activation {
+    fn apply<'s>(self, x: DualArray<'s>) -> DualArray<'s> {
+        match self {
+            Self::None => x,
+            Self::LeakyRelu(leakiness) => x.leaky_relu(leakiness),
+            Self::Sigmoid => x.sigmoid(),
+            Self::Tanh => x.tanh(),
+            Self::Sin => x.sin(),
+        }
+    }
+}
+
+pub struct Mlp {
+    layers: Vec<Dense>,
+    activation: Activation,
+}
+
+impl Mlp {
+    pub fn new(env: &mut Environment, sizes: &[usize], activation: Activation) -> Self {
+        assert!(sizes.len() >= 2);
+        let layers = sizes
+            .windows(2)
+            .map(|pair| Dense::builder(pair[0], pair[1]).build(env))
+            .collect();
+        Self { layers, activation }
+    }
+}
+
+impl Module for Mlp {
+    fn eval<'s>(&self, input: DualArray<'s>, ctx: &EvalContext) -> DualArray<'s> {
+        // activation between layers, the last layer output is left linear
+        let (last, hidden) = self.layers.split_last().unwrap();
+        hidden
+            .iter()
+            .fold(input, |x, layer| self.activation.apply(x.apply(layer, ctx)))
+            .apply(last, ctx)
+    }
+}
+
 pub struct Conv2DBuilder {
     input_channels: usize,
     output_channels: usize,
@@ -278,6 +326,50 @@ impl Module for Dropout {
     }
 }
 
+pub struct BatchNorm {
+    gamma: Parameter,
+    beta: Parameter,
+    running_mean: Parameter,
+    running_var: Parameter,
+    momentum: f32,
+    eps: f32,
+}
+
+impl BatchNorm {
+    pub fn new(env: &mut Environment, channels: usize) -> Self {
+        let gamma = env.trainable_parameter([channels], "gamma", Initializer::One);
+        let beta = env.trainable_parameter([channels], "beta", Initializer::Zero);
+
+        let running_mean = env.static_parameter([channels], "running_mean");
+        env.writer(&running_mean).zero_fill();
+        let running_var =
+            env.static_parameter_with_data([channels], "running_var", &vec![1.0; channels]);
+
+        Self {
+            gamma,
+            beta,
+            running_mean,
+            running_var,
+            momentum: 0.1,
+            eps: 1.0E-5,
+        }
+    }
+}
+
+impl Module for BatchNorm {
+    fn eval<'s>(&self, input: DualArray<'s>, ctx: &EvalContext) -> DualArray<'s> {
+        input.next_colour().batch_norm(
+            &self.gamma,
+            &self.beta,
+            &self.running_mean,
+            &self.running_var,
+            ctx.is_training,
+            self.momentum,
+            self.eps,
+        )
+    }
+}
+
 struct LSTMWeight {
     input: Parameter,
     hidden: Parameter,
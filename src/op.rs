@@ -20,30 +20,51 @@ pub(crate) type OpNodeId = NodeIndex<usize>;
 pub(crate) type OpEdgeId = EdgeIndex<usize>;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum Literal {
     F32(NotNan<f32>),
     U32(u32),
 }
 
+impl Literal {
+    pub(crate) fn to_f32_bits(self) -> f32 {
+        match self {
+            Self::F32(value) => value.into_inner(),
+            Self::U32(value) => f32::from_bits(value),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum ReduceOp {
     Max,
     Sum,
+    USum,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum BuiltInOp {
     Coord,
     Rand { uid: usize },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum CompareMode {
     Eq,
+    Ne,
     Gt,
+    Ge,
+    Lt,
+    Le,
+    UEq,
+    UGt,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum BinaryOp {
     Add,
     Sub,
@@ -52,48 +73,222 @@ pub(crate) enum BinaryOp {
     Pow,
     UAdd,
     UMul,
+    UDiv,
     URem,
     UBitXor,
+    UBitAnd,
+    UBitOr,
+    UShl,
+    UShr,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum UnaryOp {
     Mov,
     Neg,
     Sqrt,
+    Rsqrt,
     Exp,
     Log,
     Sin,
     Cos,
     FloatToUint,
     UintToFloat,
+    FloatToHalf,
+    HalfToFloat,
+}
+
+// rounds to nearest even like packHalf2x16, the result is in the low 16 bits
+pub(crate) fn f32_to_f16_bits(value: f32) -> u32 {
+    let bits = value.to_bits();
+    let sign = (bits >> 16) & 0x8000;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mant = bits & 0x7f_ffff;
+    if exp == 0xff {
+        return sign | 0x7c00 | if mant != 0 { 0x200 } else { 0 };
+    }
+    let round = |half: u32, rem: u32, halfway: u32| {
+        half + ((rem > halfway || (rem == halfway && (half & 1) != 0)) as u32)
+    };
+    let exp = exp - 127 + 15;
+    if exp >= 0x1f {
+        sign | 0x7c00
+    } else if exp <= 0 {
+        if exp < -10 {
+            return sign;
+        }
+        let mant = mant | 0x80_0000;
+        let shift = (14 - exp) as u32;
+        sign | round(mant >> shift, mant & ((1 << shift) - 1), 1 << (shift - 1))
+    } else {
+        // a carry out of the mantissa correctly rounds up to the next exponent
+        sign | round(((exp as u32) << 10) | (mant >> 13), mant & 0x1fff, 0x1000)
+    }
+}
+
+pub(crate) fn f16_bits_to_f32(half: u32) -> f32 {
+    let sign = (half & 0x8000) << 16;
+    let exp = (half >> 10) & 0x1f;
+    let mant = half & 0x3ff;
+    match exp {
+        0 => {
+            let value = (mant as f32) / 16_777_216.0;
+            if sign != 0 {
+                -value
+            } else {
+                value
+            }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mant << 13)),
+        _ => f32::from_bits(sign | ((exp + 112) << 23) | (mant << 13)),
+    }
+}
+
+impl CompareMode {
+    pub(crate) fn eval(&self, a: f32, b: f32) -> bool {
+        match self {
+            Self::Eq => a == b,
+            Self::Ne => a != b,
+            Self::Gt => a > b,
+            Self::Ge => a >= b,
+            Self::Lt => a < b,
+            Self::Le => a <= b,
+            Self::UEq => a.to_bits() == b.to_bits(),
+            Self::UGt => a.to_bits() > b.to_bits(),
+        }
+    }
+
+    pub(crate) fn glsl_operator(&self) -> &'static str {
+        match self {
+            Self::Eq | Self::UEq => "==",
+            Self::Ne => "!=",
+            Self::Gt | Self::UGt => ">",
+            Self::Ge => ">=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+        }
+    }
+
+    pub(crate) fn is_uint(&self) -> bool {
+        matches!(self, Self::UEq | Self::UGt)
+    }
+}
+
+impl BinaryOp {
+    // values are u32 bit patterns for the integer ops, as in the kernels
+    pub(crate) fn eval(&self, a: f32, b: f32) -> f32 {
+        let (ua, ub) = (a.to_bits(), b.to_bits());
+        match self {
+            Self::Add => a + b,
+            Self::Sub => a - b,
+            Self::Mul => a * b,
+            Self::Div => a / b,
+            Self::Pow => a.powf(b),
+            Self::UAdd => f32::from_bits(ua.wrapping_add(ub)),
+            Self::UMul => f32::from_bits(ua.wrapping_mul(ub)),
+            Self::UDiv => f32::from_bits(ua.checked_div(ub).unwrap_or(u32::MAX)),
+            Self::URem => f32::from_bits(ua % ub),
+            Self::UBitXor => f32::from_bits(ua ^ ub),
+            Self::UBitAnd => f32::from_bits(ua & ub),
+            Self::UBitOr => f32::from_bits(ua | ub),
+            Self::UShl => f32::from_bits(ua << (ub & 31)),
+            Self::UShr => f32::from_bits(ua >> (ub & 31)),
+        }
+    }
+
+    pub(crate) fn is_uint(&self) -> bool {
+        matches!(
+            self,
+            Self::UAdd
+                | Self::UMul
+                | Self::UDiv
+                | Self::URem
+                | Self::UBitXor
+                | Self::UBitAnd
+                | Self::UBitOr
+                | Self::UShl
+                | Self::UShr
+        )
+    }
+}
+
+impl UnaryOp {
+    pub(crate) fn eval(&self, a: f32) -> f32 {
+        match self {
+            Self::Mov => a,
+            Self::Neg => -a,
+            Self::Sqrt => a.sqrt(),
+            Self::Rsqrt => 1.0 / a.sqrt(),
+            Self::Exp => a.exp(),
+            Self::Log => a.ln(),
+            Self::Sin => a.sin(),
+            Self::Cos => a.cos(),
+            Self::UintToFloat => a.to_bits() as f32,
+            Self::FloatToUint => f32::from_bits(a as u32),
+            Self::FloatToHalf => f32::from_bits(f32_to_f16_bits(a)),
+            Self::HalfToFloat => f16_bits_to_f32(a.to_bits() & 0xffff),
+        }
+    }
 }
 
 pub(crate) const MAX_OP_ARGS: usize = 4;
 
 pub(crate) const MATMUL_MAX_K_SIZE: usize = 1024;
 
+// sums over longer axes add in a tree, so rounding error grows with log(k) instead of k
+pub(crate) const REDUCE_PAIRWISE_MIN_K_SIZE: usize = 1024;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum MatMulOutputMode {
     Batches,
     Rows,
 }
 
+// f16 values are stored one per 32-bit word, as produced by FloatToHalf
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum StorageType {
+    F32,
+    F16,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum Op {
-    Input { parameter_id: ParameterId },
-    Output { parameter_id: ParameterId },
+    Input {
+        parameter_id: ParameterId,
+    },
+    Output {
+        parameter_id: ParameterId,
+    },
     Literal(Literal),
     BuiltIn(BuiltInOp),
     Unary(UnaryOp),
     Binary(BinaryOp),
     CompareAndSelect(CompareMode),
-    MatMul { output_mode: MatMulOutputMode },
-    Reduce { reduce_op: ReduceOp, axis: Axis }, // TODO: 2D version?
-    Unpad { axis: Axis, pad: usize },           // TODO: 2D version?
-    WindowsToImage { stride: (usize, usize) },
-    Gather { axis: Axis },
-    ScatterAdd { axis: Axis },
+    MatMul {
+        output_mode: MatMulOutputMode,
+        input_type: StorageType,
+    },
+    Reduce {
+        reduce_op: ReduceOp,
+        axis: Axis,
+    }, // TODO: 2D version?
+    Unpad {
+        axis: Axis,
+        pad: usize,
+    }, // TODO: 2D version?
+    WindowsToImage {
+        stride: (usize, usize),
+    },
+    Gather {
+        axis: Axis,
+    },
+    ScatterAdd {
+        axis: Axis,
+    },
 }
 
 impl Op {
@@ -160,14 +355,17 @@ impl fmt::Display for Op {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct OpNode {
     pub(crate) colour: usize,
     pub(crate) shape: Shape,
     pub(crate) op: Op,
     pub(crate) cluster_id: Option<ClusterId>,
+    pub(crate) no_fuse: bool,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct OpEdge {
     pub(crate) arg: usize,
     pub(crate) view: View,
@@ -203,6 +401,7 @@ impl OpGraphExt for OpGraph {
             shape,
             op,
             cluster_id: None,
+            no_fuse: false,
         });
         for (index, input_id) in inputs.iter().copied().enumerate() {
             self.add_edge(
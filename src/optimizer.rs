@@ -1,17 +1,70 @@
 use crate::common::*;
 
+// frozen parameters still pass gradients to earlier layers, but are never updated
+fn unfrozen(parameters: &[Parameter]) -> impl Iterator<Item = &Parameter> {
+    parameters.iter().filter(|param| !param.is_frozen())
+}
+
 pub fn add_weight_decay_to_grad(scope: &Scope, parameters: &[Parameter], weight_decay: f32) {
     if weight_decay == 0.0 {
         return;
     }
 
     scope.next_colour();
-    for param in parameters.iter() {
+    for param in unfrozen(parameters) {
         let (w, g) = scope.parameter(param).into_inner();
         g.accumulate(w * weight_decay);
     }
 }
 
+// Sums gradients over several passes before an optimizer step.  Each pass is scaled by
+// 1/mini_batch_size by set_loss, so the step uses the mean over the passes: N passes of
+// batch size B then give the same gradient as a single pass with batch size N*B.
+pub struct GradientAccumulator {
+    accumulators: Vec<(Parameter, Parameter)>,
+}
+
+impl GradientAccumulator {
+    pub fn new(env: &mut Environment, parameters: &[Parameter]) -> Self {
+        let accumulators = parameters
+            .iter()
+            .map(|param| {
+                let acc_param = env.static_parameter(param.shape(), "grad_acc");
+                (param.clone(), acc_param)
+            })
+            .collect();
+        let tmp = Self { accumulators };
+        tmp.zero_grads(env);
+        tmp
+    }
+
+    // call from the graph that computes the loss, after the loss has been set
+    pub fn accumulate_grads(&self, scope: &Scope) {
+        scope.next_colour();
+        for (param, acc_param) in self.accumulators.iter() {
+            let g = scope.parameter(param).loss_grad();
+            scope.update_parameter_value(acc_param, |acc| acc + g);
+        }
+    }
+
+    // call from the step graph before building the optimizer
+    pub fn apply_to_grads(&self, scope: &Scope, pass_count: usize) {
+        assert!(pass_count > 0);
+        scope.next_colour();
+        let scale = 1.0 / (pass_count as f32);
+        for (param, acc_param) in self.accumulators.iter() {
+            let (_, g) = scope.parameter(param).into_inner();
+            g.accumulate(scope.parameter_value(acc_param) * scale);
+        }
+    }
+
+    pub fn zero_grads(&self, env: &mut Environment) {
+        for (_, acc_param) in self.accumulators.iter() {
+            env.writer(acc_param).zero_fill()
+        }
+    }
+}
+
 pub trait Optimizer {
     fn reset_state(&self, env: &mut Environment);
 }
@@ -32,7 +85,7 @@ impl StochasticGradientDescent {
         let mut state = Vec::new();
 
         let learning_rate = learning_rate.into_array(scope);
-        for param in parameters.iter() {
+        for param in unfrozen(parameters) {
             let g = scope.parameter(param).loss_grad();
             if momentum == 0.0 {
                 scope.update_parameter_value(param, |theta| theta - learning_rate * g);
@@ -73,30 +126,146 @@ impl Adam {
         beta2: f32,
         epsilon: f32,
     ) -> Self {
-        scope.next_colour();
-        let mut state = Vec::new();
+        let state = adam_update(
+            env,
+            scope,
+            parameters,
+            learning_rate.into_array(scope),
+            beta1,
+            beta2,
+            epsilon,
+            0.0,
+        );
+
+        let tmp = Self { state };
+        tmp.reset_state(env);
+        tmp
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn adam_update<'s>(
+    env: &mut Environment,
+    scope: &'s Scope,
+    parameters: &[Parameter],
+    alpha: Array<'s>,
+    beta1: f32,
+    beta2: f32,
+    epsilon: f32,
+    weight_decay: f32,
+) -> Vec<Parameter> {
+    scope.next_colour();
+    let mut state = Vec::new();
+
+    let t_param = env.static_parameter([1], "t");
+    let t = scope.update_parameter_value(&t_param, |t| t + 1.0);
+    state.push(t_param);
+
+    for param in unfrozen(parameters) {
+        let shape = param.shape();
+        let m_param = env.static_parameter(shape, "m");
+        let v_param = env.static_parameter(shape, "v");
+
+        let g = scope.parameter(param).loss_grad();
+        let m = scope.update_parameter_value(&m_param, |m| m * beta1 + g * (1.0 - beta1));
+        let v = scope.update_parameter_value(&v_param, |v| v * beta2 + g * g * (1.0 - beta2));
+        state.push(m_param);
+        state.push(v_param);
+
+        scope.update_parameter_value(param, |theta| {
+            // weight decay is applied directly to the parameter, not via the moments
+            let theta = if weight_decay == 0.0 {
+                theta
+            } else {
+                theta - alpha * weight_decay * theta
+            };
+            theta
+                - alpha * m
+                    / (1.0 - (beta1.ln() * t).exp())
+                    / (v.sqrt() / (1.0 - (beta2.ln() * t).exp()).sqrt() + epsilon)
+        });
+    }
+
+    state
+}
+
+impl Optimizer for Adam {
+    fn reset_state(&self, env: &mut Environment) {
+        for param in self.state.iter() {
+            env.writer(param).zero_fill()
+        }
+    }
+}
+
+pub struct AdamW {
+    state: Vec<Parameter>,
+}
+
+impl AdamW {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<'s>(
+        env: &mut Environment,
+        scope: &'s Scope,
+        parameters: &[Parameter],
+        learning_rate: impl IntoArray<'s>,
+        beta1: f32,
+        beta2: f32,
+        epsilon: f32,
+        weight_decay: f32,
+    ) -> Self {
+        let state = adam_update(
+            env,
+            scope,
+            parameters,
+            learning_rate.into_array(scope),
+            beta1,
+            beta2,
+            epsilon,
+            weight_decay,
+        );
+
+        let tmp = Self { state };
+        tmp.reset_state(env);
+        tmp
+    }
+}
+
+impl Optimizer for AdamW {
+    fn reset_state(&self, env: &mut Environment) {
+        for param in self.state.iter() {
+            env.writer(param).zero_fill()
+        }
+    }
+}
 
-        let t_param = env.static_parameter([1], "t");
-        let t = scope.update_parameter_value(&t_param, |t| t + 1.0);
-        state.push(t_param);
+pub struct RmsProp {
+    state: Vec<Parameter>,
+}
 
-        let alpha = learning_rate.into_array(scope);
+impl RmsProp {
+    pub fn new<'s>(
+        env: &mut Environment,
+        scope: &'s Scope,
+        parameters: &[Parameter],
+        learning_rate: impl IntoArray<'s>,
+        alpha: f32,
+        epsilon: f32,
+    ) -> Self {
+        scope.next_colour();
+        let mut state = Vec::new();
 
-        for param in parameters.iter() {
+        let learning_rate = learning_rate.into_array(scope);
+        for param in unfrozen(parameters) {
             let shape = param.shape();
-            let m_param = env.static_parameter(shape, "m");
             let v_param = env.static_parameter(shape, "v");
 
             let g = scope.parameter(param).loss_grad();
-            let m = scope.update_parameter_value(&m_param, |m| m * beta1 + g * (1.0 - beta1));
-            let v = scope.update_parameter_value(&v_param, |v| v * beta2 + g * g * (1.0 - beta2));
-            state.push(m_param);
+            let v = scope.update_parameter_value(&v_param, |v| v * alpha + g * g * (1.0 - alpha));
             state.push(v_param);
 
-            scope.update_parameter_value(param, |theta| 
-                theta - 
-                    alpha * 
-                    m / (1.0 - (beta1.ln() * t).exp()) / (v.sqrt() / (1.0 - (beta2.ln() * t).exp()).sqrt() + epsilon));
+            scope.update_parameter_value(param, |theta| {
+                theta - learning_rate * g / (v.sqrt() + epsilon)
+            });
         }
 
         let tmp = Self { state };
@@ -105,10 +274,107 @@ impl Adam {
     }
 }
 
-impl Optimizer for Adam {
+impl Optimizer for RmsProp {
+    fn reset_state(&self, env: &mut Environment) {
+        for param in self.state.iter() {
+            env.writer(param).zero_fill()
+        }
+    }
+}
+
+pub struct Adagrad {
+    state: Vec<Parameter>,
+}
+
+impl Adagrad {
+    pub fn new<'s>(
+        env: &mut Environment,
+        scope: &'s Scope,
+        parameters: &[Parameter],
+        learning_rate: impl IntoArray<'s>,
+        epsilon: f32,
+    ) -> Self {
+        scope.next_colour();
+        let mut state = Vec::new();
+
+        let learning_rate = learning_rate.into_array(scope);
+        for param in unfrozen(parameters) {
+            let shape = param.shape();
+            let s_param = env.static_parameter(shape, "s");
+
+            let g = scope.parameter(param).loss_grad();
+            let s = scope.update_parameter_value(&s_param, |s| s + g * g);
+            state.push(s_param);
+
+            scope.update_parameter_value(param, |theta| {
+                theta - learning_rate * g / (s.sqrt() + epsilon)
+            });
+        }
+
+        let tmp = Self { state };
+        tmp.reset_state(env);
+        tmp
+    }
+}
+
+impl Optimizer for Adagrad {
     fn reset_state(&self, env: &mut Environment) {
         for param in self.state.iter() {
             env.writer(param).zero_fill()
         }
     }
 }
+
+#[derive(Clone)]
+pub struct ParameterGroup {
+    pub name: String,
+    pub parameters: Vec<Parameter>,
+    pub learning_rate: f32,
+    pub weight_decay: f32,
+}
+
+impl ParameterGroup {
+    pub fn new(name: impl Into<String>, parameters: Vec<Parameter>, learning_rate: f32) -> Self {
+        Self {
+            name: name.into(),
+            parameters,
+            learning_rate,
+            weight_decay: 0.0,
+        }
+    }
+}
+
+pub struct GroupedOptimizer {
+    optimizers: Vec<Box<dyn Optimizer>>,
+}
+
+impl GroupedOptimizer {
+    // builds one optimizer per group from the parameters and learning rate of the group,
+    // after adding the weight decay of the group to the gradients
+    pub fn new<'s, F>(
+        env: &mut Environment,
+        scope: &'s Scope,
+        groups: &[ParameterGroup],
+        mut build: F,
+    ) -> Self
+    where
+        F: FnMut(&mut Environment, &'s Scope, &[Parameter], f32) -> Box<dyn Optimizer>,
+    {
+        let optimizers = groups
+            .iter()
+            .map(|group| {
+                add_weight_decay_to_grad(scope, &group.parameters, group.weight_decay);
+                build(env, scope, &group.parameters, group.learning_rate)
+            })
+            .collect();
+        Self { optimizers }
+    }
+}
+
+impl Optimizer for GroupedOptimizer {
+    fn reset_state(&self, env: &mut Environment) {
+        for optimizer in self.optimizers.iter() {
+            optimizer.reset_state(env);
+        }
+    }
+}
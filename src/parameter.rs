@@ -9,6 +9,7 @@ slotmap::new_key_type! {
 #[derive(Clone, Copy, Debug)]
 pub enum Initializer {
     Zero,
+    One,
     RandNormal(f32),
     RandUniform(f32),
 }
@@ -30,6 +31,10 @@ pub(crate) struct ParameterStorage {
     pub(crate) name: String,
     pub(crate) buffer_id: Option<BufferId>,
     pub(crate) reset_to: Option<Initializer>,
+    pub(crate) double_buffered: bool,
+    pub(crate) back_buffer_id: Option<BufferId>,
+    pub(crate) back_buffer_written: bool,
+    pub(crate) frozen: bool,
 }
 
 pub(crate) type SharedParameters = Rc<RefCell<SlotMap<ParameterId, ParameterStorage>>>;
@@ -68,6 +73,24 @@ impl Parameter {
     }
 
     pub fn is_trainable(&self) -> bool {
-        self.owner.borrow().get(self.id).unwrap().reset_to.is_some()
+        let owner = self.owner.borrow();
+        let storage = owner.get(self.id).unwrap();
+        storage.reset_to.is_some() && !storage.frozen
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.owner.borrow().get(self.id).unwrap().frozen
+    }
+
+    // Only affects graphs built afterwards, since optimizers choose which parameters
+    // to update when the graph is built.
+    pub fn set_trainable(&self, trainable: bool) {
+        let mut owner = self.owner.borrow_mut();
+        let storage = owner.get_mut(self.id).unwrap();
+        assert!(
+            storage.reset_to.is_some(),
+            "only trainable parameters can be frozen"
+        );
+        storage.frozen = !trainable;
     }
 }
@@ -0,0 +1,129 @@
+use crate::common::*;
+use std::{f32::consts::PI, io::Write};
+
+pub trait Schedule {
+    fn learning_rate(&self, step: usize) -> f32;
+
+    // the same schedule computed on the device from a step count array
+    fn graph_learning_rate<'s>(&self, step: Array<'s>) -> Array<'s>;
+
+    fn write_learning_rate(&self, env: &mut Environment, parameter: &Parameter, step: usize) {
+        assert_eq!(parameter.shape(), Shape::from([1]));
+        let mut w = env.writer(parameter);
+        w.write_all(bytemuck::bytes_of(&self.learning_rate(step)))
+            .unwrap();
+    }
+}
+
+pub struct StepDecay {
+    initial: f32,
+    gamma: f32,
+    step_size: usize,
+}
+
+impl StepDecay {
+    pub fn new(initial: f32, gamma: f32, step_size: usize) -> Self {
+        assert!(step_size > 0);
+        Self {
+            initial,
+            gamma,
+            step_size,
+        }
+    }
+}
+
+impl Schedule for StepDecay {
+    fn learning_rate(&self, step: usize) -> f32 {
+        self.initial * self.gamma.powi((step / self.step_size) as i32)
+    }
+
+    fn graph_learning_rate<'s>(&self, step: Array<'s>) -> Array<'s> {
+        let decay_count = (step / (self.step_size as f32)).into_u32().into_f32();
+        self.gamma.into_array(step.scope()).pow(decay_count) * self.initial
+    }
+}
+
+pub struct CosineAnnealing {
+    max: f32,
+    min: f32,
+    period: usize,
+}
+
+impl CosineAnnealing {
+    pub fn new(max: f32, min: f32, period: usize) -> Self {
+        assert!(period > 0);
+        Self { max, min, period }
+    }
+}
+
+impl Schedule for CosineAnnealing {
+    fn learning_rate(&self, step: usize) -> f32 {
+        let t = (step.min(self.period) as f32) / (self.period as f32);
+        self.min + 0.5 * (self.max - self.min) * (1.0 + (PI * t).cos())
+    }
+
+    fn graph_learning_rate<'s>(&self, step: Array<'s>) -> Array<'s> {
+        let t = step.clamp(0.0, self.period as f32) / (self.period as f32);
+        self.min + 0.5 * (self.max - self.min) * (1.0 + (t * PI).cos())
+    }
+}
+
+pub struct LinearWarmup<S: Schedule> {
+    warmup_steps: usize,
+    schedule: S,
+}
+
+impl<S: Schedule> LinearWarmup<S> {
+    pub fn new(warmup_steps: usize, schedule: S) -> Self {
+        Self {
+            warmup_steps,
+            schedule,
+        }
+    }
+}
+
+impl<S: Schedule> Schedule for LinearWarmup<S> {
+    fn learning_rate(&self, step: usize) -> f32 {
+        // ramp up to the start value of the wrapped schedule, then hand over to it
+        if step < self.warmup_steps {
+            self.schedule.learning_rate(0) * ((step + 1) as f32) / (self.warmup_steps as f32)
+        } else {
+            self.schedule.learning_rate(step - self.warmup_steps)
+        }
+    }
+
+    fn graph_learning_rate<'s>(&self, step: Array<'s>) -> Array<'s> {
+        let warmup_steps = self.warmup_steps as f32;
+        let warmup = (step + 1.0) * (self.schedule.learning_rate(0) / warmup_steps);
+        let after = self.schedule.graph_learning_rate(step - warmup_steps);
+        step.select_gt(warmup_steps - 0.5, after, warmup)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cosine_annealing() {
+        let (max, min, period) = (0.1, 0.001, 20);
+        let schedule = CosineAnnealing::new(max, min, period);
+        for step in 0..=period {
+            let expected =
+                min + 0.5 * (max - min) * (1.0 + (PI * (step as f32) / (period as f32)).cos());
+            assert!((schedule.learning_rate(step) - expected).abs() < 1.0E-6);
+        }
+        assert!((schedule.learning_rate(period) - min).abs() < 1.0E-6);
+        assert!((schedule.learning_rate(period + 5) - min).abs() < 1.0E-6);
+    }
+
+    #[test]
+    fn step_decay_with_warmup() {
+        let schedule = LinearWarmup::new(4, StepDecay::new(1.0, 0.5, 10));
+        let rates: Vec<f32> = [0, 1, 3, 4, 13, 14, 24]
+            .iter()
+            .map(|&step| schedule.learning_rate(step))
+            .collect();
+        assert_eq!(rates, &[0.25, 0.5, 1.0, 1.0, 1.0, 0.5, 0.25]);
+    }
+}
@@ -11,6 +11,7 @@ pub(crate) const MAX_DIM: usize = 7;
 pub(crate) type ShapeVec = TinyVec<[usize; MAX_DIM]>;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Axis(u8);
 
 impl Axis {
@@ -38,6 +39,7 @@ impl DivRoundUp for usize {
 pub struct SignedIndex(pub isize);
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Shape(ShapeVec);
 
 impl Shape {
@@ -91,12 +93,21 @@ impl Shape {
         )
     }
 
-    pub(crate) fn batched_matmul(&self, rhs: Shape, output_mode: MatMulOutputMode) -> Self {
+    pub(crate) fn batched_matmul(
+        &self,
+        rhs: Shape,
+        output_mode: MatMulOutputMode,
+        split_k: bool,
+    ) -> Self {
         let [b0, m, k0]: [usize; 3] = self.try_into().unwrap();
         let [b1, k1, n]: [usize; 3] = rhs.try_into().unwrap();
         assert_eq!(b0, b1);
         assert_eq!(k0, k1);
-        let r = k0.div_round_up(MATMUL_MAX_K_SIZE);
+        let r = if split_k {
+            k0.div_round_up(MATMUL_MAX_K_SIZE)
+        } else {
+            1
+        };
         match output_mode {
             MatMulOutputMode::Batches => Shape::from([r, b0, m, n]),
             MatMulOutputMode::Rows => Shape::from([r, m, b0, n]),
@@ -221,6 +232,41 @@ impl Shape {
         self.element_count() * mem::size_of::<f32>()
     }
 
+    // a single -1 entry is replaced by the length that keeps the element count
+    pub fn infer_reshape(&self, dims: &[isize]) -> Self {
+        assert!(
+            dims.iter().filter(|&&n| n == -1).count() <= 1,
+            "at most one dimension can be inferred"
+        );
+        assert!(dims.iter().all(|&n| n >= -1));
+        let known: usize = dims
+            .iter()
+            .filter(|&&n| n != -1)
+            .map(|&n| n as usize)
+            .product();
+        let element_count = self.element_count();
+        let inferred = if known == 0 { 0 } else { element_count / known };
+        let shape: Shape = dims
+            .iter()
+            .map(|&n| if n == -1 { inferred } else { n as usize })
+            .collect();
+        assert_eq!(
+            shape.element_count(),
+            element_count,
+            "cannot reshape {} to {:?}",
+            self,
+            dims
+        );
+        shape
+    }
+
+    // shape of the words that store this shape as f16 pairs along the last axis
+    #[must_use]
+    pub fn packed_f16(&self) -> Self {
+        let axis = Axis::from_index(self.len() - 1);
+        self.resize_axis(axis, self[axis].div_round_up(2))
+    }
+
     #[must_use]
     pub(crate) fn insert_axis(&self, axis: Axis, len: usize) -> Self {
         let mut tmp = *self;
@@ -319,6 +365,7 @@ impl ops::Add for Shape {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum AxisMapping {
     Source { axis: Axis, step: isize },
     Broadcast,
@@ -352,6 +399,7 @@ impl AxisMapping {
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct View {
     pub(crate) input_shape: Shape,
     pub(crate) input_offsets: TinyVec<[isize; MAX_DIM]>,
@@ -418,6 +466,24 @@ impl View {
                 })
     }
 
+    pub(crate) fn canonical(&self) -> Self {
+        // views that read the same elements in the same order compare equal
+        if self.is_contiguous() {
+            return self.output_shape.identity_view();
+        }
+        let mut tmp = *self;
+        for (mapping, len) in tmp
+            .output_mapping
+            .iter_mut()
+            .zip(self.output_shape.iter().copied())
+        {
+            if len == 1 {
+                *mapping = AxisMapping::Broadcast;
+            }
+        }
+        tmp
+    }
+
     pub(crate) fn try_from_reshape(input_shape: Shape, output_shape: Shape) -> Option<Self> {
         if input_shape == output_shape {
             return Some(input_shape.identity_view());
@@ -601,6 +667,18 @@ impl View {
         }
     }
 
+    pub(crate) fn is_broadcast(&self) -> bool {
+        if let Some(prefix) = self.output_shape.len().checked_sub(self.input_shape.len()) {
+            self.input_shape
+                .iter()
+                .zip(self.output_shape.iter().skip(prefix))
+                .all(|(&from, &to)| from == to || from == 1)
+                && *self == View::broadcast(self.input_shape, self.output_shape)
+        } else {
+            false
+        }
+    }
+
     fn get_axis_step(&self, axis: isize) -> Option<(Axis, isize)> {
         let axis = self.output_shape.axis(axis);
         self.output_mapping
@@ -666,4 +744,36 @@ mod tests {
 
         assert!(View::try_from_reshape(Shape::from([8]), Shape::from([1, 9, 1])).is_none());
     }
+
+    #[test]
+    fn infer_reshape() {
+        let shape = Shape::from([2, 3, 4]);
+        assert_eq!(shape.infer_reshape(&[-1, 4]), Shape::from([6, 4]));
+        assert_eq!(shape.infer_reshape(&[2, -1]), Shape::from([2, 12]));
+        assert_eq!(shape.infer_reshape(&[4, 3, 2]), Shape::from([4, 3, 2]));
+    }
+
+    #[test]
+    #[should_panic]
+    fn infer_reshape_uneven() {
+        Shape::from([2, 3, 4]).infer_reshape(&[-1, 5]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn infer_reshape_two_inferred() {
+        Shape::from([2, 3, 4]).infer_reshape(&[-1, -1]);
+    }
+
+    #[test]
+    fn canonical() {
+        let a = View::try_from_reshape(Shape::from([8, 1]), Shape::from([8])).unwrap();
+        let b = Shape::from([8]).identity_view();
+        assert_ne!(a, b);
+        assert_eq!(a.canonical(), b.canonical());
+
+        let c = Shape::from([2, 4]).identity_view().transposed();
+        assert_eq!(c.canonical(), c);
+        assert_ne!(c.canonical(), Shape::from([4, 2]).identity_view());
+    }
 }
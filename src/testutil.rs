@@ -0,0 +1,167 @@
+use crate::common::*;
+
+const GRADIENT_CHECK_RAND_SEED: u32 = 0;
+
+// Compares the analytic gradient of the loss built by f with a central difference
+// estimate, perturbing each element of input in turn.  The loss is scaled by the
+// mini-batch size in the same way as set_loss.  Returns the max relative error.
+pub fn gradient_check<B: Backend>(
+    env: &mut B,
+    input: &Parameter,
+    epsilon: f32,
+    f: impl for<'s> Fn(&'s Scope) -> DualArray<'s>,
+) -> f32 {
+    let scope = env.scope();
+    let loss = f(&scope);
+    let loss_shape = loss.shape();
+    let loss_param = env.static_parameter(loss_shape, "gradient_check_loss");
+    let grad_param = env.static_parameter(input.shape(), "gradient_check_grad");
+    let loss_value = loss.set_loss();
+    scope.write_parameter_value(&loss_param, loss_value);
+    scope.write_parameter_value(&grad_param, scope.parameter(input).loss_grad());
+    let graph = scope.build_graph();
+
+    env.run(&graph, GRADIENT_CHECK_RAND_SEED);
+    let analytic = env.read_parameter_to_vec(&grad_param);
+
+    let mini_batch_scale = 1.0 / (loss_shape[0] as f64);
+    let eval_loss = |env: &mut B, data: &[f32]| -> f64 {
+        env.write_parameter(input, data);
+        env.run(&graph, GRADIENT_CHECK_RAND_SEED);
+        let sum: f64 = env
+            .read_parameter_to_vec(&loss_param)
+            .iter()
+            .map(|&x| x as f64)
+            .sum();
+        sum * mini_batch_scale
+    };
+
+    let orig = env.read_parameter_to_vec(input);
+    let mut data = orig.clone();
+    let mut max_error = 0f32;
+    for (index, analytic) in analytic.iter().copied().enumerate() {
+        data[index] = orig[index] + epsilon;
+        let loss_plus = eval_loss(env, &data);
+        data[index] = orig[index] - epsilon;
+        let loss_minus = eval_loss(env, &data);
+        data[index] = orig[index];
+
+        let numerical = ((loss_plus - loss_minus) / (2.0 * epsilon as f64)) as f32;
+        let scale = analytic.abs().max(numerical.abs()).max(1.0E-3);
+        max_error = max_error.max((analytic - numerical).abs() / scale);
+    }
+    env.write_parameter(input, &orig);
+
+    max_error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loss::*;
+
+    fn test_data(n: usize, seed: usize) -> Vec<f32> {
+        (0..n)
+            .map(|i| (((i + seed) * 37) % 17) as f32 / 17.0 - 0.5)
+            .collect()
+    }
+
+    #[test]
+    fn gradient_check_tanh() {
+        let mut env = CpuEnvironment::new();
+        let x_param = env.static_parameter_with_data([4, 6], "x", &test_data(24, 0));
+
+        let error = gradient_check(&mut env, &x_param, 1.0E-2, |scope| {
+            (scope.parameter(&x_param) * 2.0).tanh().square()
+        });
+        assert!(error < 1.0E-2, "max relative error {}", error);
+    }
+
+    #[test]
+    fn gradient_check_softmax() {
+        let mut env = CpuEnvironment::new();
+        let x_param = env.static_parameter_with_data([3, 5], "x", &test_data(15, 1));
+        let y_param = env.static_parameter_with_data([3, 1], "y", &[4.0, 0.0, 2.0]);
+
+        let error = gradient_check(&mut env, &x_param, 1.0E-2, |scope| {
+            softmax_cross_entropy_loss(scope.parameter(&x_param), &y_param)
+        });
+        assert!(error < 1.0E-2, "max relative error {}", error);
+    }
+
+    #[test]
+    fn gradient_check_conv2d() {
+        let mut env = CpuEnvironment::new();
+        let x_param = env.static_parameter_with_data([2, 5, 5, 2], "x", &test_data(100, 3));
+        let f_param = env.static_parameter_with_data([1, 3, 3, 3, 2], "f", &test_data(54, 4));
+
+        for input in [&x_param, &f_param] {
+            let error = gradient_check(&mut env, input, 1.0E-2, |scope| {
+                scope
+                    .parameter(&x_param)
+                    .conv2d(&f_param, 1, (2, 2))
+                    .square()
+                    .reshape([2, 27])
+            });
+            assert!(error < 1.0E-2, "max relative error {}", error);
+        }
+    }
+
+    #[test]
+    fn gradient_check_focal() {
+        let mut env = CpuEnvironment::new();
+        let x_param = env.static_parameter_with_data([3, 4], "x", &test_data(12, 2));
+        let y_data: Vec<f32> = (0..12)
+            .map(|i| if i % 3 == 0 { 1.0 } else { 0.0 })
+            .collect();
+        let y_param = env.static_parameter_with_data([3, 4], "y", &y_data);
+
+        let error = gradient_check(&mut env, &x_param, 1.0E-2, |scope| {
+            focal_loss(scope.parameter(&x_param) * 4.0, &y_param, 2.0, Some(0.25))
+        });
+        assert!(error < 1.0E-2, "max relative error {}", error);
+    }
+
+    #[test]
+    fn gradient_check_scatter_add() {
+        let mut env = CpuEnvironment::new();
+        let a_param = env.static_parameter_with_data([4, 3], "a", &test_data(12, 5));
+        let v_param = env.static_parameter_with_data([6, 3], "v", &test_data(18, 6));
+        let i_param = env.static_parameter_with_data([6], "i", &[2.0, 0.0, 2.0, 3.0, 2.0, 0.0]);
+
+        for input in [&a_param, &v_param] {
+            let error = gradient_check(&mut env, input, 1.0E-2, |scope| {
+                let indices = scope.parameter_value(&i_param).into_u32();
+                let a = scope.parameter(&a_param);
+                let v = scope.parameter(&v_param);
+                (a.scatter_add(v * v, 0, indices) * a).tanh()
+            });
+            assert!(error < 1.0E-2, "max relative error {}", error);
+        }
+    }
+
+    #[test]
+    fn gradient_check_temporal_smoothness() {
+        let mut env = CpuEnvironment::new();
+        let x_param = env.static_parameter_with_data([3, 6], "x", &test_data(18, 7));
+
+        let error = gradient_check(&mut env, &x_param, 1.0E-2, |scope| {
+            let x = scope.parameter(&x_param);
+            x.temporal_smoothness(-1) + x.sin().temporal_smoothness(0).reduce_sum(-1, true)
+        });
+        assert!(error < 1.0E-2, "max relative error {}", error);
+    }
+
+    #[test]
+    fn gradient_check_l2_normalize() {
+        let mut env = CpuEnvironment::new();
+        let x_param = env.static_parameter_with_data([3, 4], "x", &test_data(12, 17));
+        let w_param = env.static_parameter_with_data([3, 4], "w", &test_data(12, 19));
+
+        let error = gradient_check(&mut env, &x_param, 1.0E-2, |scope| {
+            let x = scope.parameter(&x_param);
+            (x.l2_normalize(-1, 1.0E-6) * &w_param).reduce_sum(-1, true)
+        });
+        assert!(error < 1.0E-2, "max relative error {}", error);
+    }
+}